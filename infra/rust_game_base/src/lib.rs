@@ -20,6 +20,9 @@ pub mod dsl;
 pub mod actions;
 pub mod framework;
 pub mod game;
+pub mod rollout;
+#[cfg(test)]
+mod scenario;
 pub mod simulator;
 pub mod value;
 
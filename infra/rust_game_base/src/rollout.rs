@@ -0,0 +1,440 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The simulator for searches, which updates the state in place instead of
+// cloning it like state_update does, and can undo the updates.
+//
+//   let mut r = Rollout::new(&state);
+//   for cmds in candidates {
+//       r.apply(&cmds);
+//       let score = evaluate(r.units());
+//       r.undo();
+//   }
+//
+// Machines are kept as Units, which are small and Copy, and the undo log
+// is the units before each step, so a step allocates nothing once the log
+// has grown. ActionResults are not recorded.
+
+use crate::game::*;
+use crate::simulator::{laser_damage_base, self_destruct_power, THRUST_ENERGY, THRUST_HEAT};
+use std::cmp::{max, min};
+
+// Machine in a compact form.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
+pub struct Unit {
+    pub id: i16,
+    pub role: Role,
+    pub x: i32,
+    pub y: i32,
+    pub vx: i32,
+    pub vy: i32,
+    pub energy: u16,
+    pub laser_power: u16,
+    pub cool_down_per_turn: u16,
+    pub life: u16,
+    pub heat: u16,
+    pub heat_limit: u16,
+    pub move_limit: u16,
+}
+
+impl Unit {
+    pub fn from_machine(m: &Machine) -> Unit {
+        Unit {
+            id: m.machine_id as i16,
+            role: m.role,
+            x: m.position.x as i32,
+            y: m.position.y as i32,
+            vx: m.velocity.x as i32,
+            vy: m.velocity.y as i32,
+            energy: m.params.energy as u16,
+            laser_power: m.params.laser_power as u16,
+            cool_down_per_turn: m.params.cool_down_per_turn as u16,
+            life: m.params.life as u16,
+            heat: m.heat as u16,
+            heat_limit: m.heat_limit as u16,
+            move_limit: m.move_limit as u16,
+        }
+    }
+
+    pub fn to_machine(&self) -> Machine {
+        Machine {
+            role: self.role,
+            machine_id: self.id as isize,
+            position: self.position(),
+            velocity: self.velocity(),
+            params: Param {
+                energy: self.energy as usize,
+                laser_power: self.laser_power as usize,
+                cool_down_per_turn: self.cool_down_per_turn as usize,
+                life: self.life as usize,
+            },
+            heat: self.heat as usize,
+            heat_limit: self.heat_limit as usize,
+            move_limit: self.move_limit as usize,
+        }
+    }
+
+    pub fn position(&self) -> Point {
+        Point::new(self.x as isize, self.y as isize)
+    }
+
+    pub fn velocity(&self) -> Point {
+        Point::new(self.vx as isize, self.vy as isize)
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.energy == 0 && self.laser_power == 0 && self.cool_down_per_turn == 0 && self.life == 0
+    }
+
+    fn accelerate(&mut self, a: Point) {
+        self.vx += a.x as i32;
+        self.vy += a.y as i32;
+    }
+
+    // Heat saturates instead of overflowing; the cooldown brings it under
+    // heat_limit anyway.
+    fn add_heat(&mut self, heat: usize) {
+        self.heat = min(self.heat as usize + heat, u16::MAX as usize) as u16;
+    }
+
+    fn kill(&mut self) {
+        self.energy = 0;
+        self.laser_power = 0;
+        self.cool_down_per_turn = 0;
+        self.life = 0;
+    }
+
+    // Same as machine_update_cooldown.
+    fn cool_down(&mut self) {
+        let heat = self.heat - min(self.cool_down_per_turn, self.heat);
+        let mut damage = heat - min(heat, self.heat_limit);
+        self.heat = min(heat, self.heat_limit);
+        for p in &mut [
+            &mut self.energy,
+            &mut self.laser_power,
+            &mut self.cool_down_per_turn,
+            &mut self.life,
+        ] {
+            let d = min(damage, **p);
+            **p -= d;
+            damage -= d;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rollout {
+    turn: usize,
+    obstacle: Option<Obstacle>,
+    units: Vec<Unit>,
+    // Units before each step, units.len() of them per step.
+    log: Vec<Unit>,
+    steps: usize,
+}
+
+impl Rollout {
+    pub fn new(state: &CurrentState) -> Rollout {
+        Rollout {
+            turn: state.turn,
+            obstacle: state.obstacle,
+            units: state
+                .machines
+                .iter()
+                .map(|(m, _)| Unit::from_machine(m))
+                .collect(),
+            log: vec![],
+            steps: 0,
+        }
+    }
+
+    pub fn units(&self) -> &[Unit] {
+        &self.units
+    }
+
+    pub fn unit(&self, id: isize) -> Option<&Unit> {
+        self.units.iter().find(|u| u.id as isize == id)
+    }
+
+    // The number of steps that can be undone.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    // The state as state_update would return it, without ActionResults.
+    pub fn to_state(&self) -> CurrentState {
+        CurrentState {
+            turn: self.turn,
+            obstacle: self.obstacle,
+            machines: self
+                .units
+                .iter()
+                .map(|u| (u.to_machine(), vec![]))
+                .collect(),
+        }
+    }
+
+    pub fn game_state(&self) -> CurrentGameState {
+        if self.winner().is_some() {
+            CurrentGameState::END
+        } else {
+            CurrentGameState::PLAYING
+        }
+    }
+
+    // Same as get_winner.
+    pub fn winner(&self) -> Option<Role> {
+        let alive = |role| self.units.iter().any(|u| u.role == role && !u.is_dead());
+        let defender_alive = alive(Role::DEFENDER);
+        if !defender_alive || !alive(Role::ATTACKER) {
+            Some(if defender_alive {
+                Role::DEFENDER
+            } else {
+                Role::ATTACKER
+            })
+        } else {
+            None
+        }
+    }
+
+    fn index(&self, id: isize) -> usize {
+        self.units.iter().position(|u| u.id as isize == id).unwrap()
+    }
+
+    // Updates the state as state_update does.
+    pub fn apply(&mut self, commands: &[Command]) -> CurrentGameState {
+        self.log.extend_from_slice(&self.units);
+        self.steps += 1;
+
+        if self.obstacle.is_some() {
+            for u in &mut self.units {
+                let x = u.x.abs();
+                let y = u.y.abs();
+                let mut a = Point::new(0, 0);
+                if x >= y {
+                    a.x = -u.x.signum() as isize;
+                }
+                if x <= y {
+                    a.y = -u.y.signum() as isize;
+                }
+                u.accelerate(a);
+            }
+        }
+
+        for (i, c) in commands.iter().enumerate() {
+            if let Command::Thrust(id, delta) = *c {
+                if delta.lmax_distance() == 0 {
+                    panic!("Thrust(0,0) cannot be chosen in alien GUI")
+                }
+                let twice = commands[..i].iter().any(|c| match *c {
+                    Command::Thrust(other, _) => other == id,
+                    _ => false,
+                });
+                if twice {
+                    panic!("Multiple thrusts from same id");
+                }
+                for u in &mut self.units {
+                    if u.id as isize == id && u.energy as usize >= THRUST_ENERGY {
+                        u.add_heat(THRUST_HEAT);
+                        u.accelerate(-delta);
+                        u.energy -= THRUST_ENERGY as u16;
+                    }
+                }
+            }
+        }
+
+        for u in &mut self.units {
+            u.x += u.vx;
+            u.y += u.vy;
+        }
+
+        for c in commands {
+            if let Command::Beam(id, target, power) = *c {
+                self.beam(id, target, power);
+            }
+        }
+        for c in commands {
+            if let Command::Bomb(id) = *c {
+                self.bomb(id);
+            }
+        }
+
+        for u in &mut self.units {
+            u.cool_down();
+        }
+
+        if let Some(obstacle) = self.obstacle {
+            for u in &mut self.units {
+                if u.position().lmax_distance() <= obstacle.gravity_radius as isize {
+                    u.kill();
+                }
+            }
+        }
+
+        self.game_state()
+    }
+
+    // Reverts the last apply, returning false if there is none.
+    pub fn undo(&mut self) -> bool {
+        if self.steps == 0 {
+            return false;
+        }
+        let start = self.log.len() - self.units.len();
+        self.units.copy_from_slice(&self.log[start..]);
+        self.log.truncate(start);
+        self.steps -= 1;
+        true
+    }
+
+    // Same as do_laser_helper.
+    fn beam(&mut self, id: isize, target: Point, power: isize) {
+        let d = target - self.units[self.index(id)].position();
+        let damage = max(laser_damage_base(&d) * power - (d.lmax_distance() - 1), 0) as usize;
+        for u in &mut self.units {
+            let dist = (u.position() - target).lmax_distance();
+            if dist <= 15 {
+                u.add_heat(damage >> (2 * dist as usize));
+            }
+            if u.id as isize == id {
+                u.add_heat(power as usize);
+            }
+        }
+    }
+
+    // Same as do_self_destruct_helper.
+    fn bomb(&mut self, id: isize) {
+        let origin = self.units[self.index(id)];
+        let (area, power) = self_destruct_power(&origin.to_machine());
+        for u in &mut self.units {
+            let distance = (u.position() - origin.position()).lmax_distance() as usize;
+            if distance <= area {
+                u.add_heat(power - min(power, 32 * distance));
+            }
+            if u.id == origin.id {
+                u.kill();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{get_winner, state_update};
+
+    #[test]
+    fn test_rollout() {
+        let machine = |role, machine_id, position, velocity, params| Machine {
+            role,
+            machine_id,
+            position,
+            velocity,
+            params,
+            heat: 0,
+            heat_limit: 64,
+            move_limit: 1,
+        };
+        let state = CurrentState {
+            turn: 3,
+            obstacle: Some(Obstacle {
+                gravity_radius: 16,
+                stage_half_size: 128,
+            }),
+            machines: vec![
+                (
+                    machine(
+                        Role::DEFENDER,
+                        1,
+                        Point::new(30, -6),
+                        Point::new(-2, -3),
+                        Param {
+                            energy: 100,
+                            laser_power: 16,
+                            cool_down_per_turn: 8,
+                            life: 1,
+                        },
+                    ),
+                    vec![],
+                ),
+                (
+                    machine(
+                        Role::ATTACKER,
+                        0,
+                        Point::new(-27, -5),
+                        Point::new(3, -2),
+                        Param {
+                            energy: 18,
+                            laser_power: 64,
+                            cool_down_per_turn: 10,
+                            life: 1,
+                        },
+                    ),
+                    vec![],
+                ),
+                (
+                    machine(
+                        Role::ATTACKER,
+                        2,
+                        Point::new(40, 20),
+                        Point::new(0, 0),
+                        Param {
+                            energy: 2,
+                            laser_power: 0,
+                            cool_down_per_turn: 0,
+                            life: 3,
+                        },
+                    ),
+                    vec![],
+                ),
+            ],
+        };
+        let turns = vec![
+            vec![Command::Thrust(1, Point::new(1, 1))],
+            vec![
+                Command::Beam(0, Point::new(25, -12), 40),
+                Command::Thrust(2, Point::new(0, -1)),
+            ],
+            vec![
+                Command::Thrust(2, Point::new(1, 0)),
+                Command::Beam(1, Point::new(-15, -12), 16),
+            ],
+            vec![Command::Thrust(2, Point::new(-1, 1))],
+            vec![Command::Bomb(2), Command::Beam(0, Point::new(16, -20), 30)],
+            vec![],
+        ];
+
+        let mut expected = vec![state.clone()];
+        let mut r = Rollout::new(&state);
+        for cmds in &turns {
+            let (status, next) = state_update(expected.last().unwrap(), cmds);
+            assert_eq!(r.apply(cmds), status);
+            let mut next = next;
+            for m in &mut next.machines {
+                m.1.clear();
+            }
+            assert_eq!(r.to_state(), next);
+            assert_eq!(r.winner(), get_winner(&next));
+            expected.push(next);
+        }
+        assert_eq!(r.steps(), turns.len());
+
+        while r.steps() > 0 {
+            expected.pop();
+            assert!(r.undo());
+            assert_eq!(&r.to_state(), expected.last().unwrap());
+        }
+        assert!(!r.undo());
+        assert_eq!(r.to_state(), state);
+    }
+}
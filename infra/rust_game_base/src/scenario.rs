@@ -0,0 +1,315 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Scenarios for testing the simulator, written as
+//
+//   scenario NAME
+//   obstacle GRAVITY_RADIUS STAGE_HALF_SIZE
+//   machine ID ROLE at X Y params E L C S [moving VX VY] [heat H] [limit L] [moves M]
+//   turn [COMMAND; COMMAND...]
+//   expect playing | expect end winner ROLE
+//   expect ID [at X Y] [moving VX VY] [params E L C S] [heat H] [dead | alive]
+//
+// where a COMMAND is "thrust ID DX DY", "bomb ID" or "beam ID X Y POWER".
+// Expectations after a turn are checked against the state after it, and
+// only for the fields they have. Every turn is also run with Rollout,
+// which has to agree with state_update.
+//
+// The corpus is in scenarios.txt.
+
+use crate::game::*;
+use crate::rollout::Rollout;
+use crate::simulator::{get_winner, state_update};
+
+// Fields of a machine, each of which may be left out.
+#[derive(Debug, Default)]
+struct Spec {
+    at: Option<Point>,
+    moving: Option<Point>,
+    params: Option<Param>,
+    heat: Option<usize>,
+    limit: Option<usize>,
+    moves: Option<usize>,
+    dead: Option<bool>,
+}
+
+#[derive(Debug)]
+enum Check {
+    Game(CurrentGameState, Option<Role>),
+    Machine(isize, Spec),
+}
+
+#[derive(Debug, Default)]
+struct Turn {
+    commands: Vec<Command>,
+    // With the line numbers.
+    checks: Vec<(usize, Check)>,
+}
+
+#[derive(Debug, Default)]
+struct Scenario {
+    name: String,
+    state: CurrentState,
+    turns: Vec<Turn>,
+}
+
+struct Tokens<'a>(std::iter::Peekable<std::str::SplitWhitespace<'a>>);
+
+impl<'a> Tokens<'a> {
+    fn word(&mut self) -> Result<&'a str, String> {
+        self.0
+            .next()
+            .ok_or_else(|| "unexpected end of line".to_string())
+    }
+
+    fn int<T: std::str::FromStr>(&mut self) -> Result<T, String> {
+        let w = self.word()?;
+        w.parse().map_err(|_| format!("expected a number: {}", w))
+    }
+
+    fn point(&mut self) -> Result<Point, String> {
+        Ok(Point::new(self.int()?, self.int()?))
+    }
+
+    fn role(&mut self) -> Result<Role, String> {
+        match self.word()? {
+            "attacker" => Ok(Role::ATTACKER),
+            "defender" => Ok(Role::DEFENDER),
+            w => Err(format!("unknown role: {}", w)),
+        }
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        match self.0.next() {
+            None => Ok(()),
+            Some(w) => Err(format!("unexpected {}", w)),
+        }
+    }
+
+    fn spec(&mut self) -> Result<Spec, String> {
+        let mut s = Spec::default();
+        while let Some(w) = self.0.next() {
+            match w {
+                "at" => s.at = Some(self.point()?),
+                "moving" => s.moving = Some(self.point()?),
+                "params" => {
+                    s.params = Some(Param {
+                        energy: self.int()?,
+                        laser_power: self.int()?,
+                        cool_down_per_turn: self.int()?,
+                        life: self.int()?,
+                    })
+                }
+                "heat" => s.heat = Some(self.int()?),
+                "limit" => s.limit = Some(self.int()?),
+                "moves" => s.moves = Some(self.int()?),
+                "dead" => s.dead = Some(true),
+                "alive" => s.dead = Some(false),
+                _ => return Err(format!("unknown field: {}", w)),
+            }
+        }
+        Ok(s)
+    }
+
+    fn command(&mut self) -> Result<Command, String> {
+        let c = match self.word()? {
+            "thrust" => Command::Thrust(self.int()?, self.point()?),
+            "bomb" => Command::Bomb(self.int()?),
+            "beam" => Command::Beam(self.int()?, self.point()?, self.int()?),
+            w => return Err(format!("unknown command: {}", w)),
+        };
+        self.end()?;
+        Ok(c)
+    }
+}
+
+fn parse_line(scenarios: &mut Vec<Scenario>, line: &str, no: usize) -> Result<(), String> {
+    let mut ts = Tokens(line.split_whitespace().peekable());
+    let keyword = ts.word()?;
+    if keyword == "scenario" {
+        scenarios.push(Scenario {
+            name: ts.word()?.to_string(),
+            ..Default::default()
+        });
+        return ts.end();
+    }
+    let s = scenarios.last_mut().ok_or("expected scenario")?;
+    match keyword {
+        "obstacle" => {
+            s.state.obstacle = Some(Obstacle {
+                gravity_radius: ts.int()?,
+                stage_half_size: ts.int()?,
+            });
+            ts.end()?;
+        }
+        "machine" => {
+            if !s.turns.is_empty() {
+                return Err("machine after turn".into());
+            }
+            let (machine_id, role) = (ts.int()?, ts.role()?);
+            let spec = ts.spec()?;
+            let m = Machine {
+                role,
+                machine_id,
+                position: spec.at.ok_or("machine without at")?,
+                velocity: spec.moving.unwrap_or_default(),
+                params: spec.params.ok_or("machine without params")?,
+                heat: spec.heat.unwrap_or(0),
+                heat_limit: spec.limit.unwrap_or(64),
+                move_limit: spec.moves.unwrap_or(1),
+            };
+            s.state.machines.push((m, vec![]));
+        }
+        "turn" => {
+            let rest = line.trim_start()["turn".len()..].trim();
+            let mut commands = vec![];
+            for c in rest.split(';').filter(|c| !c.trim().is_empty()) {
+                commands.push(Tokens(c.split_whitespace().peekable()).command()?);
+            }
+            s.turns.push(Turn {
+                commands,
+                checks: vec![],
+            });
+        }
+        "expect" => {
+            let check = match *ts.0.peek().ok_or("unexpected end of line")? {
+                "playing" => {
+                    ts.word()?;
+                    Check::Game(CurrentGameState::PLAYING, None)
+                }
+                "end" => {
+                    ts.word()?;
+                    let winner = match ts.0.next() {
+                        Some("winner") => Some(ts.role()?),
+                        Some(w) => return Err(format!("unexpected {}", w)),
+                        None => None,
+                    };
+                    Check::Game(CurrentGameState::END, winner)
+                }
+                _ => Check::Machine(ts.int()?, ts.spec()?),
+            };
+            ts.end()?;
+            s.turns
+                .last_mut()
+                .ok_or("expect before turn")?
+                .checks
+                .push((no, check));
+        }
+        w => return Err(format!("unknown keyword: {}", w)),
+    }
+    Ok(())
+}
+
+fn parse(text: &str) -> Result<Vec<Scenario>, String> {
+    let mut scenarios = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        parse_line(&mut scenarios, line, i + 1).map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
+    Ok(scenarios)
+}
+
+fn check(state: &CurrentState, game: CurrentGameState, c: &Check) -> Result<(), String> {
+    match c {
+        Check::Game(expected, winner) => {
+            if game != *expected {
+                return Err(format!("{:?} instead of {:?}", game, expected));
+            }
+            if winner.is_some() && get_winner(state) != *winner {
+                return Err(format!(
+                    "{:?} won instead of {:?}",
+                    get_winner(state),
+                    winner
+                ));
+            }
+        }
+        Check::Machine(id, spec) => {
+            let m = get_machine_by_id(state, *id).ok_or(format!("no machine {}", id))?;
+            let dead = m.params == Param::default();
+            let mismatch = |field: &str, actual: &dyn std::fmt::Debug| {
+                Err(format!("machine {} {}: {:?} in {:?}", id, field, actual, m))
+            };
+            if spec.at.is_some() && spec.at != Some(m.position) {
+                return mismatch("at", &m.position);
+            }
+            if spec.moving.is_some() && spec.moving != Some(m.velocity) {
+                return mismatch("moving", &m.velocity);
+            }
+            if spec.params.is_some() && spec.params != Some(m.params) {
+                return mismatch("params", &m.params);
+            }
+            if spec.heat.is_some() && spec.heat != Some(m.heat) {
+                return mismatch("heat", &m.heat);
+            }
+            if spec.limit.is_some() && spec.limit != Some(m.heat_limit) {
+                return mismatch("limit", &m.heat_limit);
+            }
+            if spec.moves.is_some() && spec.moves != Some(m.move_limit) {
+                return mismatch("moves", &m.move_limit);
+            }
+            if spec.dead.is_some() && spec.dead != Some(dead) {
+                return mismatch("dead", &dead);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run(s: &Scenario) -> Result<(), String> {
+    let mut state = s.state.clone();
+    let mut rollout = Rollout::new(&state);
+    for (i, turn) in s.turns.iter().enumerate() {
+        let (game, mut next) = state_update(&state, &turn.commands);
+        for m in &mut next.machines {
+            m.1.clear();
+        }
+        if rollout.apply(&turn.commands) != game || rollout.to_state() != next {
+            return Err(format!(
+                "turn {}: rollout {:?} differs from {:?}",
+                i + 1,
+                rollout.to_state(),
+                next
+            ));
+        }
+        for (no, c) in turn.checks.iter() {
+            check(&next, game, c).map_err(|e| format!("line {}: {}", no, e))?;
+        }
+        state = next;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scenarios() {
+        let scenarios = parse(include_str!("scenarios.txt")).unwrap();
+        assert!(scenarios.len() >= 10);
+        let failures: Vec<String> = scenarios
+            .iter()
+            .filter_map(|s| run(s).err().map(|e| format!("{}: {}", s.name, e)))
+            .collect();
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+
+        assert!(parse("machine 0 attacker at 0 0 params 1 1 1 1").is_err());
+        assert!(parse("scenario s\nturn thrust 0 1").is_err());
+        assert!(parse("scenario s\nexpect playing").is_err());
+    }
+}
@@ -16,9 +16,9 @@
 use self::super::game::*;
 use std::cmp::{max, min};
 
-const THRUST_HEAT: usize = 8;
+pub(crate) const THRUST_HEAT: usize = 8;
 const OVERHEAT: usize = 64;
-const THRUST_ENERGY: usize = 1;
+pub(crate) const THRUST_ENERGY: usize = 1;
 
 fn machine_generated_heat(m: &Machine, heat: usize) -> Machine {
     Machine {
@@ -79,7 +79,7 @@ fn lookup_machine(s: &CurrentState, id: isize) -> Option<Machine> {
     return None;
 }
 
-fn laser_damage_base(delta: &Point) -> isize {
+pub(crate) fn laser_damage_base(delta: &Point) -> isize {
     // FIXME need experiments
     if (delta.x == 0 || delta.y == 0) {
         3
@@ -236,7 +236,7 @@ fn lookup_destruct_power_table(sumenergy: usize) -> usize {
     }
 }
 
-fn self_destruct_power(m: &Machine) -> (usize, usize) {
+pub(crate) fn self_destruct_power(m: &Machine) -> (usize, usize) {
     let area = 32;
     let sumenergy =
         m.params.energy + m.params.laser_power + m.params.cool_down_per_turn + m.params.life;
@@ -0,0 +1,240 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Annotates many messages at once, e.g. "oka batch messages out" writes
+// out/message1.svg and out/message1.txt for messages/message1.png, and
+// out/index.md with the operators and unknown glyphs of each message and
+// the messages each kind of glyph appears in.
+//
+// Files that cannot be read as messages (e.g. messages/decoded1.txt) are
+// skipped.
+
+use super::{
+    catalog, components, framed, output_svg, output_txt, parse_file, AnnotateConfig, Error, Glyph,
+    Kind, Lab,
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+// What the index says about a message.
+struct Summary {
+    name: String,
+    lab: Lab,
+    glyphs: usize,
+    // Labels in the order they appear.
+    operators: Vec<String>,
+    // Labels of Unnamed glyphs and hashes of the others, with counts.
+    unknown: Vec<(String, usize)>,
+    kinds: Vec<String>,
+}
+
+// The name of the variant, e.g. Int for Int(3).
+fn variant(k: &Kind) -> String {
+    let debug = format!("{:?}", k);
+    debug.split('(').next().unwrap().to_string()
+}
+
+fn push_unique<T: PartialEq>(v: &mut Vec<T>, x: T) {
+    if !v.contains(&x) {
+        v.push(x);
+    }
+}
+
+fn count(v: &mut Vec<(String, usize)>, x: String) {
+    match v.iter_mut().find(|(y, _)| *y == x) {
+        Some((_, n)) => *n += 1,
+        None => v.push((x, 1)),
+    }
+}
+
+fn annotate(file: &str, out: &Path, config: &AnnotateConfig) -> Result<Summary, Error> {
+    let grid = parse_file(Some(file))?;
+    let comps = components(&grid, &config.catalog, config);
+    let name = Path::new(file)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or("non-UTF-8 path")?
+        .to_string();
+
+    let mut summary = Summary {
+        name: name.clone(),
+        lab: config.lab,
+        glyphs: 0,
+        operators: vec![],
+        unknown: vec![],
+        kinds: vec![],
+    };
+    let mut glyphs = vec![];
+    for c in comps {
+        match &c.k {
+            None if framed(&c.comp) => count(
+                &mut summary.unknown,
+                catalog::hash(&c.comp)[..8].to_string(),
+            ),
+            None => (),
+            Some(k @ Kind::Unnamed(_)) => count(&mut summary.unknown, k.label(config)),
+            Some(Kind::Int(_))
+            | Some(Kind::Var(_))
+            | Some(Kind::Binary(_))
+            | Some(Kind::Modulated(_)) => (),
+            Some(k) => push_unique(&mut summary.operators, k.label(config).replace('\n', " ")),
+        }
+        if let Some(k) = c.k {
+            push_unique(&mut summary.kinds, variant(&k));
+            glyphs.push(Glyph {
+                rows: c.rows,
+                cols: c.cols,
+                k,
+            });
+        }
+    }
+    summary.glyphs = glyphs.len();
+
+    let mut svg = std::fs::File::create(out.join(format!("{}.svg", name)))?;
+    output_svg(&mut svg, &grid, &glyphs, config)?;
+    let mut txt = std::fs::File::create(out.join(format!("{}.txt", name)))?;
+    output_txt(&mut txt, &glyphs, config)?;
+    Ok(summary)
+}
+
+// Code in a table cell.
+fn code(s: &str) -> String {
+    format!("`{}`", s.replace('|', "\\|"))
+}
+
+fn output_index(
+    w: &mut impl Write,
+    summaries: &[Summary],
+    skipped: &[String],
+) -> Result<(), std::io::Error> {
+    writeln!(w, "# Messages\n")?;
+    writeln!(
+        w,
+        "| Message | Laboratory | Glyphs | Operators | Unknown glyphs |"
+    )?;
+    writeln!(w, "| --- | --- | --- | --- | --- |")?;
+    for s in summaries {
+        writeln!(
+            w,
+            "| {0} ([svg]({0}.svg), [text]({0}.txt)) | {1:?} | {2} | {3} | {4} |",
+            s.name,
+            s.lab,
+            s.glyphs,
+            s.operators
+                .iter()
+                .map(|o| code(o))
+                .collect::<Vec<_>>()
+                .join(" "),
+            s.unknown
+                .iter()
+                .map(|(u, n)| format!("{} ×{}", code(u), n))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )?;
+    }
+    if !skipped.is_empty() {
+        writeln!(w, "\nSkipped: {}", skipped.join(", "))?;
+    }
+
+    let mut kinds = BTreeMap::<&str, Vec<&str>>::new();
+    for s in summaries {
+        for k in s.kinds.iter() {
+            kinds.entry(k).or_default().push(&s.name);
+        }
+    }
+    writeln!(w, "\n# Kinds\n")?;
+    writeln!(w, "| Kind | Messages |")?;
+    writeln!(w, "| --- | --- |")?;
+    for (k, names) in kinds {
+        writeln!(w, "| {} | {} |", k, names.join(", "))?;
+    }
+    Ok(())
+}
+
+// Annotates the files into the directory `out`, detecting the laboratory
+// of each file unless `lab` is given.
+pub fn batch(
+    files: &[String],
+    out: &str,
+    config: &AnnotateConfig,
+    lab: Option<Lab>,
+) -> Result<(), Error> {
+    std::fs::create_dir_all(out)?;
+    let out = Path::new(out);
+    let (mut summaries, mut skipped) = (vec![], vec![]);
+    for f in files {
+        match annotate(f, out, &config.for_file(Some(f), lab)) {
+            Ok(s) => summaries.push(s),
+            Err(e) => {
+                eprintln!("{}: skipped: {}", f, e);
+                skipped.push(f.clone());
+            }
+        }
+    }
+    let mut index = std::fs::File::create(out.join("index.md"))?;
+    output_index(&mut index, &summaries, &skipped)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let message = dir.path().join("message2.txt");
+        let lines = crate::render::parse_text("ap inc 1 = 2\nap neg 1 = -1").unwrap();
+        let mut grid = crate::render::render(&lines).unwrap();
+        // A glyph no one knows below the lines, and a piece of a picture
+        // next to it that is not listed.
+        let (h, w) = (grid.len(), grid[0].len());
+        let row = |js: &[usize]| {
+            (0..w)
+                .map(|k| k == 0 || js.contains(&k) || k == w - 1)
+                .collect()
+        };
+        grid.splice(
+            h - 2..h - 2,
+            vec![row(&[]), row(&[2, 3, 4, 8]), row(&[2, 4, 9])],
+        );
+        crate::output(&mut std::fs::File::create(&message).unwrap(), &grid).unwrap();
+        std::fs::write(dir.path().join("decoded2.txt"), "ap inc 1 = 2\n").unwrap();
+
+        let files = crate::inputs(dir.path().to_str().unwrap(), &["png", "txt"]).unwrap();
+        let config = AnnotateConfig::default();
+        batch(&files, out.path().to_str().unwrap(), &config, None).unwrap();
+
+        let index = std::fs::read_to_string(out.path().join("index.md")).unwrap();
+        let unknown = catalog::hash(&vec![vec![true, true, true], vec![true, false, true]]);
+        assert!(
+            index.contains(&format!(
+                "| message2 ([svg](message2.svg), [text](message2.txt)) | Pegovka | 10 \
+                 | `ap` `Succ` `is` `Neg` | `{}` ×1 |",
+                &unknown[..8]
+            )),
+            "{}",
+            index
+        );
+        assert!(index.contains("Skipped: "), "{}", index);
+        assert!(index.contains("| Apply | message2 |"), "{}", index);
+        assert!(index.contains("| Int | message2 |"), "{}", index);
+        let txt = std::fs::read_to_string(out.path().join("message2.txt")).unwrap();
+        assert_eq!(txt, "ap Succ 1 is 2 \nap Neg 1 is -1 \n");
+        assert!(out.path().join("message2.svg").exists());
+    }
+}
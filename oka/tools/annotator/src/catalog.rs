@@ -0,0 +1,174 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A catalog of the glyphs parse_glyph does not know, so that they can be
+// named without editing the code.
+//
+// The catalog is a text file with a glyph per line:
+//
+//     HASH BITMAP [NAME]
+//
+// where BITMAP has the rows of the glyph separated by '/' as in FIXED, and
+// HASH is a short name for it to be used on the command line. Glyphs
+// without NAME are yet to be named. A name is an operator if render knows
+// it (e.g. "add", "cons", ":1029" or "x0"), and is shown as is otherwise.
+
+use super::{render, Error, Grid, Kind};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub hash: String,
+    pub bitmap: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Catalog {
+    pub entries: Vec<Entry>,
+}
+
+pub fn bitmap(comp: &Grid) -> String {
+    comp.iter()
+        .map(|row| row.iter().map(|c| if *c { '1' } else { '0' }).collect())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+// FNV-1a of the bitmap, which does not change between builds unlike
+// std's hasher.
+pub fn hash(comp: &Grid) -> String {
+    let h = bitmap(comp).bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    format!("{:016x}", h)
+}
+
+impl Catalog {
+    // A missing file is an empty catalog.
+    pub fn load(file: &str) -> Result<Catalog, Error> {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Catalog::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Catalog::parse(&text).map_err(|e| format!("{}: {}", file, e).into())
+    }
+
+    fn parse(text: &str) -> Result<Catalog, String> {
+        let mut entries = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut ws = line.split_whitespace();
+            let (hash, bitmap) = match (ws.next(), ws.next()) {
+                (Some(h), Some(b)) => (h.to_string(), b.to_string()),
+                _ => return Err(format!("line {}: expected HASH BITMAP [NAME]", i + 1)),
+            };
+            let name = ws.collect::<Vec<_>>().join(" ");
+            entries.push(Entry {
+                hash,
+                bitmap,
+                name: if name.is_empty() { None } else { Some(name) },
+            });
+        }
+        Ok(Catalog { entries })
+    }
+
+    pub fn save(&self, file: &str) -> Result<(), Error> {
+        let mut text = "# Glyphs unknown to the annotator: HASH BITMAP [NAME]\n".to_string();
+        for e in self.entries.iter() {
+            text += &format!("{} {}", e.hash, e.bitmap);
+            if let Some(name) = &e.name {
+                text += &format!(" {}", name);
+            }
+            text += "\n";
+        }
+        std::fs::write(file, text)?;
+        Ok(())
+    }
+
+    pub fn get(&self, comp: &Grid) -> Option<&Entry> {
+        let bitmap = bitmap(comp);
+        self.entries.iter().find(|e| e.bitmap == bitmap)
+    }
+
+    // The kind of a named glyph.
+    pub fn kind(&self, comp: &Grid) -> Option<Kind> {
+        let name = self.get(comp)?.name.as_ref()?;
+        Some(render::kind(name).unwrap_or_else(|_| Kind::Named(name.clone())))
+    }
+
+    // Adds the glyph unless it is in the catalog, returning whether it is new.
+    pub fn add(&mut self, comp: &Grid) -> bool {
+        if self.get(comp).is_some() {
+            return false;
+        }
+        self.entries.push(Entry {
+            hash: hash(comp),
+            bitmap: bitmap(comp),
+            name: None,
+        });
+        true
+    }
+
+    // Names the glyph of the hash, or any prefix that is not ambiguous.
+    pub fn name(&mut self, hash: &str, name: &str) -> Result<&Entry, Error> {
+        let mut found = self.entries.iter_mut().filter(|e| e.hash.starts_with(hash));
+        match (found.next(), found.next()) {
+            (Some(e), None) => {
+                e.name = Some(name.to_string());
+                Ok(e)
+            }
+            (None, _) => Err(format!("no glyph {} in the catalog", hash).into()),
+            _ => Err(format!("{} is ambiguous", hash).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_test() {
+        let sun = vec![vec![true, false, true], vec![false, true, false]];
+        let plus = vec![vec![false, true], vec![true, true]];
+        assert_eq!(bitmap(&sun), "101/010");
+        assert_eq!(hash(&sun).len(), 16);
+        assert_ne!(hash(&sun), hash(&plus));
+
+        let mut c = Catalog::default();
+        assert!(c.add(&sun));
+        assert!(c.add(&plus));
+        assert!(!c.add(&sun));
+        assert_eq!(c.kind(&sun), None);
+
+        c.name(&hash(&sun)[..8], "the sun").unwrap();
+        c.name(&hash(&plus), "add").unwrap();
+        assert!(c.name("", "x").is_err());
+        assert!(c.name("zz", "x").is_err());
+        assert_eq!(c.kind(&sun), Some(Kind::Named("the sun".into())));
+        assert_eq!(c.kind(&plus), Some(Kind::Sum));
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        c.save(path).unwrap();
+        assert_eq!(Catalog::load(path).unwrap().entries, c.entries);
+        assert!(Catalog::load("no such catalog").unwrap().entries.is_empty());
+        assert!(Catalog::parse("0123").is_err());
+    }
+}
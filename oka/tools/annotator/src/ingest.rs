@@ -0,0 +1,429 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Reads the cell grid from an image, which may be a screenshot rather than
+// a message as published.
+//
+// 1. Pixels are split into lit and dark with Otsu's threshold, so tints
+//    and anti-aliasing do not matter.
+// 2. The pitch of each axis is estimated separately: the autocorrelation of
+//    the luma edges gives candidates, the best one fitting the edges to a
+//    lattice wins, and a least-squares fit makes it precise enough for
+//    fractional scaling.
+// 3. Each cell takes the majority of the pixels around its center, so
+//    isolated noise is ignored. Cells cut by the image border are dropped.
+// 4. Blank margins are trimmed, and a frame is added if the image has none,
+//    so that the grid looks like a message to `parse`.
+
+use super::{Error, Grid};
+
+pub struct Ingested {
+    pub grid: Grid,
+    // Size of a cell in pixels, horizontally and vertically.
+    pub pitch: (f64, f64),
+    // From 0 to 1: how well the edges fit the lattice times how clearly
+    // the cells are lit or dark.
+    pub confidence: f64,
+}
+
+// A lattice of lines at offset + k * pitch.
+#[derive(Clone, Copy, Debug)]
+struct Lattice {
+    pitch: f64,
+    offset: f64,
+}
+
+// Otsu's threshold, or None if all pixels have the same level.
+fn otsu(hist: &[usize; 256]) -> Option<u8> {
+    let total: usize = hist.iter().sum();
+    let sum: f64 = hist.iter().enumerate().map(|(i, c)| (i * c) as f64).sum();
+    let (mut best, mut threshold) = (-1.0, 0);
+    let (mut n0, mut sum0) = (0, 0.0);
+    for (t, &c) in hist.iter().enumerate() {
+        n0 += c;
+        sum0 += (t * c) as f64;
+        let n1 = total - n0;
+        if n0 == 0 || n1 == 0 {
+            continue;
+        }
+        let (m0, m1) = (sum0 / n0 as f64, (sum - sum0) / n1 as f64);
+        let var = n0 as f64 * n1 as f64 * (m0 - m1) * (m0 - m1);
+        if var > best {
+            best = var;
+            threshold = t;
+        }
+    }
+    if best < 0.0 {
+        return None;
+    }
+    Some(threshold as u8)
+}
+
+// The mean level of the pixels in `range`.
+fn mean(hist: &[usize; 256], range: std::ops::Range<usize>) -> f64 {
+    let n: usize = hist[range.clone()].iter().sum();
+    let sum: usize = range.map(|i| i * hist[i]).sum();
+    sum as f64 / n.max(1) as f64
+}
+
+// Weight of the edges at each pixel boundary along an axis, with the
+// level of noise subtracted. An edge blurred over a pixel counts half at
+// both of its sides, so the least-squares fit puts it in between.
+// Most boundaries are inside cells, where edges are noise, so the median
+// plus two standard deviations of a Poisson count is taken as that level.
+fn edges(levels: &[Vec<f64>], transpose: bool) -> Vec<f64> {
+    let (h, w) = (levels.len(), levels[0].len());
+    let (n, m) = if transpose { (h, w) } else { (w, h) };
+    let get = |i: usize, j: usize| {
+        if transpose {
+            levels[i][j]
+        } else {
+            levels[j][i]
+        }
+    };
+    let mut prof = vec![0.0; n];
+    for (i, p) in prof.iter_mut().enumerate().skip(1) {
+        *p = (0..m).map(|j| (get(i, j) - get(i - 1, j)).abs()).sum();
+    }
+    let mut sorted = prof.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let floor = sorted[n / 2] + 2.0 * sorted[n / 2].sqrt();
+    prof.iter().map(|v| (v - floor).max(0.0)).collect()
+}
+
+fn autocorrelation(prof: &[f64], lag: usize) -> f64 {
+    prof.iter()
+        .zip(prof[lag..].iter())
+        .map(|(a, b)| a * b)
+        .sum()
+}
+
+// How far edges may be from a lattice line, in pixels: half a pixel for
+// rounding, and a bit more for larger cells. Wider windows would let the
+// wrong pitches match edges by chance.
+fn tolerance(pitch: f64) -> f64 {
+    (0.5 + pitch / 20.0).min(1.5)
+}
+
+fn distance(l: Lattice, x: f64) -> f64 {
+    let r = (x - l.offset).rem_euclid(l.pitch);
+    r.min(l.pitch - r)
+}
+
+// The fraction of the edges on the lattice.
+fn recall(prof: &[f64], l: Lattice) -> f64 {
+    let total: f64 = prof.iter().sum();
+    let on: f64 = prof
+        .iter()
+        .enumerate()
+        .filter(|(x, _)| distance(l, *x as f64) <= tolerance(l.pitch))
+        .map(|(_, v)| v)
+        .sum();
+    on / total
+}
+
+// Recall times the fraction of lattice lines that have edges, which rules
+// out multiples and fractions of the pitch.
+fn score(prof: &[f64], l: Lattice) -> f64 {
+    let lit = prof.iter().enumerate().filter(|(_, v)| **v > 0.0);
+    let first = lit.clone().next().map_or(0, |(x, _)| x) as f64;
+    let last = lit.clone().next_back().map_or(0, |(x, _)| x) as f64;
+    let (mut lines, mut hit) = (0, 0);
+    let mut k = ((first - l.offset) / l.pitch).ceil();
+    while l.offset + k * l.pitch <= last {
+        let c = l.offset + k * l.pitch;
+        let tol = tolerance(l.pitch);
+        lines += 1;
+        if (((c - tol).ceil().max(0.0) as usize)..=((c + tol).floor() as usize).min(prof.len() - 1))
+            .any(|x| prof[x] > 0.0)
+        {
+            hit += 1;
+        }
+        k += 1.0;
+    }
+    if lines == 0 {
+        return 0.0;
+    }
+    recall(prof, l) * hit as f64 / lines as f64
+}
+
+// Least-squares fit of the edges near the lattice lines.
+fn refine(prof: &[f64], l: Lattice) -> Lattice {
+    let (mut sw, mut sk, mut sx, mut skk, mut skx) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for (x, &w) in prof.iter().enumerate() {
+        let x = x as f64;
+        if w == 0.0 || distance(l, x) > tolerance(l.pitch) {
+            continue;
+        }
+        let k = ((x - l.offset) / l.pitch).round();
+        sw += w;
+        sk += w * k;
+        sx += w * x;
+        skk += w * k * k;
+        skx += w * k * x;
+    }
+    let det = sw * skk - sk * sk;
+    if det.abs() < 1e-9 {
+        return l;
+    }
+    let pitch = (sw * skx - sk * sx) / det;
+    let offset = (sx - pitch * sk) / sw;
+    Lattice {
+        pitch,
+        offset: offset.rem_euclid(pitch),
+    }
+}
+
+fn lattice(prof: &[f64]) -> Result<(Lattice, f64), Error> {
+    let max_lag = (prof.len() / 2).min(256);
+    let best_lag = (2..max_lag)
+        .max_by(|a, b| {
+            autocorrelation(prof, *a)
+                .partial_cmp(&autocorrelation(prof, *b))
+                .unwrap()
+        })
+        .ok_or("image too small")?;
+    let mut best: Option<(Lattice, f64)> = None;
+    // The strongest period is a multiple of the pitch.
+    for k in 1..=best_lag / 2 {
+        let center = best_lag as f64 / k as f64;
+        for step in -6..=6 {
+            let pitch = center + step as f64 * 0.1 / k as f64;
+            if pitch < 2.0 {
+                continue;
+            }
+            for phase in 0..16 {
+                let l = Lattice {
+                    pitch,
+                    offset: pitch * phase as f64 / 16.0,
+                };
+                let s = score(prof, l);
+                match best {
+                    Some((_, b)) if b >= s => (),
+                    _ => best = Some((l, s)),
+                }
+            }
+        }
+    }
+    let (mut l, _) = best.ok_or("no grid found")?;
+    for _ in 0..3 {
+        l = refine(prof, l);
+    }
+    Ok((l, recall(prof, l)))
+}
+
+// Cells fully inside `0..len` pixels, as pixel ranges.
+fn cells(l: Lattice, len: usize) -> Vec<(f64, f64)> {
+    let mut start = l.offset.rem_euclid(l.pitch);
+    // An edge just before the first line is rounding, not a cut cell.
+    if start >= l.pitch - 0.5 {
+        start -= l.pitch;
+    }
+    let mut res = vec![];
+    while start + l.pitch <= len as f64 + 0.5 {
+        res.push((start.max(0.0), start + l.pitch));
+        start += l.pitch;
+    }
+    res
+}
+
+// The pixels in the middle half of a cell.
+fn middle(start: f64, end: f64, len: usize) -> std::ops::Range<usize> {
+    let q = (end - start) / 4.0;
+    (start + q) as usize..((end - q).ceil() as usize).min(len)
+}
+
+pub fn ingest(img: &image::RgbImage) -> Result<Ingested, Error> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    if w < 4 || h < 4 {
+        return Err("image too small".into());
+    }
+    let luma = |x: usize, y: usize| {
+        let p = img.get_pixel(x as u32, y as u32);
+        ((p[0] as u32 + p[1] as u32 + p[2] as u32) / 3) as u8
+    };
+    let mut hist = [0; 256];
+    for (x, y) in iproduct!(0..w, 0..h) {
+        hist[luma(x, y) as usize] += 1;
+    }
+    let threshold = otsu(&hist).ok_or("blank image")?;
+    let mask: Vec<Vec<bool>> = (0..h)
+        .map(|y| (0..w).map(|x| luma(x, y) > threshold).collect())
+        .collect();
+
+    // Luma in units of the contrast, so that an edge between a dark and a
+    // lit cell weighs 1.
+    let contrast =
+        mean(&hist, threshold as usize + 1..256) - mean(&hist, 0..threshold as usize + 1);
+    if contrast <= 0.0 {
+        return Err("blank image".into());
+    }
+    let levels: Vec<Vec<f64>> = (0..h)
+        .map(|y| (0..w).map(|x| luma(x, y) as f64 / contrast).collect())
+        .collect();
+
+    let (lx, cx) = lattice(&edges(&levels, false))?;
+    let (ly, cy) = lattice(&edges(&levels, true))?;
+
+    // Samples the middle half of each cell.
+    let (cols, rows) = (cells(lx, w), cells(ly, h));
+    let mut clarity = 0.0;
+    let mut grid: Grid = vec![];
+    for &(y0, y1) in rows.iter() {
+        let ys = middle(y0, y1, h);
+        let mut row = vec![];
+        for &(x0, x1) in cols.iter() {
+            let xs = middle(x0, x1, w);
+            let n = xs.len() * ys.len();
+            let lit = iproduct!(xs, ys.clone())
+                .filter(|(x, y)| mask[*y][*x])
+                .count();
+            let frac = lit as f64 / n.max(1) as f64;
+            clarity += (2.0 * frac - 1.0).abs();
+            row.push(frac > 0.5);
+        }
+        grid.push(row);
+    }
+    let n = rows.len() * cols.len();
+    if n == 0 {
+        return Err("no grid found".into());
+    }
+    Ok(Ingested {
+        grid: frame(trim(grid)?),
+        pitch: (lx.pitch, ly.pitch),
+        confidence: cx * cy * clarity / n as f64,
+    })
+}
+
+fn trim(grid: Grid) -> Result<Grid, Error> {
+    let lit_rows: Vec<_> = (0..grid.len())
+        .filter(|&i| grid[i].iter().any(|c| *c))
+        .collect();
+    let lit_cols: Vec<_> = (0..grid[0].len())
+        .filter(|&j| grid.iter().any(|row| row[j]))
+        .collect();
+    let (r0, r1) = match (lit_rows.first(), lit_rows.last()) {
+        (Some(r0), Some(r1)) => (*r0, *r1 + 1),
+        _ => return Err("blank image".into()),
+    };
+    let (c0, c1) = (lit_cols[0], lit_cols[lit_cols.len() - 1] + 1);
+    Ok(grid[r0..r1]
+        .iter()
+        .map(|row| row[c0..c1].to_vec())
+        .collect())
+}
+
+// Adds a frame and a blank margin unless the grid has a frame.
+fn frame(grid: Grid) -> Grid {
+    let (h, w) = (grid.len(), grid[0].len());
+    let framed = h > 2
+        && w > 2
+        && grid[0].iter().all(|c| *c)
+        && grid[h - 1].iter().all(|c| *c)
+        && grid.iter().all(|row| row[0] && row[w - 1]);
+    if framed {
+        return grid;
+    }
+    let mut res = vec![vec![true; w + 4], vec![false; w + 4]];
+    for row in grid {
+        let mut r = vec![true, false];
+        r.extend(row);
+        r.extend(vec![false, true]);
+        res.push(r);
+    }
+    res.push(vec![false; w + 4]);
+    res.push(vec![true; w + 4]);
+    for row in res.iter_mut().take(h + 3).skip(1) {
+        row[0] = true;
+        row[w + 3] = true;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ingest_test() {
+        let text = "ap ap ap s x0 x1 x12 = ap ap x0 x2 ap x1 x2\n\
+                    :1029 = ap ap cons 7 ap ap cons -123229502148636 nil\n\
+                    ap mod -300 = Bin(-300)";
+        let grid = crate::render::render(&crate::render::parse_text(text).unwrap()).unwrap();
+        let (h, w) = (grid.len() as f64, grid[0].len() as f64);
+
+        // Draws the grid with a tint, 5 x 3.5 pixel cells, blurred edges,
+        // a gray border cut through cells and a bit of noise.
+        let (px, py, border) = (5.0, 3.5, 7.0);
+        let width = (2.0 * border + w * px - 3.0) as u32;
+        let height = (2.0 * border + h * py) as u32;
+        let mut seed = 1u64;
+        let img = image::RgbImage::from_fn(width, height, |x, y| {
+            // The cell under the center of the pixel.
+            let (cx, cy) = (
+                (x as f64 + 0.5 - border) / px,
+                (y as f64 + 0.5 - border) / py,
+            );
+            if cx < 0.0 || cy < 0.0 || cx >= w || cy >= h {
+                return image::Rgb([90, 90, 90]);
+            }
+            let mut v = if grid[cy as usize][cx as usize] {
+                1.0
+            } else {
+                0.0
+            };
+            if cx.fract() * px < 1.0
+                && cx >= 1.0
+                && grid[cy as usize][cx as usize - 1] != (v == 1.0)
+            {
+                v = 0.5;
+            }
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            if seed >> 58 == 0 {
+                v = 1.0 - v;
+            }
+            image::Rgb([
+                (30.0 + 160.0 * v) as u8,
+                (20.0 + 200.0 * v) as u8,
+                (60.0 + 190.0 * v) as u8,
+            ])
+        });
+
+        let ingested = ingest(&img).unwrap();
+        assert!((ingested.pitch.0 - px).abs() < 0.05, "{:?}", ingested.pitch);
+        assert!((ingested.pitch.1 - py).abs() < 0.05, "{:?}", ingested.pitch);
+        assert!(ingested.confidence > 0.5, "{}", ingested.confidence);
+        let show = |g: &Grid| {
+            g.iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|c| if *c { '#' } else { '.' })
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        assert_eq!(show(&ingested.grid), show(&grid));
+
+        // All black and uniform gray have no lit and dark pixels to split.
+        for level in &[0, 128] {
+            let img = image::RgbImage::from_pixel(64, 48, image::Rgb([*level; 3]));
+            let err = ingest(&img).err().map(|e| e.to_string());
+            assert_eq!(err.as_deref(), Some("blank image"), "level {}", level);
+        }
+    }
+}
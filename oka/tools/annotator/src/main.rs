@@ -22,7 +22,16 @@ extern crate lazy_static;
 extern crate tempfile;
 #[macro_use]
 extern crate itertools;
+extern crate rust_game_base;
 
+mod batch;
+mod catalog;
+mod ingest;
+mod render;
+mod transcribe;
+mod wav;
+
+use rust_game_base::value::{self, Value};
 use std::io::Read;
 
 type Error = Box<dyn std::error::Error>;
@@ -48,15 +57,125 @@ enum Lab {
     Pflockingen, // molecules, lives, ...
 }
 
-static mut LABORATORY: Lab = Lab::Pegovka;
+// Names printed for the glyphs.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Language {
+    Annotator, // Sum, True, ...
+    Galaxy,    // add, t, ... as in galaxy.txt
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Colors {
+    Dark,
+    Light,
+}
+
+// Colors of an svg annotation.
+struct Palette {
+    background: &'static str,
+    cell: &'static str,
+    text: &'static str,
+    outline: &'static str,
+    number: &'static str,
+    chemistry: &'static str,
+    unknown: &'static str,
+    other: &'static str,
+}
+
+impl Colors {
+    fn palette(self) -> Palette {
+        match self {
+            Colors::Dark => Palette {
+                background: "black",
+                cell: "#757575",
+                text: "white",
+                outline: "black",
+                number: "#004D40",    // green
+                chemistry: "#01579B", // blue
+                unknown: "#BF360C",   // red
+                other: "#827717",     // yellow
+            },
+            Colors::Light => Palette {
+                background: "white",
+                cell: "#BDBDBD",
+                text: "black",
+                outline: "white",
+                number: "#66BB6A",
+                chemistry: "#42A5F5",
+                unknown: "#FF7043",
+                other: "#D4E157",
+            },
+        }
+    }
+}
+
+// How messages are annotated, which may differ between files.
+#[derive(Clone, Debug)]
+struct AnnotateConfig {
+    lab: Lab,
+    language: Language,
+    colors: Colors,
+    // Names of the glyphs that parse_glyph does not know.
+    catalog: std::rc::Rc<catalog::Catalog>,
+}
 
-fn laboratory() -> Lab {
-    unsafe { LABORATORY }
+impl Default for AnnotateConfig {
+    fn default() -> AnnotateConfig {
+        AnnotateConfig {
+            lab: Lab::Pegovka,
+            language: Language::Annotator,
+            colors: Colors::Dark,
+            catalog: Default::default(),
+        }
+    }
+}
+
+impl AnnotateConfig {
+    // Messages in a pflockingen directory are from the Pflockingen
+    // laboratory unless the laboratory is given.
+    fn for_file(&self, file: Option<&str>, lab: Option<Lab>) -> AnnotateConfig {
+        let in_pflockingen = match file {
+            Some(f) => std::path::Path::new(f)
+                .components()
+                .any(|c| c.as_os_str() == "pflockingen"),
+            None => false,
+        };
+        AnnotateConfig {
+            lab: match lab {
+                Some(lab) => lab,
+                None if in_pflockingen => Lab::Pflockingen,
+                None => Lab::Pegovka,
+            },
+            ..self.clone()
+        }
+    }
 }
 
 fn run() -> Result<(), Error> {
     use clap::{App, Arg, SubCommand};
 
+    let config_args = || {
+        vec![
+            Arg::with_name("pflockingen")
+                .short("f")
+                .long("pflockingen")
+                .help(
+                    "Annotates messages from the Pflockingen laboratory (default is pegovka unless in a pflockingen directory)",
+                ),
+            Arg::with_name("language")
+                .short("l")
+                .long("language")
+                .help("Names of the glyphs")
+                .possible_values(&["annotator", "galaxy"])
+                .default_value("annotator"),
+            Arg::with_name("colors")
+                .long("colors")
+                .help("Color scheme of svg")
+                .possible_values(&["dark", "light"])
+                .default_value("dark"),
+        ]
+    };
+
     let matches = App::new("oka")
         .arg(
             Arg::with_name("output")
@@ -65,9 +184,16 @@ fn run() -> Result<(), Error> {
                 .help("output file name")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("catalog")
+                .short("c")
+                .long("catalog")
+                .help("catalog of the glyph names (e.g. glyphs.txt), required by catalog")
+                .takes_value(true),
+        )
         .subcommands(vec![
             SubCommand::with_name("png2txt")
-                .about("parse given png image to txt format and output")
+                .about("parse given png image or wav recording to txt format and output")
                 .arg(
                     Arg::with_name("INPUT")
                         .help("Sets the input file to use")
@@ -75,14 +201,7 @@ fn run() -> Result<(), Error> {
                 ),
             SubCommand::with_name("annotate")
                 .about("annotate the given program file (png or txt) and output")
-                .arg(
-                    Arg::with_name("pflockingen")
-                        .short("f")
-                        .long("pflockingen")
-                        .help(
-                            "Annotates messages from the Pflockingen laboratory (default is pegovka)",
-                        ),
-                )
+                .args(&config_args())
                 .arg(
                     Arg::with_name("text")
                         .short("t")
@@ -96,10 +215,70 @@ fn run() -> Result<(), Error> {
                         .help("Sets the input file to use")
                         .index(1),
                 ),
+            SubCommand::with_name("batch")
+                .about("annotate the messages (png or txt) in a directory into svg and text, with an index.md")
+                .args(&config_args())
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Directory of the messages")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("Directory to write the annotations to")
+                        .required(true)
+                        .index(2),
+                ),
+            SubCommand::with_name("render")
+                .about("draw the given program text as a png image in the alien notation")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .index(1),
+                ),
+            SubCommand::with_name("catalog")
+                .about("list the glyphs that are not known and name them")
+                .subcommands(vec![
+                    SubCommand::with_name("scan")
+                        .about("add the unknown glyphs in the given files to the catalog and list where they occur")
+                        .arg(
+                            Arg::with_name("INPUT")
+                                .help("Files (png or txt) or directories of png files")
+                                .required(true)
+                                .multiple(true),
+                        ),
+                    SubCommand::with_name("name")
+                        .about("name a glyph of the catalog")
+                        .arg(
+                            Arg::with_name("HASH")
+                                .help("Hash of the glyph, or a prefix of it")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("NAME")
+                                .help("Name, or an operator in galaxy.txt (e.g. add or :1029)")
+                                .required(true)
+                                .index(2),
+                        ),
+                ]),
+            SubCommand::with_name("transcribe")
+                .about("transcribe the definitions in the given program file (png or txt) to galaxy.txt format")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .index(1),
+                ),
         ])
         .get_matches();
 
     let mut w = writer(matches.value_of("output"))?;
+    let catalog_file = matches.value_of("catalog");
+    let mut catalog = match catalog_file {
+        Some(f) => catalog::Catalog::load(f)?,
+        None => Default::default(),
+    };
 
     match matches.subcommand() {
         ("png2txt", Some(m)) => {
@@ -107,54 +286,216 @@ fn run() -> Result<(), Error> {
             output(&mut w, &grid)?;
         }
         ("annotate", Some(m)) => {
-            if m.is_present("pflockingen") {
-                unsafe {
-                    LABORATORY = Lab::Pflockingen;
-                }
-            }
+            let (config, lab) = annotate_config(m, catalog);
+            let config = config.for_file(m.value_of("INPUT"), lab);
             let in_text = m.is_present("text");
 
             let grid = parse_file(m.value_of("INPUT"))?;
-            let glyphs = parse(&grid)?;
+            let glyphs = parse(&grid, &config)?;
 
             if in_text {
-                output_txt(&mut w, &glyphs);
+                output_txt(&mut w, &glyphs, &config)?;
+            } else {
+                output_svg(&mut w, &grid, &glyphs, &config)?;
+            }
+        }
+        ("batch", Some(m)) => {
+            let (config, lab) = annotate_config(m, catalog);
+            let files = inputs(m.value_of("INPUT").unwrap(), &["png", "txt"])?;
+            batch::batch(&files, m.value_of("OUTPUT").unwrap(), &config, lab)?;
+        }
+        ("render", Some(m)) => {
+            let mut text = String::new();
+            if let Some(f) = m.value_of("INPUT") {
+                std::fs::File::open(f)?.read_to_string(&mut text)?;
             } else {
-                output_svg(&mut w, &grid, &glyphs)?;
+                std::io::stdin().read_to_string(&mut text)?;
+            }
+            let grid = render::render(&render::parse_text(&text)?)?;
+            render::output_png(&mut w, &grid)?;
+        }
+        ("catalog", Some(m)) => {
+            let catalog_file = catalog_file.ok_or("catalog needs --catalog")?;
+            match m.subcommand() {
+                ("scan", Some(m)) => {
+                    let mut files = vec![];
+                    for f in m.values_of("INPUT").unwrap() {
+                        files.extend(inputs(f, &["png"])?);
+                    }
+                    scan(&mut w, &files, &mut catalog, catalog_file)?;
+                }
+                ("name", Some(m)) => {
+                    let e =
+                        catalog.name(m.value_of("HASH").unwrap(), m.value_of("NAME").unwrap())?;
+                    writeln!(w, "{} {} {}", e.hash, e.bitmap, e.name.as_ref().unwrap())?;
+                    catalog.save(catalog_file)?;
+                }
+                _ => return Err("No such subcommand".into()),
             }
         }
+        ("transcribe", Some(m)) => {
+            let grid = parse_file(m.value_of("INPUT"))?;
+            let config = AnnotateConfig {
+                catalog: std::rc::Rc::new(catalog),
+                ..Default::default()
+            };
+            let glyphs = parse(&grid, &config)?;
+            transcribe::output_transcribed(&mut w, &glyphs)?;
+        }
         _ => return Err("No such subcommand".into()),
     };
     Ok(())
 }
 
-fn output_txt(w: &mut impl std::io::Write, glyphs: &Vec<Glyph>) {
+// The config given by the options of annotate, and the laboratory if given.
+fn annotate_config(
+    m: &clap::ArgMatches,
+    catalog: catalog::Catalog,
+) -> (AnnotateConfig, Option<Lab>) {
+    let config = AnnotateConfig {
+        language: match m.value_of("language") {
+            Some("galaxy") => Language::Galaxy,
+            _ => Language::Annotator,
+        },
+        colors: match m.value_of("colors") {
+            Some("light") => Colors::Light,
+            _ => Colors::Dark,
+        },
+        catalog: std::rc::Rc::new(catalog),
+        ..Default::default()
+    };
+    let lab = if m.is_present("pflockingen") {
+        Some(Lab::Pflockingen)
+    } else {
+        None
+    };
+    (config, lab)
+}
+
+// Orders message2 before message10.
+fn natural_key(s: &str) -> Vec<(String, u64)> {
+    let mut key = vec![];
+    let mut rest = s;
+    while !rest.is_empty() {
+        let text = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let digits = rest[text..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(rest.len(), |i| text + i);
+        key.push((
+            rest[..text].to_string(),
+            rest[text..digits].parse().unwrap_or(0),
+        ));
+        rest = &rest[digits..];
+    }
+    key
+}
+
+// The file, or the files in the directory with one of the extensions.
+fn inputs(path: &str, extensions: &[&str]) -> Result<Vec<String>, Error> {
+    if !std::path::Path::new(path).is_dir() {
+        return Ok(vec![path.into()]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let p = entry?.path();
+        let ext = p.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if p.is_file() && extensions.contains(&ext) {
+            files.push(p.to_str().ok_or("non-UTF-8 path")?.to_string());
+        }
+    }
+    files.sort_by_key(|f| natural_key(f));
+    Ok(files)
+}
+
+// Adds the glyphs the annotator does not know to the catalog, and lists
+// all the glyphs of the catalog found in the files.
+fn scan(
+    w: &mut impl std::io::Write,
+    files: &[String],
+    catalog: &mut catalog::Catalog,
+    catalog_file: &str,
+) -> Result<(), Error> {
+    let mut found: Vec<(String, String)> = vec![];
+    for f in files {
+        let grid = parse_file(Some(f))?;
+        let config = AnnotateConfig::default().for_file(Some(f), None);
+        for c in components(&grid, catalog, &config) {
+            let listed = match c.k {
+                None | Some(Kind::Unnamed(_)) => true,
+                _ => catalog.get(&c.comp).is_some(),
+            };
+            if !listed {
+                continue;
+            }
+            if catalog.add(&c.comp) {
+                eprintln!("new glyph {}", catalog::hash(&c.comp));
+            }
+            found.push((
+                catalog.get(&c.comp).unwrap().hash.clone(),
+                format!("{}:{}:{}", f, c.rows.start, c.cols.start),
+            ));
+        }
+    }
+    catalog.save(catalog_file)?;
+    for e in catalog.entries.iter() {
+        let places: Vec<_> = found.iter().filter(|(h, _)| *h == e.hash).collect();
+        if places.is_empty() {
+            continue;
+        }
+        let name = e.name.as_ref().map_or("?", |n| n.as_str());
+        writeln!(w, "{} {} {}", e.hash, name, e.bitmap)?;
+        for (_, place) in places {
+            writeln!(w, "    {}", place)?;
+        }
+    }
+    Ok(())
+}
+
+// Groups glyphs into lines of text, from top to bottom and left to right.
+fn lines(glyphs: &[Glyph]) -> Vec<Vec<&Glyph>> {
     let mut s = glyphs.iter().map(|g| g.rows.start).collect::<Vec<_>>();
     s.sort();
     s.dedup();
     // TODO: scale.
-    for r in s {
-        let mut gs: Vec<_> = glyphs.iter().filter(|g| g.rows.start == r).collect();
-        gs.sort_by_key(|g| g.cols.start);
+    s.into_iter()
+        .map(|r| {
+            let mut gs: Vec<_> = glyphs.iter().filter(|g| g.rows.start == r).collect();
+            gs.sort_by_key(|g| g.cols.start);
+            gs
+        })
+        .collect()
+}
+
+fn output_txt(
+    w: &mut impl std::io::Write,
+    glyphs: &[Glyph],
+    config: &AnnotateConfig,
+) -> Result<(), std::io::Error> {
+    for gs in lines(glyphs) {
         for g in gs {
-            print!("{} ", g.k.to_string());
+            write!(w, "{} ", g.k.label(config))?;
         }
-        println!();
+        writeln!(w)?;
     }
+    Ok(())
 }
 
 fn output_svg(
     w: &mut impl std::io::Write,
     grid: &Grid,
     glyphs: &Vec<Glyph>,
+    config: &AnnotateConfig,
 ) -> Result<(), std::io::Error> {
     const SZ: usize = 14;
+    let p = config.colors.palette();
     let H = grid.len();
     let W = grid[0].len();
     let out = format!(
         r##"<?xml version="1.0"?>
 <svg xmlns="http://www.w3.org/2000/svg" height="{}" width="{}">
-  <rect y="0" x="0" height="{0}" width="{1}" fill="black" />
+  <rect y="0" x="0" height="{0}" width="{1}" fill="{background}" />
   {}
   {}
 </svg>"##,
@@ -168,12 +509,13 @@ fn output_svg(
                     .filter_map(|(j, c)| {
                         if *c {
                             Some(format!(
-                                r##"<rect y="{}" x="{}" height="{}" width="{}" fill="#757575"/>
+                                r##"<rect y="{}" x="{}" height="{}" width="{}" fill="{}"/>
     "##,
                                 i * SZ,
                                 j * SZ,
                                 SZ,
-                                SZ
+                                SZ,
+                                p.cell
                             ))
                         } else {
                             None
@@ -187,14 +529,14 @@ fn output_svg(
             let (x1,x2) = (g.rows.start * SZ, g.rows.end * SZ);
             let (y1,y2) = (g.cols.start * SZ, g.cols.end * SZ);
             let color = match g.k {
-                Kind::Int(_) | Kind::Var(_) | Kind::Binary(_) => "#004D40", // green
-                Kind::Molecule(_) | Kind::Amino(_,_) => "#01579B", // blue
-                Kind::Unnamed(_) => "#BF360C", // red
-                _ => "#827717", // yellow
+                Kind::Int(_) | Kind::Var(_) | Kind::Binary(_) | Kind::Modulated(_) => p.number,
+                Kind::Molecule(_) | Kind::Amino(_,_) => p.chemistry,
+                Kind::Unnamed(_) => p.unknown,
+                _ => p.other,
             };
             format!(
                 r#"<rect y="{}" x="{}" height="{}" width="{}" fill="{}" fill-opacity="0.5"/>
-<text y="{}" x="{}" dominant-baseline='middle' text-anchor='middle' fill='white' style='paint-order: stroke; fill: white; stroke: black; stroke-width: 1px; font-family="monospace"; font-size: 18px'>{}</text>
+<text y="{}" x="{}" dominant-baseline='middle' text-anchor='middle' fill='{text}' style='paint-order: stroke; fill: {text}; stroke: {outline}; stroke-width: 1px; font-family="monospace"; font-size: 18px'>{}</text>
 "#,
             x1,
             y1,
@@ -204,11 +546,14 @@ fn output_svg(
             (x1+x2)/2,
             (y1+y2)/2,
             {
-                let n = g.k.to_string().split("\n").count() as isize;
-                g.k.to_string().split("\n").into_iter().enumerate().map(|(i,s)|format!(r#"<tspan x="{}" dy="{}em">{}</tspan>"#, (y1+y2)/2, if i==0 {-(n/2) as f64} else {1.1}, s)).collect::<Vec<String>>().join("\n")
+                let n = g.k.label(config).split("\n").count() as isize;
+                g.k.label(config).split("\n").enumerate().map(|(i,s)|format!(r#"<tspan x="{}" dy="{}em">{}</tspan>"#, (y1+y2)/2, if i==0 {-(n/2) as f64} else {1.1}, s)).collect::<Vec<String>>().join("\n")
             },
+            text = p.text,
+            outline = p.outline,
             )
-        }).collect::<Vec<String>>().join("")
+        }).collect::<Vec<String>>().join(""),
+        background = p.background,
     );
     writeln!(w, "{}", out)
 }
@@ -222,7 +567,7 @@ struct Glyph {
     k: Kind,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 enum Kind {
     Int(isize),
     Is,
@@ -239,6 +584,7 @@ enum Kind {
     ToBin,
     FromBin,
     Binary(isize),
+    Modulated(String), // bits of a signal other than a number
     Op(&'static str),
     Pow(usize), // x^?
     LBra,
@@ -249,22 +595,13 @@ enum Kind {
     Amino(&'static str, &'static str),
     Life(&'static str),
     Unnamed(String),
+    Named(String), // in the catalog
 }
 
 impl ToString for Kind {
     fn to_string(&self) -> String {
         match self {
-            Kind::Int(n) => format!(
-                "{}{}",
-                n,
-                if laboratory() == Lab::Pflockingen
-                    && (1 <= *n && (*n as usize) - 1 < ELEMENTS.len())
-                {
-                    format!("\n{}", ELEMENTS[*n as usize - 1])
-                } else {
-                    "".into()
-                }
-            ),
+            Kind::Int(n) => n.to_string(),
             Kind::Is => "is".into(),
             Kind::Apply => "ap".into(),
             Kind::Succ => "Succ".into(),
@@ -280,6 +617,12 @@ impl ToString for Kind {
             Kind::ToBin => "ToBin".into(),
             Kind::FromBin => "FromBin".into(),
             Kind::Binary(i) => format!("Bin({})", i),
+            Kind::Modulated(bits) => format!(
+                "Bin({})",
+                value::demodulate_from_string(bits)
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| bits.clone())
+            ),
             Kind::Op(ref s) => s.to_string(),
             Kind::Pow(base) => format!("{}^", base),
             Kind::LBra => "[".into(),
@@ -302,6 +645,25 @@ impl ToString for Kind {
             Kind::Amino(_, abbr) => format!("{}", abbr),
             Kind::Life(s) => s.to_string(),
             Kind::Unnamed(x) => format!("{}?", x),
+            Kind::Named(x) => x.clone(),
+        }
+    }
+}
+
+impl Kind {
+    // The name printed in annotations.
+    fn label(&self, config: &AnnotateConfig) -> String {
+        match self {
+            Kind::Int(n)
+                if config.lab == Lab::Pflockingen
+                    && (1 <= *n && (*n as usize) - 1 < ELEMENTS.len()) =>
+            {
+                format!("{}\n{}", n, ELEMENTS[*n as usize - 1])
+            }
+            _ => match config.language {
+                Language::Galaxy => transcribe::token(self).unwrap_or_else(|| self.to_string()),
+                Language::Annotator => self.to_string(),
+            },
         }
     }
 }
@@ -461,7 +823,14 @@ fn clip(g: &Grid, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> Grid {
     g[x0..x1].iter().map(|row| row[y0..y1].to_vec()).collect()
 }
 
-fn parse_glyph(comp: &Grid, flip: bool) -> Option<Kind> {
+// Whether `comp` has the filled top row and left column of a number, a
+// variable or an operator, so that parse_glyph not knowing it is news
+// rather than a part of a picture.
+fn framed(comp: &Grid) -> bool {
+    comp.len() > 1 && comp[0].len() > 1 && comp[0].iter().all(|c| *c) && comp.iter().all(|r| r[0])
+}
+
+fn parse_glyph(comp: &Grid, flip: bool, lab: Lab) -> Option<Kind> {
     let inside = |i: usize, j: usize| i < comp.len() && j < comp[0].len();
     let get = |i: usize, j: usize| inside(i, j) && (comp[i][j] ^ flip);
 
@@ -479,34 +848,28 @@ fn parse_glyph(comp: &Grid, flip: bool) -> Option<Kind> {
         }
     }
 
+    // A modulated signal: the bits on the top row and their negation below.
     if !flip && comp.len() == 2 {
-        let bin = (|| {
-            let n = comp[0].len();
-            let bs = comp[0]
-                .iter()
-                .zip(comp[1].iter())
-                .map(|(x, y)| if x == y { None } else { Some(*x) })
-                .collect::<Option<Vec<_>>>()?;
-            let negative = if bs.len() < 2 || bs[0] == bs[1] {
-                None
-            } else {
-                Some(bs[0])
-            }?;
-            let k = (2..n).find(|i| !bs[*i])?;
-            let m = (k - 2) * 4;
-            if n - 1 != k + m {
-                return None;
+        let bits = comp[0]
+            .iter()
+            .zip(comp[1].iter())
+            .map(|(x, y)| if x == y { None } else { Some(*x) })
+            .collect::<Option<Vec<_>>>();
+        if let Some(bits) = bits {
+            let mut it = bits.iter().cloned();
+            match value::demodulate(&mut it) {
+                // Not all bits are read.
+                Some(_) if it.next().is_some() => (),
+                Some(Value::Int(n)) => return Some(Kind::Binary(n as isize)),
+                Some(_) => {
+                    return Some(Kind::Modulated(
+                        bits.iter().map(|b| if *b { '1' } else { '0' }).collect(),
+                    ))
+                }
+                None => (),
             }
-            let num = bs.as_slice()[n - m..n]
-                .iter()
-                .fold(0isize, |acc, b| acc * 2 + (if *b { 1 } else { 0 }));
-            Some(Kind::Binary(num * (if negative { -1 } else { 1 })))
-        })();
-        if bin.is_some() {
-            return bin;
         }
     }
-
     // num or var
     if !((1..comp.len()).all(|i| get(i, 0)) && (1..comp[0].len()).all(|j| get(0, j))) {
         return None;
@@ -526,15 +889,15 @@ fn parse_glyph(comp: &Grid, flip: bool) -> Option<Kind> {
 
     let is_var = n > 2 && (0..=n).all(|i| get(i, 0) && get(i, n) && get(0, i) && get(n, i));
     if is_var {
-        match parse_glyph(&clip(comp, (1, 1), (n, n)), !flip) {
+        match parse_glyph(&clip(comp, (1, 1), (n, n)), !flip, lab) {
             Some(Kind::Int(i)) if i >= 0 => return Some(Kind::Var(i as usize)),
             _ => (),
         };
         if n > 5 {
-            match parse_glyph(&clip(comp, (2, 2), (n - 1, n - 1)), flip) {
-                Some(Kind::Int(i)) => return Some(Kind::Pow(i as usize)),
-                _ => (),
-            };
+            let inner = clip(comp, (2, 2), (n - 1, n - 1));
+            if let Some(Kind::Int(i)) = parse_glyph(&inner, flip, lab) {
+                return Some(Kind::Pow(i as usize));
+            }
         }
     }
 
@@ -555,7 +918,7 @@ fn parse_glyph(comp: &Grid, flip: bool) -> Option<Kind> {
     }
 
     if !get(0, 0) {
-        if (laboratory() == Lab::Pflockingen) && (num == 0) {
+        if (lab == Lab::Pflockingen) && (num == 0) {
             return None;
         }
         return Some(Kind::Int(num));
@@ -588,7 +951,28 @@ fn component(grid: &Grid, x: usize, y: usize, used: &mut Grid, cells: &mut Vec<(
     }
 }
 
-fn parse(grid: &Grid) -> Result<Vec<Glyph>, Error> {
+// A connected component of the grid, with its kind if it is a glyph.
+struct Component {
+    rows: std::ops::Range<usize>,
+    cols: std::ops::Range<usize>,
+    comp: Grid,
+    k: Option<Kind>,
+}
+
+fn parse(grid: &Grid, config: &AnnotateConfig) -> Result<Vec<Glyph>, Error> {
+    Ok(components(grid, &config.catalog, config)
+        .into_iter()
+        .filter_map(|c| {
+            Some(Glyph {
+                rows: c.rows,
+                cols: c.cols,
+                k: c.k?,
+            })
+        })
+        .collect())
+}
+
+fn components(grid: &Grid, catalog: &catalog::Catalog, config: &AnnotateConfig) -> Vec<Component> {
     let (h, w) = (grid.len(), grid[0].len());
     let mut used = vec![vec![false; w]; h];
     let mut res = vec![];
@@ -627,18 +1011,47 @@ fn parse(grid: &Grid) -> Result<Vec<Glyph>, Error> {
             }
         }
 
-        if let Some(k) = parse_glyph(&comp, false) {
-            res.push(Glyph {
-                rows: x0..x1,
-                cols: y0..y1,
-                k,
-            });
+        let k = match parse_glyph(&comp, false, config.lab) {
+            None if config.lab == Lab::Pegovka && nil_signal(grid, &comp, x0, y0, &res) => {
+                Some(Kind::Modulated("00".into()))
+            }
+            None | Some(Kind::Unnamed(_)) if catalog.kind(&comp).is_some() => catalog.kind(&comp),
+            k => k,
+        };
+        if k.is_some() {
             for (i, j) in add {
                 used[i][j] = true;
             }
         }
+        // The blank top row of nil's signal is a part of it.
+        let x0 = match &k {
+            Some(Kind::Modulated(_)) if x1 - x0 == 1 => x0 - 1,
+            _ => x0,
+        };
+        res.push(Component {
+            rows: x0..x1,
+            cols: y0..y1,
+            comp,
+            k,
+        });
     }
-    Ok(res)
+    res
+}
+
+// Only the negation of nil's signal 00 is visible, a bar of two cells
+// like many parts of pictures. It is taken as the signal only below a
+// blank row and right after is or ToBin, where signals appear.
+fn nil_signal(grid: &Grid, comp: &Grid, x0: usize, y0: usize, found: &[Component]) -> bool {
+    *comp == vec![vec![true, true]]
+        && (y0..y0 + 2).all(|j| !grid[x0 - 1][j])
+        && matches!(
+            found
+                .iter()
+                .filter(|c| c.rows.contains(&x0) && c.cols.end <= y0)
+                .max_by_key(|c| c.cols.end)
+                .and_then(|c| c.k.as_ref()),
+            Some(Kind::Is) | Some(Kind::ToBin)
+        )
 }
 
 fn output(w: &mut impl std::io::Write, grid: &Grid) -> Result<(), std::io::Error> {
@@ -657,7 +1070,9 @@ fn output(w: &mut impl std::io::Write, grid: &Grid) -> Result<(), std::io::Error
 fn parse_txt(file: &str) -> Result<Grid, Error> {
     let mut buf = String::new();
     std::fs::File::open(file)?.read_to_string(&mut buf)?;
-    buf.split('\n')
+    // Blank lines, e.g. at the end of the output of png2txt, are not rows.
+    buf.lines()
+        .filter(|row| !row.is_empty())
         .map(|row| {
             row.chars()
                 .map(|c| match c {
@@ -670,39 +1085,31 @@ fn parse_txt(file: &str) -> Result<Grid, Error> {
         .collect()
 }
 
-fn parse_img(file: &str) -> Result<Grid, Error> {
-    let img = image::open(file)?;
-    let img = img.into_rgb();
-
-    let H = img.height();
-    let W = img.width();
-
-    let (x0, y0) = iproduct!(0..W, 0..H)
-        .find(|(x, y)| img.get_pixel(*x, *y)[0] > 250)
-        .ok_or(Error::from("white not found"))?;
-    let SZ = (0..)
-        .find(|i| img.get_pixel(x0 + i, y0 + i)[0] < 5)
-        .ok_or(Error::from("black not found"))?;
-    let (x1, y1) = iproduct!((0..W).rev(), (0..H).rev())
-        .find(|(x, y)| img.get_pixel(*x, *y)[0] > 250)
-        .ok_or(Error::from("white not found"))?;
-    let (x1, y1) = (x1 + 1, y1 + 1);
-
-    Ok((y0..y1)
-        .step_by(SZ as usize)
-        .map(|y| {
-            (x0..x1)
-                .step_by(SZ as usize)
-                .map(|x| {
-                    if img.get_pixel(x, y)[0] < 128 {
-                        false
-                    } else {
-                        true
-                    }
-                })
-                .collect()
-        })
-        .collect())
+fn parse_img(img: image::DynamicImage, file: &str) -> Result<Grid, Error> {
+    let ingested = ingest::ingest(&img.into_rgb8())?;
+    eprintln!(
+        "{}: {}x{} cells of {:.2}x{:.2} pixels, confidence {:.2}",
+        file,
+        ingested.grid[0].len(),
+        ingested.grid.len(),
+        ingested.pitch.0,
+        ingested.pitch.1,
+        ingested.confidence
+    );
+    Ok(ingested.grid)
+}
+
+fn parse_wav(wav: &[u8], file: &str) -> Result<Grid, Error> {
+    let decoded = wav::decode(wav)?;
+    eprintln!(
+        "{}: {}x{} cells of {:.1} samples at {} Hz",
+        file,
+        decoded.grid[0].len(),
+        decoded.grid.len(),
+        decoded.bit,
+        decoded.rate
+    );
+    Ok(decoded.grid)
 }
 
 fn parse_file(file: Option<&str>) -> Result<Grid, Error> {
@@ -716,16 +1123,85 @@ fn parse_file(file: Option<&str>) -> Result<Grid, Error> {
             .to_str()
             .ok_or(Error::from("no tempfile path"))?
     };
-    if let Ok(grid) = parse_img(file) {
-        return Ok(grid);
+    let bytes = std::fs::read(file)?;
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+        return parse_wav(&bytes, file);
+    }
+    // Files that are not images are grids in text.
+    match image::open(file) {
+        Ok(img) => parse_img(img, file),
+        Err(_) => parse_txt(file),
     }
-    parse_txt(file)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn fn_test() {
         assert_eq!(2, 1 + 1);
     }
+
+    #[test]
+    fn dialect_test() {
+        let grid = render::render(&render::parse_text("0 1 ap t").unwrap()).unwrap();
+        let labels = |config: &AnnotateConfig| {
+            parse(&grid, config)
+                .unwrap()
+                .iter()
+                .map(|g| g.k.label(config))
+                .collect::<Vec<_>>()
+        };
+        let pegovka = AnnotateConfig::default();
+        assert_eq!(labels(&pegovka), vec!["0", "1", "ap", "True"]);
+        let galaxy = AnnotateConfig {
+            language: Language::Galaxy,
+            ..Default::default()
+        };
+        assert_eq!(labels(&galaxy), vec!["0", "1", "ap", "t"]);
+
+        let pflockingen = pegovka.for_file(Some("messages/pflockingen/image01.png"), None);
+        assert_eq!(pflockingen.lab, Lab::Pflockingen);
+        assert_eq!(labels(&pflockingen), vec!["1\nH", "ap", "True"]);
+        let forced = pegovka.for_file(Some("message1.png"), Some(Lab::Pflockingen));
+        assert_eq!(forced.lab, Lab::Pflockingen);
+        assert_eq!(pegovka.for_file(None, None).lab, Lab::Pegovka);
+    }
+
+    #[test]
+    fn modulated_test() {
+        let list = |xs: Vec<Value>| {
+            xs.into_iter()
+                .rev()
+                .fold(Value::Nil, |l, x| Value::Cons(Box::new(x), Box::new(l)))
+        };
+        let value = list(vec![
+            Value::Int(1),
+            list(vec![Value::Int(2), Value::Int(-3)]),
+        ]);
+        let signal = |v: &Value| Kind::Modulated(value::modulate_to_string(v));
+        let lines = vec![
+            vec![Kind::ToBin, Kind::Is, signal(&Value::Nil), Kind::Int(0)],
+            vec![Kind::Int(1), signal(&value), Kind::Binary(-300)],
+        ];
+        let grid = render::render(&lines).unwrap();
+        let config = AnnotateConfig::default();
+        let glyphs = parse(&grid, &config).unwrap();
+        let parsed = crate::lines(&glyphs)
+            .iter()
+            .map(|l| l.iter().map(|g| g.k.clone()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, lines);
+        assert_eq!(lines[0][2].label(&config), "Bin(nil)");
+        assert_eq!(lines[1][1].label(&config), "Bin((1 (2 -3)))");
+
+        // Elsewhere the bar of nil's signal is not a glyph.
+        let grid = render::render(&[vec![Kind::Int(1), signal(&Value::Nil)]]).unwrap();
+        let parsed = parse(&grid, &config).unwrap();
+        assert_eq!(
+            parsed.iter().map(|g| g.k.clone()).collect::<Vec<_>>(),
+            vec![Kind::Int(1)]
+        );
+    }
 }
@@ -0,0 +1,253 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Draws messages in the alien notation, the inverse of parse_glyph.
+//
+// The input has a line of glyphs per line of text. Tokens are galaxy.txt
+// names (ap, add, cons, t, :1029, x0, ...) or the names printed by
+// "annotate -t" (Sum, cos, True, 1029?, Bin(5), ...), so both
+// galaxy definitions and annotated messages can be drawn again.
+
+use super::{Error, Grid, Kind, FIXED, NUM_TO_KIND};
+
+// Pixels per cell, as in the messages.
+const SCALE: u32 = 4;
+// Blank cells between glyphs and between lines.
+const GAP: usize = 2;
+
+pub fn kind(token: &str) -> Result<Kind, Error> {
+    let aliases = [
+        ("ap", Kind::Apply),
+        ("=", Kind::Is),
+        ("is", Kind::Is),
+        ("[", Kind::LBra),
+        ("]", Kind::RBra),
+        (";", Kind::Separator),
+        (",", Kind::Separator),
+        ("inc", Kind::Succ),
+        ("dec", Kind::Pred),
+        ("add", Kind::Sum),
+        ("mul", Kind::Product),
+        ("div", Kind::Quotient),
+        ("eq", Kind::Equals),
+        ("t", Kind::Bool(true)),
+        ("f", Kind::Bool(false)),
+        ("lt", Kind::LT),
+        ("mod", Kind::ToBin),
+        ("dem", Kind::FromBin),
+        ("neg", Kind::Op("Neg")),
+        ("s", Kind::Op(r#"\f g x -> (f x) (g x)"#)),
+        ("c", Kind::Op(r#"\f x y -> f y x"#)),
+        ("b", Kind::Op(r#"\x y z -> x (y z)"#)),
+        ("i", Kind::Op(r#"\x -> x"#)),
+        ("cons", Kind::Op("cos")),
+    ];
+    if let Some((_, k)) = aliases.iter().find(|(s, _)| *s == token) {
+        return Ok(k.clone());
+    }
+    if let Ok(n) = token.parse() {
+        return Ok(Kind::Int(n));
+    }
+    let number = |s: &str| s.parse::<u64>().is_ok();
+    if token.starts_with('x') && number(&token[1..]) {
+        return Ok(Kind::Var(token[1..].parse()?));
+    }
+    if token.starts_with(':') && number(&token[1..]) {
+        return Ok(Kind::Unnamed(token[1..].into()));
+    }
+    if token.ends_with('?') && number(&token[..token.len() - 1]) {
+        return Ok(Kind::Unnamed(token[..token.len() - 1].into()));
+    }
+    if token.starts_with("Bin(") && token.ends_with(')') {
+        return Ok(Kind::Binary(token[4..token.len() - 1].parse()?));
+    }
+    // Other names printed by the annotator, e.g. Sum or car.
+    NUM_TO_KIND
+        .values()
+        .chain(FIXED.iter().map(|(_, k)| k))
+        .find(|k| k.to_string() == token)
+        .cloned()
+        .ok_or_else(|| format!("unknown token {}", token).into())
+}
+
+pub fn parse_text(text: &str) -> Result<Vec<Vec<Kind>>, Error> {
+    text.lines()
+        .map(|line| line.split_whitespace().map(kind).collect())
+        .collect()
+}
+
+// A number with the bits in a k x k square, or an operator if `op`.
+fn number(n: isize, op: bool) -> Grid {
+    // isize::MIN wraps to itself, which is 2^63 as u64.
+    let abs = n.wrapping_abs() as u64;
+    let bits = 64 - abs.leading_zeros() as usize;
+    let k = (1..).find(|k| k * k >= bits).unwrap();
+    let h = k + 1 + if n < 0 { 1 } else { 0 };
+    (0..h)
+        .map(|i| {
+            (0..=k)
+                .map(|j| match (i, j) {
+                    (0, 0) => op,
+                    // The sign below the left edge.
+                    _ if i > k => j == 0,
+                    (0, _) | (_, 0) => true,
+                    _ => abs >> ((i - 1) * k + j - 1) & 1 == 1,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// A number inverted in a frame.
+fn var(i: usize) -> Grid {
+    let inner = number(i as isize, false);
+    let n = inner.len() + 1;
+    let mut g = vec![vec![true; n + 1]; n + 1];
+    for (i, row) in inner.iter().enumerate() {
+        for (j, c) in row.iter().enumerate() {
+            g[i + 1][j + 1] = !c;
+        }
+    }
+    g
+}
+
+// A modulated number: the bits on the top row and their negation below.
+fn binary(n: isize) -> Grid {
+    let abs = n.wrapping_abs() as u64;
+    let bits = 64 - abs.leading_zeros() as usize;
+    let w = (0..).find(|w| 4 * w >= bits).unwrap();
+    let mut bits = vec![n < 0, n >= 0];
+    bits.extend((0..=w).map(|i| i < w));
+    bits.extend((0..4 * w).rev().map(|i| abs >> i & 1 == 1));
+    vec![bits.clone(), bits.iter().map(|b| !b).collect()]
+}
+
+pub fn glyph(k: &Kind) -> Option<Grid> {
+    match k {
+        Kind::Int(n) => return Some(number(*n, false)),
+        Kind::Var(i) => return Some(var(*i)),
+        Kind::Binary(n) => return Some(binary(*n)),
+        Kind::Modulated(bits) => {
+            let top: Vec<bool> = bits.chars().map(|c| c == '1').collect();
+            return Some(vec![top.clone(), top.iter().map(|b| !b).collect()]);
+        }
+        Kind::Unnamed(s) => return s.parse().ok().map(|n| number(n, true)),
+        _ => (),
+    }
+    if let Some((fig, _)) = FIXED.iter().find(|(_, f)| f == k) {
+        return Some(
+            fig.iter()
+                .map(|row| row.chars().map(|c| c == '1').collect())
+                .collect(),
+        );
+    }
+    NUM_TO_KIND
+        .iter()
+        .find(|(_, f)| *f == k)
+        .map(|(n, _)| number(*n, true))
+}
+
+// Lays out the lines in a frame, each glyph at the top of its line.
+pub fn render(lines: &[Vec<Kind>]) -> Result<Grid, Error> {
+    let mut rows: Grid = vec![];
+    for line in lines {
+        let mut gs = vec![];
+        for k in line {
+            gs.push(glyph(k).ok_or_else(|| format!("cannot render {}", k.to_string()))?);
+        }
+        let h = gs.iter().map(|g| g.len()).max().unwrap_or(0);
+        let mut block = vec![vec![]; h];
+        for (i, g) in gs.iter().enumerate() {
+            let w = g[0].len();
+            for (r, row) in block.iter_mut().enumerate() {
+                if i > 0 {
+                    row.extend(vec![false; GAP]);
+                }
+                row.extend(g.get(r).cloned().unwrap_or_else(|| vec![false; w]));
+            }
+        }
+        if !rows.is_empty() {
+            rows.extend(vec![vec![]; GAP]);
+        }
+        rows.extend(block);
+    }
+    // One blank cell between the frame and the glyphs.
+    let w = rows.iter().map(|r| r.len()).max().unwrap_or(0) + 4;
+    let mut grid = vec![vec![true; w], vec![false; w]];
+    for row in rows {
+        let mut r = vec![true, false];
+        r.extend(row);
+        r.resize(w - 1, false);
+        r.push(true);
+        grid.push(r);
+    }
+    grid.push(vec![false; w]);
+    grid.push(vec![true; w]);
+    for r in 1..grid.len() - 1 {
+        grid[r][0] = true;
+        grid[r][w - 1] = true;
+    }
+    Ok(grid)
+}
+
+pub fn output_png(w: &mut impl std::io::Write, grid: &Grid) -> Result<(), Error> {
+    let (h, wd) = (grid.len() as u32, grid[0].len() as u32);
+    let img = image::RgbImage::from_fn(wd * SCALE, h * SCALE, |x, y| {
+        let v = if grid[(y / SCALE) as usize][(x / SCALE) as usize] {
+            255
+        } else {
+            0
+        };
+        image::Rgb([v, v, v])
+    });
+    image::DynamicImage::ImageRgb8(img).write_to(w, image::ImageOutputFormat::Png)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let text = "ap inc\n\
+                    :1029 = ap ap cons 7 ap ap cons -123229502148636 nil\n\
+                    ap ap ap s x0 x1 x12 = ap ap x0 x2 ap x1 x2\n\
+                    [ 0 , 1 ] = ap ap cons 0 ap ap cons 1 nil\n\
+                    ap mod -300 = Bin(-300) Bin(0) Bin(1) x0\n\
+                    t f i b c car cdr isnil neg lt eq mul div dem";
+        let lines = parse_text(text).unwrap();
+
+        let mut png = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+        output_png(&mut png, &render(&lines).unwrap()).unwrap();
+        let grid = crate::parse_file(png.path().to_str()).unwrap();
+        assert_eq!(grid, render(&lines).unwrap());
+
+        let glyphs = crate::parse(&grid, &Default::default()).unwrap();
+        let parsed = crate::lines(&glyphs)
+            .iter()
+            .map(|l| l.iter().map(|g| g.k.clone()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, lines);
+
+        let extremes =
+            parse_text("-9223372036854775808 9223372036854775807 Bin(-9223372036854775808)")
+                .unwrap();
+        assert!(render(&extremes).is_ok());
+
+        assert!(parse_text("ap foo").is_err());
+        assert!(render(&[vec![Kind::Pow(2)]]).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Transcribes "NAME is EXPR" lines of a message into galaxy.txt style
+// definitions, e.g. ":1029 = ap ap cons 7 nil".
+//
+// Only the vocabulary of galaxy.txt is emitted, so that the output can be
+// loaded by the galaxy evaluators and the decompiler: Succ becomes
+// "ap add 1", False "ap t i" and lists cons cells. Lines whose left side is
+// not a name (e.g. "ap Succ 0 is 1") are examples and are skipped.
+
+use super::{lines, Glyph, Kind};
+
+// The galaxy tokens of a glyph other than brackets.
+pub fn token(k: &Kind) -> Option<String> {
+    Some(
+        match k {
+            Kind::Int(n) => return Some(n.to_string()),
+            Kind::Apply => "ap",
+            Kind::Succ => "ap add 1",
+            Kind::Pred => "ap add -1",
+            Kind::Sum => "add",
+            Kind::Var(i) => return Some(format!("x{}", i)),
+            Kind::Product => "mul",
+            Kind::Quotient => "div",
+            Kind::Equals => "eq",
+            Kind::Bool(true) => "t",
+            Kind::Bool(false) => "ap t i",
+            Kind::LT => "lt",
+            Kind::Op("Neg") => "neg",
+            Kind::Op(r#"\f g x -> (f x) (g x)"#) => "s",
+            Kind::Op(r#"\f x y -> f y x"#) => "c",
+            Kind::Op(r#"\x y z -> x (y z)"#) => "b",
+            Kind::Op(r#"\x -> x"#) => "i",
+            Kind::Op("cos") => "cons",
+            Kind::Op(s) if ["car", "cdr", "nil", "isnil"].contains(s) => s,
+            // Numbered operators, e.g. the galaxy's :1029.
+            Kind::Unnamed(s) if s.parse::<u64>().is_ok() => return Some(format!(":{}", s)),
+            _ => return None,
+        }
+        .to_string(),
+    )
+}
+
+// Transcribes one expression starting at `ks[*pos]` into `out`.
+fn term(ks: &[&Kind], pos: &mut usize, out: &mut Vec<String>) -> Result<(), String> {
+    let k = ks.get(*pos).ok_or("unexpected end of expression")?;
+    *pos += 1;
+    match k {
+        Kind::Apply => {
+            out.push("ap".into());
+            term(ks, pos, out)?;
+            term(ks, pos, out)
+        }
+        Kind::LBra => {
+            let mut items = vec![];
+            if let Some(Kind::RBra) = ks.get(*pos) {
+                *pos += 1;
+            } else {
+                loop {
+                    let mut item = vec![];
+                    term(ks, pos, &mut item)?;
+                    items.push(item);
+                    *pos += 1;
+                    match ks.get(*pos - 1) {
+                        Some(Kind::Separator) => (),
+                        Some(Kind::RBra) => break,
+                        _ => return Err("expected ; or ] in a list".into()),
+                    }
+                }
+            }
+            for item in items {
+                out.push("ap ap cons".into());
+                out.extend(item);
+            }
+            out.push("nil".into());
+            Ok(())
+        }
+        k => {
+            out.push(token(k).ok_or_else(|| format!("cannot transcribe {}", k.to_string()))?);
+            Ok(())
+        }
+    }
+}
+
+fn expr(ks: &[&Kind]) -> Result<String, String> {
+    let mut pos = 0;
+    let mut out = vec![];
+    term(ks, &mut pos, &mut out)?;
+    if pos < ks.len() {
+        return Err(format!(
+            "unexpected {} after the expression",
+            ks[pos].to_string()
+        ));
+    }
+    Ok(out.join(" "))
+}
+
+// Transcribes a definition line, or returns None for other lines.
+fn definition(ks: &[&Kind]) -> Option<Result<String, String>> {
+    let is = ks.iter().position(|k| matches!(k, Kind::Is))?;
+    let name = match &ks[..is] {
+        [k] => token(k).filter(|t| t.starts_with(':'))?,
+        _ => return None,
+    };
+    Some(expr(&ks[is + 1..]).map(|e| format!("{} = {}", name, e)))
+}
+
+pub fn output_transcribed(
+    w: &mut impl std::io::Write,
+    glyphs: &[Glyph],
+) -> Result<(), std::io::Error> {
+    for (i, line) in lines(glyphs).iter().enumerate() {
+        let ks = line.iter().map(|g| &g.k).collect::<Vec<_>>();
+        match definition(&ks) {
+            Some(Ok(def)) => writeln!(w, "{}", def)?,
+            Some(Err(e)) => eprintln!("line {}: {}", i + 1, e),
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcribe_test() {
+        let t = |ks: Vec<Kind>| definition(&ks.iter().collect::<Vec<_>>());
+        let name = || Kind::Unnamed("1029".into());
+        assert_eq!(
+            t(vec![
+                name(),
+                Kind::Is,
+                Kind::Apply,
+                Kind::Succ,
+                Kind::Bool(false)
+            ]),
+            Some(Ok(":1029 = ap ap add 1 ap t i".into()))
+        );
+        assert_eq!(
+            t(vec![
+                name(),
+                Kind::Is,
+                Kind::LBra,
+                Kind::Int(0),
+                Kind::Separator,
+                Kind::LBra,
+                Kind::RBra,
+                Kind::RBra
+            ]),
+            Some(Ok(":1029 = ap ap cons 0 ap ap cons nil nil".into()))
+        );
+        assert_eq!(
+            t(vec![
+                Kind::Apply,
+                Kind::Succ,
+                Kind::Int(0),
+                Kind::Is,
+                Kind::Int(1)
+            ]),
+            None
+        );
+        assert_eq!(
+            t(vec![name(), Kind::Is, Kind::Apply, Kind::Int(1)]),
+            Some(Err("unexpected end of expression".into()))
+        );
+        assert_eq!(
+            t(vec![name(), Kind::Is, Kind::Op("arrow")]),
+            Some(Err("cannot transcribe arrow".into()))
+        );
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Reads the cell grid from a recording of the radio transmission.
+//
+// The transmission keys a tone on for lit cells and off for dark cells,
+// row by row. The recording is decoded as follows.
+//
+// 1. The power of the signal is averaged over a couple of periods of the
+//    tone, and split into on and off with a threshold between the two
+//    levels.
+// 2. The clock is recovered from the lengths of the runs of on and off,
+//    which are multiples of the bit length. Each run is then read as the
+//    nearest number of bits, so drift does not accumulate.
+// 3. The message starts with the top of its frame, a full row of lit
+//    cells followed by the lit left edge of the next row, so the first run
+//    of bits is one longer than the width.
+
+use super::{Error, Grid};
+
+// Samples of the first channel from -1 to 1, and the sample rate.
+fn samples(wav: &[u8]) -> Result<(Vec<f64>, u32), Error> {
+    if wav.len() < 12 || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err("not a wav file".into());
+    }
+    let u16_at = |i: usize| u16::from(wav[i]) | u16::from(wav[i + 1]) << 8;
+    let u32_at = |i: usize| u32::from(u16_at(i)) | u32::from(u16_at(i + 2)) << 16;
+    let mut format = None;
+    let mut pos = 12;
+    // The loop condition keeps the chunk header in bounds.
+    while pos + 8 <= wav.len() {
+        let (id, len) = (&wav[pos..pos + 4], u32_at(pos + 4) as usize);
+        let body = pos + 8;
+        let end = (body + len).min(wav.len());
+        match id {
+            b"fmt " if len >= 16 => {
+                if body + 16 > wav.len() {
+                    return Err("truncated fmt chunk".into());
+                }
+                // Format, channels, sample rate and bits per sample.
+                format = Some((
+                    u16_at(body),
+                    u16_at(body + 2),
+                    u32_at(body + 4),
+                    u16_at(body + 14),
+                ));
+            }
+            b"data" => {
+                let (tag, channels, rate, bits) = format.ok_or("no fmt chunk before data")?;
+                let size = bits as usize / 8;
+                let frame = size * channels as usize;
+                if frame == 0 {
+                    return Err("invalid fmt chunk".into());
+                }
+                let sample = |s: &[u8]| -> Option<f64> {
+                    Some(match (tag, bits) {
+                        (1, 8) => (f64::from(s[0]) - 128.0) / 128.0,
+                        (1, 16) => f64::from(i16::from_le_bytes([s[0], s[1]])) / 32768.0,
+                        (1, 24) => {
+                            f64::from(i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) / 8_388_608.0
+                        }
+                        (1, 32) => {
+                            f64::from(i32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+                                / 2_147_483_648.0
+                        }
+                        (3, 32) => f64::from(f32::from_le_bytes([s[0], s[1], s[2], s[3]])),
+                        _ => return None,
+                    })
+                };
+                let res = wav[body..end]
+                    .chunks_exact(frame)
+                    .map(|f| sample(&f[..size]))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(format!("unsupported format {} with {} bits", tag, bits))?;
+                return Ok((res, rate));
+            }
+            _ => (),
+        }
+        // Chunks are padded to even lengths.
+        pos = body + len + len % 2;
+    }
+    Err("no data chunk".into())
+}
+
+// The period of the tone in samples, from the crossings of zero where the
+// tone is on.
+fn tone_period(xs: &[f64]) -> f64 {
+    let loud = xs.iter().fold(0.0f64, |m, x| m.max(x.abs())) / 4.0;
+    let mut gaps = vec![];
+    let mut last = None;
+    for (i, x) in xs.windows(2).enumerate() {
+        if (x[0] < 0.0) != (x[1] < 0.0) {
+            if x[0].abs().max(x[1].abs()) > loud {
+                if let Some(l) = last {
+                    gaps.push(i - l);
+                }
+            }
+            last = Some(i);
+        }
+    }
+    if gaps.is_empty() {
+        return 2.0;
+    }
+    gaps.sort();
+    2.0 * gaps[gaps.len() / 2] as f64
+}
+
+// Whether the tone is on at each sample.
+fn keying(xs: &[f64]) -> Vec<bool> {
+    // Twice the period of the tone smooths out the tone itself.
+    let window = (2.0 * tone_period(xs)).ceil() as usize;
+    let mut env = Vec::with_capacity(xs.len());
+    let mut sum = 0.0;
+    for (i, x) in xs.iter().enumerate() {
+        sum += x * x;
+        if i >= window {
+            sum -= xs[i - window] * xs[i - window];
+        }
+        // Centered on the sample.
+        if i >= window / 2 {
+            env.push(sum / window as f64);
+        }
+    }
+    env.extend(vec![sum / window as f64; xs.len() - env.len()]);
+
+    // Two means between the lowest and highest levels.
+    let (mut lo, mut hi) = env
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(lo, hi), e| (lo.min(*e), hi.max(*e)));
+    for _ in 0..16 {
+        let t = (lo + hi) / 2.0;
+        let (on, off): (Vec<f64>, Vec<f64>) = env.iter().partition(|e| **e > t);
+        if on.is_empty() || off.is_empty() {
+            break;
+        }
+        lo = off.iter().sum::<f64>() / off.len() as f64;
+        hi = on.iter().sum::<f64>() / on.len() as f64;
+    }
+    env.iter().map(|e| *e > (lo + hi) / 2.0).collect()
+}
+
+// Lengths of the runs of equal values, with the values.
+fn runs(keys: &[bool]) -> Vec<(bool, usize)> {
+    let mut res: Vec<(bool, usize)> = vec![];
+    for k in keys {
+        match res.last_mut() {
+            Some((v, n)) if v == k => *n += 1,
+            _ => res.push((*k, 1)),
+        }
+    }
+    res
+}
+
+// The length of a bit in samples.
+fn clock(runs: &[(bool, usize)]) -> Result<f64, Error> {
+    // The silence before and after the message is not a multiple of it.
+    let inner = if runs.len() > 2 {
+        &runs[1..runs.len() - 1]
+    } else {
+        runs
+    };
+    let mut lens: Vec<usize> = inner.iter().map(|(_, n)| *n).collect();
+    lens.sort();
+    // The shortest runs not caused by noise are single bits.
+    let shortest = lens
+        .get(lens.len() / 20)
+        .ok_or("no signal in the recording")?;
+    let mut bit = *shortest as f64;
+    // Longer runs are counted once the bit is precise enough to tell how
+    // many bits they have.
+    for &most in &[2.0, 4.0, 8.0, 16.0, 32.0, f64::MAX] {
+        let (mut samples, mut bits) = (0.0, 0.0);
+        for n in lens.iter() {
+            let k = (*n as f64 / bit).round();
+            if k >= 1.0 && k <= most {
+                samples += *n as f64;
+                bits += k;
+            }
+        }
+        bit = samples / bits;
+    }
+    Ok(bit)
+}
+
+pub struct Decoded {
+    pub grid: Grid,
+    pub bit: f64,
+    pub rate: u32,
+}
+
+pub fn decode(wav: &[u8]) -> Result<Decoded, Error> {
+    let (xs, rate) = samples(wav)?;
+    let runs = runs(&keying(&xs));
+    let bit = clock(&runs)?;
+
+    let mut bits = vec![];
+    for (v, n) in runs.iter() {
+        bits.extend(vec![*v; (*n as f64 / bit).round() as usize]);
+    }
+    let first = bits
+        .iter()
+        .position(|b| *b)
+        .ok_or("no signal in the recording")?;
+    let last = bits.iter().rposition(|b| *b).unwrap();
+    let bits = &bits[first..=last];
+
+    let width = bits.iter().position(|b| !b).unwrap_or(bits.len()) - 1;
+    if width < 3 {
+        return Err("the message has no frame".into());
+    }
+    let grid = bits
+        .chunks(width)
+        .map(|row| {
+            let mut row = row.to_vec();
+            row.resize(width, false);
+            row
+        })
+        .collect();
+    Ok(Decoded { grid, bit, rate })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 16 bit mono recording of the grid.
+    fn record(grid: &Grid, bit: f64, tone: f64) -> Vec<u8> {
+        let bits: Vec<bool> = grid.iter().flatten().cloned().collect();
+        let silence = 1000;
+        let len = 2 * silence + (bits.len() as f64 * bit) as usize;
+        let mut seed = 1u64;
+        let data: Vec<u8> = (0..len)
+            .flat_map(|i| {
+                let t = i as f64 - silence as f64;
+                let on = t >= 0.0 && bits.get((t / bit) as usize) == Some(&true);
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let noise = ((seed >> 40) as f64 / (1u64 << 24) as f64 - 0.5) * 0.2;
+                let x = if on {
+                    0.6 * (2.0 * std::f64::consts::PI * i as f64 / tone).sin()
+                } else {
+                    0.0
+                };
+                (((x + noise) * 32767.0) as i16).to_le_bytes().to_vec()
+            })
+            .collect();
+        let mut wav = b"RIFF".to_vec();
+        wav.extend(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(&16u32.to_le_bytes());
+        // PCM, mono, 8000 Hz, 16000 bytes per second, 2 bytes per frame,
+        // 16 bits.
+        for (v, n) in &[(1, 2), (1, 2), (8000, 4), (16000, 4), (2, 2), (16, 2)] {
+            wav.extend(&(*v as u32).to_le_bytes()[..*n]);
+        }
+        wav.extend(b"data");
+        wav.extend(&(data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+
+    #[test]
+    fn wav_test() {
+        let text = "ap inc 1 = 2\n:1029 = ap ap cons 7 nil";
+        let grid = crate::render::render(&crate::render::parse_text(text).unwrap()).unwrap();
+        for &(bit, tone) in &[(12.7, 4.3), (31.0, 5.0), (9.3, 3.1), (50.2, 13.1)] {
+            let decoded = decode(&record(&grid, bit, tone)).unwrap();
+            assert!((decoded.bit - bit).abs() < 0.05, "{} {}", bit, decoded.bit);
+            assert_eq!(decoded.rate, 8000);
+            assert_eq!(decoded.grid, grid, "{}", bit);
+        }
+
+        assert!(decode(b"RIFF....WAVE").is_err());
+        assert!(decode(b"not a wav").is_err());
+
+        // Cut in the fmt chunk, in the header of the data chunk and at the
+        // start of the data.
+        let wav = record(&grid, 12.7, 4.3);
+        for &n in &[30, 40, 47] {
+            assert!(decode(&wav[..n]).is_err(), "{}", n);
+        }
+    }
+}
@@ -0,0 +1,483 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Compiles the program back to galaxy combinator text, which the galaxy
+// evaluators load like the original galaxy.txt:
+//   expr_parser diff --galaxy recompiled.txt trace.txt
+//
+// Lambdas are removed by bracket abstraction with Turner's optimizations
+// (K, I, eta, B and C), so code stays within a small factor of the
+// original. The program must use the original names of the definitions
+// (e.g. :1029), as galaxy.txt does. List primitives from combinators.rs
+// become definitions named prim_map etc. after the program.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use super::Program;
+use super::super::combinators::Kind;
+use super::super::expr::Expr;
+use super::super::rewrite::from_pattern;
+
+// The list primitives in the rule syntax, with the semantics of the other
+// runtimes; both folds call (f acc x).
+fn prim_source(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Map => "(fun (xs f) (match xs (list) h t (call @cons (call f h) (call prim_map t f))))",
+        Kind::Filter =>
+            "(fun (xs p) (match xs (list) h t (if (call p h) (call @cons h (call prim_filter t p)) (call prim_filter t p))))",
+        Kind::Foldl => "(fun (xs z f) (match xs z h t (call prim_foldl t (call f z h) f)))",
+        Kind::Foldr => "(fun (xs z f) (match xs z h t (call f (call prim_foldr t z f) h)))",
+        Kind::ZipWith =>
+            "(fun (xs ys f) (match xs (list) x xt (match ys (list) y yt (call @cons (call f x y) (call prim_zip_with xt yt f)))))",
+        Kind::Concat => "(fun (xs ys) (match xs ys h t (call @cons h (call prim_concat t ys))))",
+        Kind::Length => "(fun (xs) (match xs 0 h t (add 1 (call prim_length t))))",
+        Kind::Nth => "(fun (xs n) (if (eq n 0) (call @car xs) (call prim_nth (call @cdr xs) (sub n 1))))",
+    }
+}
+
+// A combinator term. Variables only remain while their lambda is being
+// abstracted.
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum Term {
+    Var(String),
+    Atom(String),
+    Ap(Box<Term>, Box<Term>),
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Term::Var(s) | Term::Atom(s) => write!(f, "{}", s),
+            Term::Ap(fun, arg) => write!(f, "ap {} {}", fun, arg),
+        }
+    }
+}
+
+fn atom(s: &str) -> Term {
+    Term::Atom(s.to_string())
+}
+
+fn ap(fun: Term, arg: Term) -> Term {
+    Term::Ap(Box::new(fun), Box::new(arg))
+}
+
+fn apply(fun: Term, args: Vec<Term>) -> Term {
+    args.into_iter().fold(fun, ap)
+}
+
+fn occurs(x: &str, t: &Term) -> bool {
+    match t {
+        Term::Var(s) => s == x,
+        Term::Atom(_) => false,
+        Term::Ap(fun, arg) => occurs(x, fun) || occurs(x, arg),
+    }
+}
+
+// [x]t, a term without x which applied to x is t.
+fn abstract_var(x: &str, t: Term) -> Term {
+    if !occurs(x, &t) {
+        return ap(atom("t"), t);
+    }
+    match t {
+        Term::Ap(fun, arg) => {
+            if *arg == Term::Var(x.to_string()) && !occurs(x, &fun) {
+                return *fun;
+            }
+            match (occurs(x, &fun), occurs(x, &arg)) {
+                (true, true) => apply(atom("s"), vec![abstract_var(x, *fun), abstract_var(x, *arg)]),
+                (true, false) => apply(atom("c"), vec![abstract_var(x, *fun), *arg]),
+                _ => apply(atom("b"), vec![*fun, abstract_var(x, *arg)]),
+            }
+        },
+        _ => atom("i"),
+    }
+}
+
+fn closed(params: &[&str], body: Term) -> Term {
+    params.iter().rev().fold(body, |t, p| abstract_var(p, t))
+}
+
+fn var(s: &str) -> Term {
+    Term::Var(s.to_string())
+}
+
+struct Compiler {
+    // Local variables in scope.
+    scope: Vec<String>,
+    // Primitives used so far.
+    prims: Vec<Kind>,
+}
+
+impl Compiler {
+    fn lambda(&mut self, params: &[&String], body: &Expr) -> Term {
+        let n = self.scope.len();
+        self.scope.extend(params.iter().map(|s| s.to_string()));
+        let body = self.expr(body);
+        self.scope.truncate(n);
+        params.iter().rev().fold(body, |t, p| abstract_var(p, t))
+    }
+
+    fn apply(&mut self, fun: Term, args: &[&Expr]) -> Term {
+        let args = args.iter().map(|e| self.expr(e)).collect();
+        apply(fun, args)
+    }
+
+    fn expr(&mut self, e: &Expr) -> Term {
+        match e {
+            Expr::Int(n) => atom(&n.to_string()),
+            Expr::Symbol(s) if self.scope.contains(s) => var(s),
+            Expr::Symbol(s) => atom(s),
+            Expr::List(items) => items.iter().rev().fold(atom("nil"), |tl, hd| {
+                let hd = self.expr(hd);
+                apply(atom("cons"), vec![hd, tl])
+            }),
+            Expr::True => atom("t"),
+            Expr::False => ap(atom("t"), atom("i")),
+            Expr::Neg(a) => self.apply(atom("neg"), &[a]),
+            Expr::Add(a, b) => self.apply(atom("add"), &[a, b]),
+            Expr::Sub(a, b) => {
+                let b = self.apply(atom("neg"), &[b]);
+                let a = self.expr(a);
+                apply(atom("add"), vec![a, b])
+            },
+            Expr::Mul(a, b) => self.apply(atom("mul"), &[a, b]),
+            Expr::Div(a, b) => self.apply(atom("div"), &[a, b]),
+            // a - a / b * b, with a and b bound once.
+            Expr::Mod(a, b) => {
+                let (x, y) = (|| var("a"), || var("b"));
+                let q = apply(atom("mul"), vec![apply(atom("div"), vec![x(), y()]), y()]);
+                let rem = closed(&["a", "b"], apply(atom("add"), vec![x(), ap(atom("neg"), q)]));
+                self.apply(rem, &[a, b])
+            },
+            Expr::Equ(a, b) => self.apply(atom("eq"), &[a, b]),
+            Expr::Lt(a, b) => self.apply(atom("lt"), &[a, b]),
+            // Booleans select one of two arguments.
+            Expr::Not(a) => {
+                let a = self.expr(a);
+                apply(a, vec![self.expr(&Expr::False), atom("t")])
+            },
+            Expr::Or(a, b) => {
+                let a = self.expr(a);
+                apply(a, vec![atom("t"), self.expr(b)])
+            },
+            Expr::And(a, b) => {
+                let a = self.expr(a);
+                apply(a, vec![self.expr(b), self.expr(&Expr::False)])
+            },
+            Expr::If(c, a, b) => {
+                let c = self.expr(c);
+                self.apply(c, &[a, b])
+            },
+            Expr::IsNil(a) => self.apply(atom("isnil"), &[a]),
+            // A cons applied to f is (f hd tl).
+            Expr::MatchCons(v, e1, hd, tl, e2) => {
+                let on_cons = self.lambda(&[hd, tl], e2);
+                let select = closed(&["v", "n", "f"],
+                                    apply(ap(atom("isnil"), var("v")), vec![var("n"), ap(var("v"), var("f"))]));
+                let args = vec![self.expr(v), self.expr(e1), on_cons];
+                apply(select, args)
+            },
+            Expr::LetCons(hd, tl, e1, e2) => {
+                let e1 = self.expr(e1);
+                ap(e1, self.lambda(&[hd, tl], e2))
+            },
+            Expr::Let(x, e1, e2) => {
+                let body = self.lambda(&[x], e2);
+                ap(body, self.expr(e1))
+            },
+            Expr::Call(vals) => {
+                let fun = self.expr(&vals[0]);
+                self.apply(fun, &vals[1..].iter().collect::<Vec<_>>())
+            },
+            Expr::ADD => atom("add"),
+            Expr::CAR => atom("car"),
+            Expr::CDR => atom("cdr"),
+            Expr::CONS => atom("cons"),
+            Expr::DIV => atom("div"),
+            Expr::EQ => atom("eq"),
+            Expr::ISNIL => atom("isnil"),
+            Expr::LT => atom("lt"),
+            Expr::MUL => atom("mul"),
+            Expr::NEG => atom("neg"),
+            Expr::Func(params, body) => self.lambda(&params.iter().collect::<Vec<_>>(), body),
+            Expr::Prim(kind) => {
+                if !self.prims.contains(kind) {
+                    self.prims.push(*kind);
+                }
+                atom(&format!("prim_{}", kind.name()))
+            },
+        }
+    }
+}
+
+pub fn emit(program: &Program, w: &mut dyn Write) -> io::Result<()> {
+    let mut compiler = Compiler{scope: Vec::new(), prims: Vec::new()};
+    for g in program.globals.iter() {
+        let term = compiler.lambda(&g.params.iter().collect::<Vec<_>>(), &g.body);
+        writeln!(w, "{} = {}", g.name, term)?;
+    }
+    let mut i = 0;
+    while i < compiler.prims.len() {
+        let kind = compiler.prims[i];
+        let term = compiler.expr(&from_pattern(prim_source(kind)).unwrap());
+        writeln!(w, "prim_{} = {}", kind.name(), term)?;
+        i += 1;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_galaxy() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use super::super::{ast, combinators, expr, lambda, parser, rewrite, simplified};
+
+    // A lazy evaluator sharing evaluated nodes, like the galaxy evaluators.
+    #[derive(Clone)]
+    enum Node {
+        Int(isize),
+        Global(String),
+        Ap(Rc<RefCell<Node>>, Rc<RefCell<Node>>),
+        // A partially applied combinator.
+        Op(&'static str, Vec<Rc<RefCell<Node>>>),
+    }
+    type Cell = Rc<RefCell<Node>>;
+
+    // The definitions, and the number of reductions left for the current
+    // probe, since probes with arguments of the wrong type may not
+    // terminate.
+    struct Globals {
+        defs: HashMap<String, Cell>,
+        fuel: std::cell::Cell<usize>,
+    }
+
+    fn cell(node: Node) -> Cell {
+        Rc::new(RefCell::new(node))
+    }
+    fn ap(f: Cell, x: Cell) -> Cell {
+        cell(Node::Ap(f, x))
+    }
+    fn op(name: &'static str) -> Cell {
+        cell(Node::Op(name, Vec::new()))
+    }
+    fn from_ast(v: &ast::Value) -> Cell {
+        cell(match v {
+            ast::Value::Apply(f, x) => Node::Ap(from_ast(f), from_ast(x)),
+            ast::Value::SYMBOL(s) => Node::Global(s.clone()),
+            ast::Value::INT(n) => Node::Int(*n),
+            v => Node::Op(match v {
+                ast::Value::ADD => "add", ast::Value::B => "b", ast::Value::C => "c", ast::Value::CAR => "car",
+                ast::Value::CDR => "cdr", ast::Value::CONS => "cons", ast::Value::DIV => "div",
+                ast::Value::EQ => "eq", ast::Value::I => "i", ast::Value::ISNIL => "isnil", ast::Value::LT => "lt",
+                ast::Value::MUL => "mul", ast::Value::NEG => "neg", ast::Value::NIL => "nil",
+                ast::Value::S => "s", _ => "t",
+            }, Vec::new()),
+        })
+    }
+    fn arity(name: &str) -> usize {
+        match name {
+            "i" | "neg" | "car" | "cdr" | "nil" | "isnil" => 1,
+            "s" | "b" | "c" | "cons" => 3,
+            _ => 2,
+        }
+    }
+    fn int(c: &Cell, g: &Globals) -> Result<isize, String> {
+        match eval(c, g)? {
+            Node::Int(n) => Ok(n),
+            _ => Err("not an int".to_string()),
+        }
+    }
+    fn boolean(b: bool) -> Node {
+        Node::Op(if b { "t" } else { "f" }, Vec::new())
+    }
+    // Reduces to an int or a partial application.
+    fn eval(c: &Cell, g: &Globals) -> Result<Node, String> {
+        loop {
+            if g.fuel.get() == 0 {
+                return Err("out of fuel".to_string());
+            }
+            g.fuel.set(g.fuel.get() - 1);
+            let node = c.borrow().clone();
+            let next = match node {
+                Node::Int(_) | Node::Op(_, _) => return Ok(node),
+                Node::Global(name) => eval(&g.defs[&name], g)?,
+                Node::Ap(f, x) => match eval(&f, g)? {
+                    Node::Op(name, mut args) => {
+                        args.push(x);
+                        if args.len() < arity(name) {
+                            Node::Op(name, args)
+                        } else {
+                            let a = &args;
+                            match name {
+                                "i" => eval(&a[0], g)?,
+                                "t" => eval(&a[0], g)?,
+                                "f" => eval(&a[1], g)?,
+                                "s" => Node::Ap(ap(a[0].clone(), a[2].clone()), ap(a[1].clone(), a[2].clone())),
+                                "b" => Node::Ap(a[0].clone(), ap(a[1].clone(), a[2].clone())),
+                                "c" => Node::Ap(ap(a[0].clone(), a[2].clone()), a[1].clone()),
+                                "cons" => Node::Ap(ap(a[2].clone(), a[0].clone()), a[1].clone()),
+                                "car" => Node::Ap(a[0].clone(), op("t")),
+                                "cdr" => Node::Ap(a[0].clone(), op("f")),
+                                "nil" => boolean(true),
+                                "isnil" => match eval(&a[0], g)? {
+                                    Node::Op("nil", v) => boolean(v.is_empty()),
+                                    _ => boolean(false),
+                                },
+                                "neg" => Node::Int(-int(&a[0], g)?),
+                                "add" => Node::Int(int(&a[0], g)?.wrapping_add(int(&a[1], g)?)),
+                                "mul" => Node::Int(int(&a[0], g)?.wrapping_mul(int(&a[1], g)?)),
+                                "div" => Node::Int(int(&a[0], g)?.checked_div(int(&a[1], g)?).ok_or("division by zero")?),
+                                "eq" => boolean(int(&a[0], g)? == int(&a[1], g)?),
+                                _ => boolean(int(&a[0], g)? < int(&a[1], g)?),
+                            }
+                        }
+                    },
+                    _ => return Err("cannot apply an int".to_string()),
+                },
+            };
+            *c.borrow_mut() = next;
+        }
+    }
+    // Prints data, with functions other than lists as "<fun>".
+    fn show(c: &Cell, g: &Globals) -> Result<String, String> {
+        Ok(match eval(c, g)? {
+            Node::Int(n) => n.to_string(),
+            Node::Op("nil", v) if v.is_empty() => "nil".to_string(),
+            Node::Op("cons", v) if v.len() == 2 => format!("({} . {})", show(&v[0], g)?, show(&v[1], g)?),
+            _ => "<fun>".to_string(),
+        })
+    }
+    fn is_function(c: &Cell, g: &Globals) -> bool {
+        match eval(c, g) {
+            Ok(Node::Op(name, v)) => !(name == "nil" && v.is_empty() || name == "cons" && v.len() == 2),
+            _ => false,
+        }
+    }
+    // The result of applying a function to `args` one by one, as long as it
+    // stays a function. None if the evaluation fails: arguments of the wrong
+    // type may fail in one version and not in the other, whose primitives
+    // are built differently.
+    fn probe(f: &Cell, args: &[Cell], g: &Globals) -> Option<String> {
+        g.fuel.set(200_000);
+        let mut c = f.clone();
+        for x in args {
+            if !is_function(&c, g) {
+                break;
+            }
+            c = ap(c, x.clone());
+        }
+        show(&c, g).ok()
+    }
+    fn probes() -> Vec<Vec<Cell>> {
+        let n = |i| cell(Node::Int(i));
+        let list = |items: &[isize]| items.iter().rev()
+            .fold(op("nil"), |tl, &i| ap(ap(op("cons"), n(i)), tl));
+        let pair = |a, b| ap(ap(op("cons"), n(a)), n(b));
+        let pairs = |items: &[(isize, isize)]| items.iter().rev()
+            .fold(op("nil"), |tl, &(a, b)| ap(ap(op("cons"), pair(a, b)), tl));
+        vec![
+            vec![n(0), n(0), n(0)],
+            vec![n(1), n(2), n(3)],
+            vec![n(3), n(-1), n(1)],
+            vec![op("nil"), n(0), n(1)],
+            vec![list(&[1, 2, 3]), n(1), n(0)],
+            vec![list(&[5, 0, 7]), op("nil"), n(2)],
+            vec![pair(2, 3), pair(-1, 4), n(2)],
+            vec![n(2), list(&[4, 1]), pair(1, 1)],
+            vec![list(&(0..13).collect::<Vec<_>>()), n(4), n(1)],
+            vec![pairs(&[(1, 2), (3, 4)]), pair(0, 1), n(1)],
+            vec![pair(0, 0), pairs(&[(-2, 5)]), n(3)],
+        ]
+    }
+    fn load(content: &str) -> (Vec<String>, Globals) {
+        let (defs, errors) = parser::parse(content);
+        assert!(errors.is_empty(), "{}", errors[0]);
+        let names = defs.iter().map(|d| d.name.clone()).collect();
+        let defs = defs.iter().map(|d| (d.name.clone(), from_ast(&d.value))).collect();
+        (names, Globals{defs, fuel: std::cell::Cell::new(0)})
+    }
+
+    fn run() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../tanakh/expr_parser/galaxy.txt");
+        let content = std::fs::read_to_string(path).unwrap();
+
+        // Decompiles as main.rs does, without symbols.
+        let env = expr::Env::new();
+        let mut rules = rewrite::Rules::parse(rewrite::SIMPLIFY).unwrap();
+        rules.extend(rewrite::Rules::parse(rewrite::IDIOMS).unwrap());
+        let defs = parser::parse(&content).0.iter().map(|d| {
+            let value = simplified::simplify(lambda::eval(lambda::lamdify(&d.value)));
+            let e = expr::construct(&value, &env, HashMap::new());
+            let e = rules.rewrite(&e, &d.name, &env).unwrap_or(e);
+            (d.name.clone(), e)
+        }).collect::<Vec<_>>();
+        let recognized = combinators::recognize(&defs);
+        assert!(!recognized.is_empty());
+        let defs = defs.iter().map(|(name, e)| (name.clone(), combinators::rewrite(e, name, &recognized))).collect();
+        let mut out = Vec::new();
+        emit(&Program::new(defs), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.split_whitespace().filter(|s| *s == "ap").count() <
+                2 * content.split_whitespace().filter(|s| *s == "ap").count());
+
+        // Data is compared as is, functions by their results on the probes.
+        let (names, orig) = load(&content);
+        let (_, recompiled) = load(&out);
+        let (mut probed, mut functions, mut covered) = (0, 0, 0);
+        for name in names.iter() {
+            let (a, b) = (&orig.defs[name], &recompiled.defs[name]);
+            orig.fuel.set(1_000_000);
+            if !is_function(a, &orig) {
+                recompiled.fuel.set(1_000_000);
+                assert_eq!(show(a, &orig), show(b, &recompiled), "{}", name);
+                continue;
+            }
+            functions += 1;
+            let before = probed;
+            for args in probes() {
+                // Functions in the result cannot be compared, and come from
+                // arguments the definition does not expect, e.g. an index
+                // past the end of the list.
+                let x = probe(a, &args, &orig).filter(|x| !x.contains("<fun>"));
+                if let (Some(x), Some(y)) = (x, probe(b, &args, &recompiled)) {
+                    assert_eq!(x, y, "{} applied to probe {}", name, probed);
+                    probed += 1;
+                }
+            }
+            covered += (probed > before) as usize;
+        }
+        assert!(covered > functions / 2, "{} probes, {} of {} functions", probed, covered, functions);
+
+        // A few steps of the galaxy protocol: (flag, state, images).
+        let mut states = (op("nil"), op("nil"));
+        for (x, y) in [(0, 0), (0, 0), (8, 4), (2, -8), (3, 6)] {
+            let step = |state: Cell, g: &Globals| {
+                let point = ap(ap(op("cons"), cell(Node::Int(x))), cell(Node::Int(y)));
+                let result = ap(ap(g.defs["galaxy"].clone(), state), point);
+                let state = ap(op("car"), ap(op("cdr"), result.clone()));
+                g.fuel.set(usize::MAX);
+                (show(&result, g).unwrap(), state)
+            };
+            let (a, s1) = step(states.0, &orig);
+            let (b, s2) = step(states.1, &recompiled);
+            assert_eq!(a, b);
+            states = (s1, s2);
+        }
+    }
+    // The evaluation recurses deeply.
+    std::thread::Builder::new().stack_size(1 << 30).spawn(run).unwrap().join().unwrap();
+}
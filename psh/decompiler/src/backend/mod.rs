@@ -0,0 +1,183 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Backends emitting the decompiled program as source code of a real
+// programming language.
+//
+// Values are dynamically typed in the emitted code: an int, nil, a cons
+// cell, a boolean or a curried function. Applying a boolean, nil or a cons
+// behaves like the corresponding combinator, so code that uses them as
+// functions (e.g. "_x5(fun(_x6, _x7) -> ...)") still works.
+//
+// The emitted program evaluates strictly except for the branches of
+// if/match/&&/||. It takes the initial state and clicks on the command line
+// and prints the interaction as a trace that expr_parser's "diff" command
+// can check against the evaluator:
+//   ./galaxy '()' 0 0 8 4 > trace.txt
+//
+// The galaxy backend instead compiles the program back to combinator text
+// for the galaxy evaluators. See galaxy.rs.
+
+pub mod galaxy;
+pub mod python;
+pub mod rust;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::Write;
+use super::expr::Expr;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Lang {
+    Rust,
+    Python,
+    Galaxy,
+}
+
+impl Lang {
+    pub fn from_name(name: &str) -> Option<Lang> {
+        match name {
+            "rust" | "rs" => Some(Lang::Rust),
+            "python" | "py" => Some(Lang::Python),
+            "galaxy" | "txt" => Some(Lang::Galaxy),
+            _ => None,
+        }
+    }
+}
+
+// A top level definition. Functions are split into their parameters and
+// body so that they can be emitted as native functions.
+pub struct Global {
+    pub name: String,
+    pub ident: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+}
+
+pub struct Program {
+    pub globals: Vec<Global>,
+    index: HashMap<String, usize>,
+}
+
+impl Program {
+    // `defs` are the renamed definitions, as printed by the decompiler.
+    pub fn new(defs: Vec<(String, Expr)>) -> Self {
+        let mut globals = Vec::new();
+        let mut index = HashMap::new();
+        let mut idents = BTreeSet::new();
+        for (name, expr) in defs {
+            let mut ident = format!("g_{}", sanitize(name.trim_start_matches(':')));
+            while !idents.insert(ident.clone()) {
+                ident.push('_');
+            }
+            let (params, body) = match expr {
+                Expr::Func(params, body) => (params, *body),
+                e => (Vec::new(), e),
+            };
+            index.insert(name.clone(), globals.len());
+            globals.push(Global{name, ident, params, body});
+        }
+        Self{globals, index}
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Global> {
+        self.index.get(name).map(|i| &self.globals[*i])
+    }
+}
+
+pub fn emit(program: &Program, lang: Lang, w: &mut dyn Write) -> io::Result<()> {
+    match lang {
+        Lang::Rust => rust::emit(program, w),
+        Lang::Python => python::emit(program, w),
+        Lang::Galaxy => galaxy::emit(program, w),
+    }
+}
+
+// Turns a name into a valid identifier in both Rust and Python.
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+fn local(name: &str) -> String {
+    format!("l{}{}", if name.starts_with('_') { "" } else { "_" }, sanitize(name))
+}
+
+// Collects the symbols referenced but not bound in `e`.
+fn free_vars(e: &Expr, bound: &mut Vec<String>, out: &mut BTreeSet<String>) {
+    fn bind(names: &[&String], bound: &mut Vec<String>, out: &mut BTreeSet<String>, e: &Expr) {
+        let n = bound.len();
+        bound.extend(names.iter().map(|s| s.to_string()));
+        free_vars(e, bound, out);
+        bound.truncate(n);
+    }
+    match e {
+        Expr::Symbol(s) if !bound.contains(s) => {
+            out.insert(s.clone());
+        },
+        Expr::List(v) | Expr::Call(v) => v.iter().for_each(|e| free_vars(e, bound, out)),
+        Expr::Neg(e) | Expr::Not(e) | Expr::IsNil(e) => free_vars(e, bound, out),
+        Expr::Add(e1, e2) | Expr::Sub(e1, e2) | Expr::Mul(e1, e2) | Expr::Div(e1, e2) | Expr::Mod(e1, e2) |
+        Expr::Equ(e1, e2) | Expr::Lt(e1, e2) | Expr::Or(e1, e2) | Expr::And(e1, e2) => {
+            free_vars(e1, bound, out);
+            free_vars(e2, bound, out);
+        },
+        Expr::If(e1, e2, e3) => {
+            free_vars(e1, bound, out);
+            free_vars(e2, bound, out);
+            free_vars(e3, bound, out);
+        },
+        Expr::MatchCons(v, e1, hd, tl, e2) => {
+            free_vars(v, bound, out);
+            free_vars(e1, bound, out);
+            bind(&[hd, tl], bound, out, e2);
+        },
+        Expr::LetCons(hd, tl, e1, e2) => {
+            free_vars(e1, bound, out);
+            bind(&[hd, tl], bound, out, e2);
+        },
+        Expr::Let(var, e1, e2) => {
+            free_vars(e1, bound, out);
+            bind(&[var], bound, out, e2);
+        },
+        Expr::Func(params, body) => bind(&params.iter().collect::<Vec<_>>(), bound, out, body),
+        _ => (),
+    }
+}
+
+#[test]
+fn test_program() {
+    let defs = vec![
+        (":1".to_string(), Expr::Func(vec!["_x0".to_string()],
+                                      Box::new(Expr::Add(Box::new(Expr::Symbol("_x0".to_string())),
+                                                         Box::new(Expr::Symbol("List.len".to_string())))))),
+        ("List.len".to_string(), Expr::Int(3)),
+        ("List_len".to_string(), Expr::Int(4)),
+    ];
+    let program = Program::new(defs);
+    assert_eq!(program.get(":1").unwrap().ident, "g_1");
+    assert_eq!(program.get(":1").unwrap().params, vec!["_x0".to_string()]);
+    assert_eq!(program.get("List.len").unwrap().ident, "g_List_len");
+    assert_eq!(program.get("List_len").unwrap().ident, "g_List_len_");
+
+    let mut free = BTreeSet::new();
+    free_vars(&Expr::Func(vec!["_x0".to_string()], Box::new(program.get(":1").unwrap().body.clone())),
+              &mut Vec::new(), &mut free);
+    assert_eq!(free.into_iter().collect::<Vec<_>>(), vec!["List.len".to_string()]);
+
+    let mut out = Vec::new();
+    emit(&program, Lang::Python, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("def g_1(l_x0):\n    return add(l_x0, g_List_len())"));
+}
@@ -0,0 +1,164 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Emits the program as a single Python 3 script. Local bindings become
+// immediately applied lambdas, so every definition is one expression.
+
+use std::io;
+use std::io::Write;
+use super::{local, Program};
+use super::super::expr::Expr;
+
+const RUNTIME: &str = include_str!("runtime.py.in");
+
+struct Emitter<'a> {
+    program: &'a Program,
+    // Local variables in scope.
+    scope: Vec<String>,
+}
+
+impl<'a> Emitter<'a> {
+    fn bind<F: FnOnce(&mut Self) -> String>(&mut self, names: &[&String], f: F) -> String {
+        let n = self.scope.len();
+        self.scope.extend(names.iter().map(|s| s.to_string()));
+        let ret = f(self);
+        self.scope.truncate(n);
+        ret
+    }
+
+    // A global used as a value.
+    fn global_value(&self, name: &str) -> String {
+        match self.program.get(name) {
+            Some(g) if g.params.is_empty() => format!("{}()", g.ident),
+            Some(g) => format!("Fun({}, {})", g.params.len(), g.ident),
+            None => format!("undefined({:?})", name),
+        }
+    }
+
+    fn call(&mut self, vals: &[Expr]) -> String {
+        let args = vals[1..].iter().map(|e| self.expr(e)).collect::<Vec<_>>();
+        let direct = |name: &str, arity: usize| -> Option<String> {
+            if args.len() < arity {
+                return None;
+            }
+            let call = format!("{}({})", name, args[..arity].join(", "));
+            Some(if args.len() == arity {
+                call
+            } else {
+                format!("apply_all({}, {})", call, args[arity..].join(", "))
+            })
+        };
+        let called = match &vals[0] {
+            Expr::Symbol(s) if !self.scope.contains(s) => match self.program.get(s) {
+                Some(g) if !g.params.is_empty() => direct(&g.ident, g.params.len()),
+                _ => None,
+            },
+            Expr::CAR => direct("car", 1),
+            Expr::CDR => direct("cdr", 1),
+            Expr::CONS => direct("cons", 2),
+            Expr::ISNIL => direct("is_nil", 1),
+            Expr::Prim(kind) => direct(&format!("prim_{}", kind.name()), kind.arity()),
+            _ => None,
+        };
+        match called {
+            Some(code) => code,
+            None => format!("apply_all({}, {})", self.expr(&vals[0]), args.join(", ")),
+        }
+    }
+
+    fn lambda(&mut self, params: &[&String], body: &Expr) -> String {
+        let names = params.iter().map(|p| local(p)).collect::<Vec<_>>();
+        let body = self.bind(params, |s| s.expr(body));
+        if names.is_empty() {
+            format!("(lambda: {})", body)
+        } else {
+            format!("(lambda {}: {})", names.join(", "), body)
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> String {
+        match e {
+            Expr::Int(i) => i.to_string(),
+            Expr::Symbol(s) if self.scope.contains(s) => local(s),
+            Expr::Symbol(s) => self.global_value(s),
+            Expr::List(items) =>
+                format!("make_list({})", items.iter().map(|e| self.expr(e)).collect::<Vec<_>>().join(", ")),
+            Expr::True => "True".to_string(),
+            Expr::False => "False".to_string(),
+            Expr::Neg(e) => format!("neg({})", self.expr(e)),
+            Expr::Not(e) => format!("lnot({})", self.expr(e)),
+            Expr::IsNil(e) => format!("is_nil({})", self.expr(e)),
+            Expr::Add(lhs, rhs) => format!("add({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Sub(lhs, rhs) => format!("sub({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Mul(lhs, rhs) => format!("mul({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Div(lhs, rhs) => format!("div({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Mod(lhs, rhs) => format!("rem({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Equ(lhs, rhs) => format!("eq({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Lt(lhs, rhs) => format!("lt({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Or(lhs, rhs) => format!("(True if truthy({}) else {})", self.expr(lhs), self.expr(rhs)),
+            Expr::And(lhs, rhs) => format!("({} if truthy({}) else False)", self.expr(rhs), self.expr(lhs)),
+            Expr::If(cond, e1, e2) => {
+                let cond = self.expr(cond);
+                format!("({} if truthy({}) else {})", self.expr(e1), cond, self.expr(e2))
+            },
+            Expr::MatchCons(v, e1, hd, tl, e2) => {
+                let v = self.expr(v);
+                let e1 = self.lambda(&[], e1);
+                let e2 = self.lambda(&[hd, tl], e2);
+                format!("match_cons({}, {}, {})", v, e1, e2)
+            },
+            Expr::LetCons(hd, tl, e1, e2) => {
+                let e1 = self.expr(e1);
+                format!("{}(*uncons({}))", self.lambda(&[hd, tl], e2), e1)
+            },
+            Expr::Let(var, e1, e2) => {
+                let e1 = self.expr(e1);
+                format!("{}({})", self.lambda(&[var], e2), e1)
+            },
+            Expr::Call(vals) => self.call(vals),
+            Expr::ADD => "Fun(2, add)".to_string(),
+            Expr::CAR => "Fun(1, car)".to_string(),
+            Expr::CDR => "Fun(1, cdr)".to_string(),
+            Expr::CONS => "Fun(2, cons)".to_string(),
+            Expr::DIV => "Fun(2, div)".to_string(),
+            Expr::EQ => "Fun(2, eq)".to_string(),
+            Expr::ISNIL => "Fun(1, is_nil)".to_string(),
+            Expr::LT => "Fun(2, lt)".to_string(),
+            Expr::MUL => "Fun(2, mul)".to_string(),
+            Expr::NEG => "Fun(1, neg)".to_string(),
+            Expr::Prim(kind) => format!("Fun({}, prim_{})", kind.arity(), kind.name()),
+            Expr::Func(params, body) =>
+                format!("Fun({}, {})", params.len(), self.lambda(&params.iter().collect::<Vec<_>>(), body)),
+        }
+    }
+}
+
+pub fn emit(program: &Program, w: &mut dyn Write) -> io::Result<()> {
+    write!(w, "{}", RUNTIME)?;
+    for g in program.globals.iter() {
+        let mut emitter = Emitter{program, scope: g.params.clone()};
+        let body = emitter.expr(&g.body);
+        writeln!(w, "\n")?;
+        writeln!(w, "# {}", g.name)?;
+        if g.params.is_empty() {
+            writeln!(w, "@constant")?;
+        }
+        let params = g.params.iter().map(|p| local(p)).collect::<Vec<_>>();
+        writeln!(w, "def {}({}):\n    return {}", g.ident, params.join(", "), body)?;
+    }
+    let galaxy = Emitter{program, scope: Vec::new()}.global_value("galaxy");
+    writeln!(w, "\n\ndef galaxy():\n    return {}", galaxy)?;
+    writeln!(w, "\n\nif __name__ == '__main__':\n    main()")
+}
@@ -0,0 +1,177 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Emits the program as a single Rust source file:
+//   rustc -O galaxy.rs
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+use super::{free_vars, local, Program};
+use super::super::expr::Expr;
+
+const RUNTIME: &str = include_str!("runtime.rs.in");
+
+struct Emitter<'a> {
+    program: &'a Program,
+    // Local variables in scope.
+    scope: Vec<String>,
+}
+
+impl<'a> Emitter<'a> {
+    fn bind<F: FnOnce(&mut Self) -> String>(&mut self, names: &[&String], f: F) -> String {
+        let n = self.scope.len();
+        self.scope.extend(names.iter().map(|s| s.to_string()));
+        let ret = f(self);
+        self.scope.truncate(n);
+        ret
+    }
+
+    // A global used as a value.
+    fn global_value(&self, name: &str) -> String {
+        let g = match self.program.get(name) {
+            Some(g) => g,
+            None => return format!("undefined({:?})", name),
+        };
+        if g.params.is_empty() {
+            return format!("{}()", g.ident);
+        }
+        let args = (0..g.params.len()).map(|i| format!("a[{}].clone()", i)).collect::<Vec<_>>();
+        format!("func({}, Rc::new(|a: &[V]| {}({})))", g.params.len(), g.ident, args.join(", "))
+    }
+
+    fn call(&mut self, vals: &[Expr]) -> String {
+        let args = vals[1..].iter().map(|e| self.expr(e)).collect::<Vec<_>>();
+        let direct = |name: &str, arity: usize| -> Option<String> {
+            if args.len() < arity {
+                return None;
+            }
+            let call = format!("{}({})", name, args[..arity].join(", "));
+            Some(if args.len() == arity {
+                call
+            } else {
+                format!("apply_all({}, vec![{}])", call, args[arity..].join(", "))
+            })
+        };
+        let called = match &vals[0] {
+            Expr::Symbol(s) if !self.scope.contains(s) => match self.program.get(s) {
+                Some(g) if !g.params.is_empty() => direct(&g.ident, g.params.len()),
+                _ => None,
+            },
+            Expr::CAR => direct("car", 1),
+            Expr::CDR => direct("cdr", 1),
+            Expr::CONS => direct("cons", 2),
+            Expr::ISNIL => direct("(|v: V| V::Bool(is_nil(&v)))", 1),
+            Expr::Prim(kind) => direct(&format!("prim_{}", kind.name()), kind.arity()),
+            _ => None,
+        };
+        match called {
+            Some(code) => code,
+            None => format!("apply_all({}, vec![{}])", self.expr(&vals[0]), args.join(", ")),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> String {
+        match e {
+            Expr::Int(i) => format!("V::Int({})", i),
+            Expr::Symbol(s) if self.scope.contains(s) => format!("{}.clone()", local(s)),
+            Expr::Symbol(s) => self.global_value(s),
+            Expr::List(items) =>
+                format!("list(vec![{}])", items.iter().map(|e| self.expr(e)).collect::<Vec<_>>().join(", ")),
+            Expr::True => "V::Bool(true)".to_string(),
+            Expr::False => "V::Bool(false)".to_string(),
+            Expr::Neg(e) => format!("neg({})", self.expr(e)),
+            Expr::Not(e) => format!("not({})", self.expr(e)),
+            Expr::IsNil(e) => format!("V::Bool(is_nil(&{}))", self.expr(e)),
+            Expr::Add(lhs, rhs) => format!("add({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Sub(lhs, rhs) => format!("sub({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Mul(lhs, rhs) => format!("mul({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Div(lhs, rhs) => format!("div({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Mod(lhs, rhs) => format!("rem({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Equ(lhs, rhs) => format!("eq({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Lt(lhs, rhs) => format!("lt({}, {})", self.expr(lhs), self.expr(rhs)),
+            Expr::Or(lhs, rhs) =>
+                format!("(if truthy(&{}) {{ V::Bool(true) }} else {{ {} }})", self.expr(lhs), self.expr(rhs)),
+            Expr::And(lhs, rhs) =>
+                format!("(if truthy(&{}) {{ {} }} else {{ V::Bool(false) }})", self.expr(lhs), self.expr(rhs)),
+            Expr::If(cond, e1, e2) =>
+                format!("(if truthy(&{}) {{ {} }} else {{ {} }})", self.expr(cond), self.expr(e1), self.expr(e2)),
+            Expr::MatchCons(v, e1, hd, tl, e2) => {
+                let v = self.expr(v);
+                let e1 = self.expr(e1);
+                let e2 = self.bind(&[hd, tl], |s| s.expr(e2));
+                format!("(match uncons_opt({}) {{ None => {}, Some(({}, {})) => {} }})",
+                        v, e1, local(hd), local(tl), e2)
+            },
+            Expr::LetCons(hd, tl, e1, e2) => {
+                let e1 = self.expr(e1);
+                let e2 = self.bind(&[hd, tl], |s| s.expr(e2));
+                format!("{{ let ({}, {}) = uncons({}); {} }}", local(hd), local(tl), e1, e2)
+            },
+            Expr::Let(var, e1, e2) => {
+                let e1 = self.expr(e1);
+                let e2 = self.bind(&[var], |s| s.expr(e2));
+                format!("{{ let {} = {}; {} }}", local(var), e1, e2)
+            },
+            Expr::Call(vals) => self.call(vals),
+            Expr::ADD => "builtin2(add)".to_string(),
+            Expr::CAR => "builtin1(car)".to_string(),
+            Expr::CDR => "builtin1(cdr)".to_string(),
+            Expr::CONS => "builtin2(cons)".to_string(),
+            Expr::DIV => "builtin2(div)".to_string(),
+            Expr::EQ => "builtin2(eq)".to_string(),
+            Expr::ISNIL => "builtin1(|v| V::Bool(is_nil(&v)))".to_string(),
+            Expr::LT => "builtin2(lt)".to_string(),
+            Expr::MUL => "builtin2(mul)".to_string(),
+            Expr::NEG => "builtin1(neg)".to_string(),
+            Expr::Prim(kind) => {
+                let args = (0..kind.arity()).map(|i| format!("a[{}].clone()", i)).collect::<Vec<_>>();
+                format!("func({}, Rc::new(|a: &[V]| prim_{}({})))", kind.arity(), kind.name(), args.join(", "))
+            },
+            Expr::Func(params, body) => {
+                // Locals used in the body are cloned into the closure.
+                let mut free = BTreeSet::new();
+                free_vars(e, &mut Vec::new(), &mut free);
+                let captures = free.iter()
+                    .filter(|s| self.scope.contains(s))
+                    .map(|s| format!("let {0} = {0}.clone(); ", local(s)))
+                    .collect::<String>();
+                let args = params.iter().enumerate()
+                    .map(|(i, p)| format!("let {} = a[{}].clone(); ", local(p), i))
+                    .collect::<String>();
+                let body = self.bind(&params.iter().collect::<Vec<_>>(), |s| s.expr(body));
+                format!("{{ {}func({}, Rc::new(move |a: &[V]| {{ {}{} }})) }}", captures, params.len(), args, body)
+            },
+        }
+    }
+}
+
+pub fn emit(program: &Program, w: &mut dyn Write) -> io::Result<()> {
+    write!(w, "{}", RUNTIME)?;
+    for g in program.globals.iter() {
+        let mut emitter = Emitter{program, scope: g.params.clone()};
+        let body = emitter.expr(&g.body);
+        writeln!(w)?;
+        writeln!(w, "// {}", g.name)?;
+        if g.params.is_empty() {
+            writeln!(w, "fn {}() -> V {{\n    constant!({})\n}}", g.ident, body)?;
+        } else {
+            let params = g.params.iter().map(|p| format!("{}: V", local(p))).collect::<Vec<_>>();
+            writeln!(w, "fn {}({}) -> V {{\n    {}\n}}", g.ident, params.join(", "), body)?;
+        }
+    }
+    let galaxy = Emitter{program, scope: Vec::new()}.global_value("galaxy");
+    writeln!(w, "\nfn galaxy() -> V {{\n    {}\n}}", galaxy)
+}
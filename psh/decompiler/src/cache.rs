@@ -0,0 +1,173 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Cache of decompiled definitions across runs.
+//
+// Decompiling a definition up to renaming depends on the definition itself,
+// on the definitions it calls through type inference, on the types in the
+// symbol map, on the names the rewrite rules refer to, and on the rules.
+// Definitions are cached under a hash of all of these, so re-running after
+// renaming a symbol only renames again:
+//   decompiler --cache .decompiler-cache galaxy.txt
+//
+// An entry is a file named by its key, with the inferred type, the number
+// of ill-typed applications and the rewritten expression in the pattern
+// syntax of rewrite.rs. Stale entries are never removed; delete the
+// directory after changing the decompiler itself.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use super::ast;
+use super::callgraph::{components, CallGraph};
+use super::expr::{Env, Expr};
+use super::rewrite::{from_pattern, to_pattern, Rules};
+
+// Bumped when the entry format or the decompiled output changes.
+const FORMAT: &str = "decompiler-cache 2";
+
+// 64-bit FNV-1a, which unlike std's hashers is stable across releases.
+struct Hasher(u64);
+
+impl Hasher {
+    fn new() -> Self {
+        Hasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    // Strings are terminated so that ("ab", "c") and ("a", "bc") differ.
+    fn write(&mut self, s: &str) {
+        for b in s.bytes().chain(std::iter::once(0)) {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    pub ty: String,
+    pub errors: usize,
+    pub expr: Expr,
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    // Hash of the symbol map and the rules.
+    version: u64,
+}
+
+impl Cache {
+    pub fn open(dir: &Path, env: &Env, rules: &Rules) -> io::Result<Cache> {
+        fs::create_dir_all(dir)?;
+        let mut h = Hasher::new();
+        h.write(FORMAT);
+        h.write(&format!("{:?}", rules));
+        let symbols = rules.symbols();
+        // Names only matter where rules match them.
+        for e in env.entries() {
+            let name = if symbols.contains(&e.name) { e.name.as_str() } else { "" };
+            if name.is_empty() && e.t.is_none() {
+                continue;
+            }
+            h.write(&e.orig_name);
+            h.write(name);
+            h.write(&e.t.map_or(String::new(), |t| t.to_string()));
+        }
+        Ok(Cache{dir: dir.to_path_buf(), version: h.0})
+    }
+
+    // The keys of `defs`. A key covers the definitions it calls, directly
+    // or not, so a change invalidates all its callers.
+    pub fn keys(&self, defs: &[ast::Definition], graph: &CallGraph) -> Vec<u64> {
+        let mut component_keys = vec![0; defs.len()];
+        // Dependencies come first.
+        for component in components(&graph.callees) {
+            let mut h = Hasher::new();
+            h.write(&format!("{:x}", self.version));
+            for &i in component.iter() {
+                h.write(&defs[i].name);
+                h.write(&format!("{:?}", defs[i].value));
+            }
+            let mut callees = component.iter()
+                .flat_map(|&i| graph.callees[i].iter().copied())
+                .filter(|j| !component.contains(j))
+                .map(|j| component_keys[j])
+                .collect::<Vec<_>>();
+            callees.sort_unstable();
+            callees.dedup();
+            for key in callees {
+                h.write(&format!("{:x}", key));
+            }
+            for &i in component.iter() {
+                component_keys[i] = h.0;
+            }
+        }
+        defs.iter().zip(component_keys).map(|(def, key)| {
+            let mut h = Hasher(key);
+            h.write(&def.name);
+            h.0
+        }).collect()
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}", key))
+    }
+
+    // Missing and malformed entries are misses.
+    pub fn load(&self, key: u64) -> Option<Entry> {
+        let content = fs::read_to_string(self.path(key)).ok()?;
+        let mut lines = content.lines();
+        let ty = lines.next()?.strip_prefix("type ")?.to_string();
+        let errors = lines.next()?.strip_prefix("errors ")?.parse().ok()?;
+        let expr = from_pattern(lines.next()?.strip_prefix("expr ")?).ok()?;
+        Some(Entry{ty, errors, expr})
+    }
+
+    // Entries that cannot be written in the pattern syntax are not stored.
+    pub fn store(&self, key: u64, entry: &Entry) -> io::Result<()> {
+        match to_pattern(&entry.expr) {
+            Some(expr) =>
+                fs::write(self.path(key), format!("type {}\nerrors {}\nexpr {}\n", entry.ty, entry.errors, expr)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_cache() {
+    let (defs, errors) = super::parser::parse(":1 = ap add 1\n:2 = ap :1 2\n:3 = 3\n");
+    assert!(errors.is_empty());
+    let graph = CallGraph::new(&defs);
+    let dir = std::env::temp_dir().join(format!("decompiler-test-cache-{}", std::process::id()));
+    let rules = Rules::parse("rule a: (add ?x 0) => ?x").unwrap();
+    let mut env = Env::new();
+    let cache = Cache::open(&dir, &env, &rules).unwrap();
+    let keys = cache.keys(&defs, &graph);
+
+    let e = from_pattern("(fun (x0) (match x0 (list 1 -2) h t (call @cons h t)))").unwrap();
+    assert_eq!(to_pattern(&e).unwrap(), "(fun (x0) (match x0 (list 1 -2) h t (call @cons h t)))");
+    let entry = Entry{ty: "int -> int".to_string(), errors: 1, expr: e};
+    cache.store(keys[1], &entry).unwrap();
+    assert_eq!(cache.load(keys[1]), Some(entry));
+    assert_eq!(cache.load(keys[0]), None);
+
+    // Renaming changes no key, but a changed callee changes its callers.
+    env.insert(":1", "succ", None);
+    assert_eq!(Cache::open(&dir, &env, &rules).unwrap().keys(&defs, &graph), keys);
+    let (defs2, _) = super::parser::parse(":1 = ap add 2\n:2 = ap :1 2\n:3 = 3\n");
+    let keys2 = cache.keys(&defs2, &graph);
+    assert!(keys2[0] != keys[0] && keys2[1] != keys[1] && keys2[2] == keys[2]);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,297 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Call graph of the top level definitions.
+//
+// A definition calls every definition its body refers to. Definitions that
+// are not reachable from "galaxy" are dead, and strongly connected
+// components with a loop are recursive cycles.
+//
+// The graph is written as a text report, Graphviz DOT or JSON:
+//   decompiler --callgraph calls.dot galaxy.txt
+//   dot -Tsvg calls.dot > calls.svg
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use super::ast;
+use super::expr::Env;
+use super::symbols::quote;
+
+pub const ROOT: &str = "galaxy";
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Format {
+    Text,
+    Dot,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("dot") | Some("gv") => Format::Dot,
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+}
+
+pub struct CallGraph {
+    // Definition names in the order of the input.
+    pub names: Vec<String>,
+    index: HashMap<String, usize>,
+    // Both sorted by the definition order.
+    pub callees: Vec<Vec<usize>>,
+    pub callers: Vec<Vec<usize>>,
+}
+
+impl CallGraph {
+    pub fn new(defs: &[ast::Definition]) -> Self {
+        fn refs(v: &ast::Value, out: &mut Vec<String>) {
+            match v {
+                ast::Value::SYMBOL(s) => out.push(s.clone()),
+                ast::Value::Apply(f, x) => {
+                    refs(f, out);
+                    refs(x, out);
+                },
+                _ => (),
+            }
+        }
+        let names = defs.iter().map(|d| d.name.clone()).collect::<Vec<_>>();
+        let index = names.iter().enumerate().map(|(i, name)| (name.clone(), i)).collect::<HashMap<_, _>>();
+        let mut callees = vec![Vec::new(); names.len()];
+        let mut callers = vec![Vec::new(); names.len()];
+        for (i, def) in defs.iter().enumerate() {
+            let mut symbols = Vec::new();
+            refs(&def.value, &mut symbols);
+            // References to undefined symbols are ignored.
+            let mut targets = symbols.iter().filter_map(|s| index.get(s).copied()).collect::<Vec<_>>();
+            targets.sort_unstable();
+            targets.dedup();
+            for &j in targets.iter() {
+                callers[j].push(i);
+            }
+            callees[i] = targets;
+        }
+        Self{names, index, callees, callers}
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.index.get(name).copied()
+    }
+
+    // Definitions not reachable from the root. Nothing is dead when there
+    // is no root.
+    pub fn dead(&self) -> Vec<usize> {
+        let root = match self.index(ROOT) {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        let mut reachable = vec![false; self.names.len()];
+        let mut stack = vec![root];
+        reachable[root] = true;
+        while let Some(i) = stack.pop() {
+            for &j in self.callees[i].iter() {
+                if !reachable[j] {
+                    reachable[j] = true;
+                    stack.push(j);
+                }
+            }
+        }
+        (0..self.names.len()).filter(|&i| !reachable[i]).collect()
+    }
+
+    // Recursive cycles: mutually recursive definitions, and definitions
+    // calling themselves.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut cycles = components(&self.callees).into_iter()
+            .filter(|c| c.len() > 1 || self.callees[c[0]].contains(&c[0]))
+            .map(|mut c| {
+                c.sort_unstable();
+                c
+            })
+            .collect::<Vec<_>>();
+        cycles.sort();
+        cycles
+    }
+
+    pub fn write(&self, w: &mut dyn Write, format: Format, env: &Env) -> io::Result<()> {
+        let dead = self.dead();
+        let cycles = self.cycles();
+        let mut recursive = vec![false; self.names.len()];
+        for &i in cycles.iter().flatten() {
+            recursive[i] = true;
+        }
+        let label = |i: usize| -> &str {
+            env.get_name(&self.names[i]).map_or(&self.names[i], |s| s.as_str())
+        };
+        // "Name (:1234)" for renamed definitions, ":1234" otherwise.
+        let describe = |i: usize| -> String {
+            match env.get_name(&self.names[i]) {
+                Some(name) => format!("{} ({})", name, self.names[i]),
+                None => self.names[i].clone(),
+            }
+        };
+        let describe_all = |v: &[usize]| match v {
+            [] => "(none)".to_string(),
+            v => v.iter().map(|&i| describe(i)).collect::<Vec<_>>().join(", "),
+        };
+        match format {
+            Format::Text => {
+                writeln!(w, "# {} definitions, {} dead, {} recursive cycles",
+                         self.names.len(), dead.len(), cycles.len())?;
+                for i in 0..self.names.len() {
+                    writeln!(w, "{}", describe(i))?;
+                    writeln!(w, "  calls: {}", describe_all(&self.callees[i]))?;
+                    writeln!(w, "  called by: {}", describe_all(&self.callers[i]))?;
+                }
+                for &i in dead.iter() {
+                    writeln!(w, "dead: {}", describe(i))?;
+                }
+                for c in cycles.iter() {
+                    writeln!(w, "cycle: {}", describe_all(c))?;
+                }
+            },
+            Format::Dot => {
+                writeln!(w, "digraph galaxy {{")?;
+                writeln!(w, "  node [shape=box];")?;
+                for (i, name) in self.names.iter().enumerate() {
+                    let mut attrs = vec![format!("label={}", quote(label(i)))];
+                    if dead.contains(&i) {
+                        attrs.push("style=dashed".to_string());
+                        attrs.push("fontcolor=gray".to_string());
+                    }
+                    if recursive[i] {
+                        attrs.push("color=red".to_string());
+                    }
+                    writeln!(w, "  {} [{}];", quote(name), attrs.join(", "))?;
+                }
+                for (i, callees) in self.callees.iter().enumerate() {
+                    for &j in callees.iter() {
+                        writeln!(w, "  {} -> {};", quote(&self.names[i]), quote(&self.names[j]))?;
+                    }
+                }
+                writeln!(w, "}}")?;
+            },
+            Format::Json => {
+                let names = |v: &[usize]| v.iter().map(|&i| quote(&self.names[i])).collect::<Vec<_>>().join(", ");
+                writeln!(w, "{{")?;
+                writeln!(w, "  \"definitions\": [")?;
+                for (i, name) in self.names.iter().enumerate() {
+                    let sep = if i + 1 < self.names.len() { "," } else { "" };
+                    writeln!(w, "    {{\"name\": {}, \"label\": {}, \"calls\": [{}], \"callers\": [{}], \
+                                 \"dead\": {}, \"recursive\": {}}}{}",
+                             quote(name), quote(label(i)), names(&self.callees[i]),
+                             names(&self.callers[i]), dead.contains(&i), recursive[i], sep)?;
+                }
+                writeln!(w, "  ],")?;
+                writeln!(w, "  \"dead\": [{}],", names(&dead))?;
+                writeln!(w, "  \"cycles\": [")?;
+                for (i, c) in cycles.iter().enumerate() {
+                    let sep = if i + 1 < cycles.len() { "," } else { "" };
+                    writeln!(w, "    [{}]{}", names(c), sep)?;
+                }
+                writeln!(w, "  ]")?;
+                writeln!(w, "}}")?;
+            },
+        }
+        Ok(())
+    }
+}
+
+// Returns the strongly connected components of a graph in reverse
+// topological order, i.e. a component comes after the components it has
+// edges to. Uses Tarjan's algorithm.
+pub fn components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        edges: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next: usize,
+        result: Vec<Vec<usize>>,
+    }
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, v: usize) {
+            self.index[v] = Some(self.next);
+            self.low[v] = self.next;
+            self.next += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+            for &w in self.edges[v].iter() {
+                match self.index[w] {
+                    None => {
+                        self.visit(w);
+                        self.low[v] = self.low[v].min(self.low[w]);
+                    },
+                    Some(i) if self.on_stack[w] => self.low[v] = self.low[v].min(i),
+                    _ => (),
+                }
+            }
+            if Some(self.low[v]) == self.index[v] {
+                let mut component = Vec::new();
+                loop {
+                    let w = self.stack.pop().unwrap();
+                    self.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                component.reverse();
+                self.result.push(component);
+            }
+        }
+    }
+    let n = edges.len();
+    let mut t = Tarjan{
+        edges, index: vec![None; n], low: vec![0; n], on_stack: vec![false; n],
+        stack: Vec::new(), next: 0, result: Vec::new(),
+    };
+    for v in 0..n {
+        if t.index[v].is_none() {
+            t.visit(v);
+        }
+    }
+    t.result
+}
+
+#[test]
+fn test_callgraph() {
+    let (defs, errors) = super::parser::parse(
+        ":1 = ap :2 :3\n:2 = ap :3 :2\n:3 = ap :4 1\n:4 = ap :3 :9\n:5 = :1\n:6 = :6\ngalaxy = :1\n");
+    assert!(errors.is_empty());
+    let graph = CallGraph::new(&defs);
+    let names = |v: &[usize]| v.iter().map(|&i| graph.names[i].as_str()).collect::<Vec<_>>();
+    let i = |name: &str| graph.index(name).unwrap();
+    assert_eq!(names(&graph.callees[i(":1")]), vec![":2", ":3"]);
+    assert_eq!(names(&graph.callers[i(":1")]), vec![":5", "galaxy"]);
+    assert_eq!(names(&graph.callees[i(":4")]), vec![":3"]);
+    assert_eq!(names(&graph.dead()), vec![":5", ":6"]);
+    assert_eq!(graph.cycles().iter().map(|c| names(c)).collect::<Vec<_>>(),
+               vec![vec![":2"], vec![":3", ":4"], vec![":6"]]);
+
+    let mut env = Env::new();
+    env.insert(":1", "Scene.run", None);
+    let mut out = Vec::new();
+    graph.write(&mut out, Format::Dot, &env).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("  \":1\" [label=\"Scene.run\"];\n"));
+    assert!(out.contains("  \":6\" [label=\":6\", style=dashed, fontcolor=gray, color=red];\n"));
+    assert!(out.contains("  \":2\" -> \":2\";\n"));
+}
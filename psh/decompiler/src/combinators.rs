@@ -0,0 +1,563 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Recognition of structural recursion over lists.
+//
+// A definition that destructures one of its parameters and recurses on the
+// tail, e.g.
+//   List.map = fun(l, f) -> match l { [] => [], h::t => cons(f(h), List.map(t, f)) }
+// computes one of the list primitives below, possibly specialized with some
+// of its arguments inlined. Calls of recognized definitions are rewritten to
+// the primitives:
+//   map(xs, f)           [f(x0), f(x1), ...]
+//   filter(xs, p)        the items x with p(x)
+//   foldl(xs, z, f)      f(...f(f(z, x0), x1)..., xn)
+//   foldr(xs, z, f)      f(...f(f(z, xn), xn-1)..., x0)
+//   zip_with(xs, ys, f)  [f(x0, y0), f(x1, y1), ...] up to the shorter list
+//   concat(xs, ys)       xs followed by ys
+//   length(xs)
+//   nth(xs, n)           the n-th item, counted from 0
+//
+// The confidence of a recognized definition is
+// - high when it is exactly the primitive,
+// - medium for a fold with an arbitrary step function, which may well be a
+//   more specific combinator in disguise,
+// - low when it is the primitive except for the empty list, e.g. a nth
+//   returning [] out of range.
+// Calls are rewritten unless the confidence is low.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use super::expr::Expr;
+use super::rewrite::{children, map_children, mentions};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Kind {
+    Map,
+    Filter,
+    Foldl,
+    Foldr,
+    ZipWith,
+    Concat,
+    Length,
+    Nth,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Map => "map",
+            Kind::Filter => "filter",
+            Kind::Foldl => "foldl",
+            Kind::Foldr => "foldr",
+            Kind::ZipWith => "zip_with",
+            Kind::Concat => "concat",
+            Kind::Length => "length",
+            Kind::Nth => "nth",
+        }
+    }
+
+    // The name of a definition that is the primitive, as in symbols.txt.
+    pub fn definition_name(self) -> &'static str {
+        match self {
+            Kind::Map => "List.map",
+            Kind::Filter => "List.filter",
+            Kind::Foldl => "List.foldl",
+            Kind::Foldr => "List.foldr",
+            Kind::ZipWith => "List.zip_with",
+            Kind::Concat => "List.concat",
+            Kind::Length => "List.len",
+            Kind::Nth => "List.nth",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Kind::Length => 1,
+            Kind::Map | Kind::Filter | Kind::Concat | Kind::Nth => 2,
+            Kind::Foldl | Kind::Foldr | Kind::ZipWith => 3,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Confidence::Low => write!(f, "low"),
+            Confidence::Medium => write!(f, "medium"),
+            Confidence::High => write!(f, "high"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Combinator {
+    pub kind: Kind,
+    pub confidence: Confidence,
+    // The parameters of the definition, and the call of the primitive in
+    // terms of them.
+    params: Vec<String>,
+    template: Expr,
+}
+
+impl fmt::Display for Combinator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, {} confidence", self.kind.name(), self.confidence)
+    }
+}
+
+impl Combinator {
+    // Whether the definition is the primitive itself, with the parameters
+    // in the same order.
+    pub fn is_primitive(&self) -> bool {
+        let mut vals = vec![Expr::Prim(self.kind)];
+        vals.extend(self.params.iter().map(|p| symbol(p)));
+        self.confidence == Confidence::High && self.template == Expr::Call(vals)
+    }
+
+    // The primitive applied to `args`, or None for a partial application.
+    // Arguments used more than once or inside a function are bound by a let.
+    fn instantiate(&self, args: &[Expr], fresh: &mut usize) -> Option<Expr> {
+        if args.len() < self.params.len() {
+            return None;
+        }
+        let mut names = HashMap::new();
+        let mut lets = Vec::new();
+        for (param, arg) in self.params.iter().zip(args.iter()) {
+            let direct = match &self.template {
+                Expr::Call(vals) => vals[1..].iter().any(|v| v.name() == Some(param)),
+                _ => false,
+            };
+            let value = match arg {
+                Expr::Symbol(_) | Expr::Int(_) | Expr::True | Expr::False => arg.clone(),
+                e if is_builtin(e) => arg.clone(),
+                _ if direct && count(param, &self.template) == 1 => arg.clone(),
+                _ if !mentions(param, &self.template) => continue,
+                _ => {
+                    let var = format!("{}.{}", param, *fresh);
+                    *fresh += 1;
+                    lets.push((var.clone(), arg.clone()));
+                    Expr::Symbol(var)
+                },
+            };
+            names.insert(param.clone(), value);
+        }
+        let mut e = freshen(&self.template, &mut names, fresh);
+        for (var, value) in lets.into_iter().rev() {
+            e = Expr::Let(var, Box::new(value), Box::new(e));
+        }
+        if args.len() > self.params.len() {
+            let mut vals = vec![e];
+            vals.extend(args[self.params.len()..].iter().cloned());
+            e = Expr::Call(vals);
+        }
+        Some(e)
+    }
+}
+
+// Replaces subexpressions top down. `f` returns the replacement of an
+// expression, or None to descend into it.
+fn replace(e: &Expr, f: &mut dyn FnMut(&Expr) -> Option<Expr>) -> Expr {
+    if let Some(r) = f(e) {
+        return r;
+    }
+    map_children(e, &mut |kid| Ok(replace(kid, f))).unwrap()
+}
+
+// Replaces symbols by `names`. Local names are unique within a definition,
+// so nothing is captured.
+fn substitute(e: &Expr, names: &HashMap<String, Expr>) -> Expr {
+    replace(e, &mut |e| match e {
+        Expr::Symbol(s) => names.get(s).cloned(),
+        _ => None,
+    })
+}
+
+// Substitutes `names` in `e`, renaming all binders to fresh names so that
+// the substituted expressions, which come from another definition, are not
+// captured.
+fn freshen(e: &Expr, names: &mut HashMap<String, Expr>, fresh: &mut usize) -> Expr {
+    let mut bind = |name: &String, names: &mut HashMap<String, Expr>| {
+        let new_name = format!("{}.{}", name, *fresh);
+        *fresh += 1;
+        names.insert(name.clone(), symbol(&new_name));
+        new_name
+    };
+    match e {
+        Expr::Symbol(s) => names.get(s).cloned().unwrap_or_else(|| e.clone()),
+        Expr::Func(params, body) => {
+            let params = params.iter().map(|p| bind(p, names)).collect();
+            Expr::Func(params, Box::new(freshen(body, names, fresh)))
+        },
+        Expr::MatchCons(v, e1, hd, tl, e2) => {
+            let (hd, tl) = (bind(hd, names), bind(tl, names));
+            Expr::MatchCons(Box::new(freshen(v, names, fresh)), Box::new(freshen(e1, names, fresh)),
+                            hd, tl, Box::new(freshen(e2, names, fresh)))
+        },
+        Expr::LetCons(hd, tl, e1, e2) => {
+            let (hd, tl) = (bind(hd, names), bind(tl, names));
+            Expr::LetCons(hd, tl, Box::new(freshen(e1, names, fresh)), Box::new(freshen(e2, names, fresh)))
+        },
+        Expr::Let(var, e1, e2) => {
+            let var = bind(var, names);
+            Expr::Let(var, Box::new(freshen(e1, names, fresh)), Box::new(freshen(e2, names, fresh)))
+        },
+        e => map_children(e, &mut |kid| Ok(freshen(kid, names, fresh))).unwrap(),
+    }
+}
+
+fn count(name: &str, e: &Expr) -> usize {
+    match e {
+        Expr::Symbol(s) => (s == name) as usize,
+        e => children(e).into_iter().map(|e| count(name, e)).sum(),
+    }
+}
+
+fn symbol(name: &str) -> Expr {
+    Expr::Symbol(name.to_string())
+}
+
+fn prim(kind: Kind, args: Vec<Expr>) -> Expr {
+    let mut vals = vec![Expr::Prim(kind)];
+    vals.extend(args);
+    Expr::Call(vals)
+}
+
+// A function of `params`, eta-reduced if possible.
+fn lambda(params: Vec<String>, body: Expr) -> Expr {
+    if let Expr::Call(vals) = &body {
+        let n = vals.len() - params.len();
+        if n >= 1 &&
+            vals[n..].iter().zip(params.iter()).all(|(v, p)| v.name() == Some(p)) &&
+            !vals[..n].iter().any(|v| params.iter().any(|p| mentions(p, v))) {
+            return if n == 1 { vals[0].clone() } else { Expr::Call(vals[..n].to_vec()) };
+        }
+    }
+    Expr::Func(params, Box::new(body))
+}
+
+fn is_builtin(e: &Expr) -> bool {
+    matches!(e, Expr::ADD | Expr::CAR | Expr::CDR | Expr::CONS | Expr::DIV | Expr::EQ | Expr::ISNIL |
+             Expr::LT | Expr::MUL | Expr::NEG | Expr::Prim(_))
+}
+
+// A definition destructuring its `list`-th parameter.
+struct Shape {
+    list: usize,
+    // None when the list is destructured without checking for [].
+    nil: Option<Expr>,
+    hd: String,
+    tl: String,
+    cons: Expr,
+}
+
+fn destructure(e: &Expr, params: &[String]) -> Option<Shape> {
+    let param = |v: &Expr| match v {
+        Expr::Symbol(s) => params.iter().position(|p| p == s),
+        _ => None,
+    };
+    let shape = match e {
+        Expr::MatchCons(v, e1, hd, tl, e2) => Shape{
+            list: param(v)?, nil: Some((**e1).clone()), hd: hd.clone(), tl: tl.clone(), cons: (**e2).clone(),
+        },
+        Expr::LetCons(hd, tl, v, e2) => Shape{
+            list: param(v)?, nil: None, hd: hd.clone(), tl: tl.clone(), cons: (**e2).clone(),
+        },
+        // l(fun(hd, tl) -> ...)
+        Expr::Call(vals) if vals.len() == 2 => match &vals[1] {
+            Expr::Func(ps, body) if ps.len() == 2 => Shape{
+                list: param(&vals[0])?, nil: None, hd: ps[0].clone(), tl: ps[1].clone(), cons: (**body).clone(),
+            },
+            _ => return None,
+        },
+        // if isnil(l) { e1 } else { ... car(l) ... cdr(l) ... }
+        Expr::If(cond, e1, e2) => {
+            let list = match &**cond {
+                Expr::IsNil(v) => param(v)?,
+                _ => return None,
+            };
+            let l = &params[list];
+            let (hd, tl) = (format!("{}_hd", l), format!("{}_tl", l));
+            let cons = replace(e2, &mut |e| match e {
+                Expr::Call(vals) if vals.len() == 2 && vals[1].name() == Some(l) => match vals[0] {
+                    Expr::CAR => Some(symbol(&hd)),
+                    Expr::CDR => Some(symbol(&tl)),
+                    _ => None,
+                },
+                _ => None,
+            });
+            Shape{list, nil: Some((**e1).clone()), hd, tl, cons}
+        },
+        _ => return None,
+    };
+    // The list itself is [] in the nil case, and must not be used otherwise.
+    if mentions(&params[shape.list], &shape.cons) {
+        return None;
+    }
+    let l = &params[shape.list];
+    let nil = shape.nil.map(|e| if e.name() == Some(l) { Expr::List(Vec::new()) } else { e });
+    Some(Shape{nil, ..shape})
+}
+
+// Collects the arguments of the recursive calls in `e`. Fails if the
+// definition or the tails are used otherwise: `tails` are pairs of a
+// parameter and its tail, which must be passed in place of the parameter.
+fn recursive_calls<'a>(e: &'a Expr, name: &str, arity: usize, tails: &[(usize, &str)],
+                       out: &mut Vec<&'a [Expr]>) -> bool {
+    match e {
+        Expr::Call(vals) if vals[0].name() == Some(name) => {
+            let args = &vals[1..];
+            if args.len() != arity || tails.iter().any(|&(i, tl)| args[i].name() != Some(tl)) {
+                return false;
+            }
+            out.push(args);
+            args.iter().enumerate()
+                .all(|(i, a)| tails.iter().any(|&(j, _)| i == j) || recursive_calls(a, name, arity, tails, out))
+        },
+        Expr::Symbol(s) => s != name && tails.iter().all(|&(_, tl)| s != tl),
+        e => children(e).into_iter().all(|e| recursive_calls(e, name, arity, tails, out)),
+    }
+}
+
+// The recursive call shared by all recursive calls in `cons`.
+fn recursive_call(cons: &Expr, name: &str, params: &[String], tails: &[(usize, &str)]) -> Option<Expr> {
+    let mut calls = Vec::new();
+    if !recursive_calls(cons, name, params.len(), tails, &mut calls) || calls.is_empty() ||
+        calls.iter().any(|c| c != &calls[0]) {
+        return None;
+    }
+    let mut vals = vec![symbol(name)];
+    vals.extend(calls[0].iter().cloned());
+    Some(Expr::Call(vals))
+}
+
+fn contains(e: &Expr, sub: &Expr) -> bool {
+    e == sub || children(e).into_iter().any(|e| contains(e, sub))
+}
+
+// Recognizes the definitions computing list primitives.
+pub fn recognize(defs: &[(String, Expr)]) -> HashMap<String, Combinator> {
+    // Galaxy conses with an alias, e.g. ":1115 = cons".
+    let conses = defs.iter().filter(|(_, e)| *e == Expr::CONS).map(|(name, _)| name.as_str()).collect();
+    let r = Recognizer{conses};
+    defs.iter().filter_map(|(name, e)| r.definition(name, e).map(|c| (name.clone(), c))).collect()
+}
+
+struct Recognizer<'a> {
+    conses: HashSet<&'a str>,
+}
+
+impl<'a> Recognizer<'a> {
+    fn is_cons<'e>(&self, e: &'e Expr) -> Option<(&'e Expr, &'e Expr)> {
+        match e {
+            Expr::Call(vals) if vals.len() == 3 => match &vals[0] {
+                Expr::CONS => Some((&vals[1], &vals[2])),
+                Expr::Symbol(s) if self.conses.contains(s.as_str()) => Some((&vals[1], &vals[2])),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn definition(&self, name: &str, e: &Expr) -> Option<Combinator> {
+        let (params, body) = match e {
+            Expr::Func(params, body) => (params, &**body),
+            _ => return None,
+        };
+        let shape = destructure(body, params)?;
+        if shape.nil.as_ref().is_some_and(|e| mentions(name, e)) {
+            return None;
+        }
+        if let Some(c) = self.zip(name, params, &shape) {
+            return Some(c);
+        }
+        let rec = recursive_call(&shape.cons, name, params, &[(shape.list, &shape.tl)])?;
+        let args = match &rec {
+            Expr::Call(vals) => &vals[1..],
+            _ => unreachable!(),
+        };
+        let list = symbol(&params[shape.list]);
+        let hd = symbol(&shape.hd);
+        let acc = format!("{}_acc", params[shape.list]);
+        let changing = (0..params.len())
+            .filter(|&i| i != shape.list && args[i].name() != Some(&params[i]))
+            .collect::<Vec<_>>();
+        // The primitive, the nil case it has, and whether it is exact.
+        let (kind, template, nil, exact) = match changing.as_slice() {
+            [] => match &shape.cons {
+                Expr::Add(a, b) if (**a == Expr::Int(1) && **b == rec) || (**a == rec && **b == Expr::Int(1)) =>
+                    (Kind::Length, prim(Kind::Length, vec![list]), Some(Expr::Int(0)), true),
+                e if self.is_cons(e).is_some_and(|(x, tl)| *tl == rec && !contains(x, &rec)) => {
+                    let x = self.is_cons(e).unwrap().0;
+                    match &shape.nil {
+                        Some(Expr::Symbol(ys)) if *x == hd && params.contains(ys) =>
+                            (Kind::Concat, prim(Kind::Concat, vec![list, symbol(ys)]), Some(symbol(ys)), true),
+                        _ => (Kind::Map, prim(Kind::Map, vec![list, lambda(vec![shape.hd.clone()], x.clone())]),
+                              Some(Expr::List(Vec::new())), true),
+                    }
+                },
+                Expr::If(c, a, b) if !contains(c, &rec) &&
+                    (self.is_cons(a) == Some((&hd, &rec)) && **b == rec ||
+                     **a == rec && self.is_cons(b) == Some((&hd, &rec))) => {
+                    let c = if **b == rec { (**c).clone() } else { Expr::Not(c.clone()) };
+                    (Kind::Filter, prim(Kind::Filter, vec![list, lambda(vec![shape.hd.clone()], c)]),
+                     Some(Expr::List(Vec::new())), true)
+                },
+                cons => {
+                    let z = shape.nil.clone()?;
+                    if count(name, cons) != 1 {
+                        return None;
+                    }
+                    let step = replace(cons, &mut |e| if *e == rec { Some(symbol(&acc)) } else { None });
+                    let f = lambda(vec![acc, shape.hd.clone()], step);
+                    let exact = !matches!(f, Expr::Func(_, _));
+                    (Kind::Foldr, prim(Kind::Foldr, vec![list, z.clone(), f]), Some(z), exact)
+                },
+            },
+            &[i] => {
+                let n = symbol(&params[i]);
+                let is_zero = |e: &Expr| match e {
+                    Expr::Equ(a, b) => (**a == Expr::Int(0) && **b == n) || (**a == n && **b == Expr::Int(0)),
+                    _ => false,
+                };
+                match &shape.cons {
+                    Expr::If(c, a, b) if is_zero(c) && **a == hd && **b == rec &&
+                        args[i] == Expr::Sub(Box::new(n.clone()), Box::new(Expr::Int(1))) =>
+                        (Kind::Nth, prim(Kind::Nth, vec![list, n]), None, true),
+                    cons if *cons == rec => {
+                        let names = vec![(params[i].clone(), symbol(&acc))].into_iter().collect();
+                        let step = substitute(&args[i], &names);
+                        let f = lambda(vec![acc, shape.hd.clone()], step);
+                        let exact = !matches!(f, Expr::Func(_, _));
+                        (Kind::Foldl, prim(Kind::Foldl, vec![list, n.clone(), f]), Some(n), exact)
+                    },
+                    _ => return None,
+                }
+            },
+            _ => return None,
+        };
+        let confidence = if shape.nil != nil {
+            Confidence::Low
+        } else if exact {
+            Confidence::High
+        } else {
+            Confidence::Medium
+        };
+        Some(Combinator{kind, confidence, params: params.clone(), template})
+    }
+
+    // match xs { [] => [], x::xs' => match ys { [] => [], y::ys' => cons(f(x, y), self(xs', ys')) } }
+    fn zip(&self, name: &str, params: &[String], outer: &Shape) -> Option<Combinator> {
+        let inner = destructure(&outer.cons, params)?;
+        if inner.list == outer.list || inner.nil != Some(Expr::List(Vec::new())) ||
+            mentions(&params[outer.list], &inner.cons) {
+            return None;
+        }
+        let rec = recursive_call(&inner.cons, name, params, &[(outer.list, &outer.tl), (inner.list, &inner.tl)])?;
+        let args = match &rec {
+            Expr::Call(vals) => &vals[1..],
+            _ => unreachable!(),
+        };
+        if (0..params.len()).any(|i| i != outer.list && i != inner.list && args[i].name() != Some(&params[i])) {
+            return None;
+        }
+        let x = match self.is_cons(&inner.cons) {
+            Some((x, tl)) if *tl == rec && !contains(x, &rec) => x,
+            _ => return None,
+        };
+        let f = lambda(vec![outer.hd.clone(), inner.hd.clone()], x.clone());
+        let template = prim(Kind::ZipWith, vec![symbol(&params[outer.list]), symbol(&params[inner.list]), f]);
+        let confidence = if outer.nil == Some(Expr::List(Vec::new())) { Confidence::High } else { Confidence::Low };
+        Some(Combinator{kind: Kind::ZipWith, confidence, params: params.to_vec(), template})
+    }
+}
+
+// Rewrites the calls of recognized definitions in `e` to the primitives,
+// except recursive calls in the definition `self_name` itself.
+pub fn rewrite(e: &Expr, self_name: &str, recognized: &HashMap<String, Combinator>) -> Expr {
+    fn go(e: &Expr, self_name: &str, recognized: &HashMap<String, Combinator>, fresh: &mut usize) -> Expr {
+        let e = map_children(e, &mut |kid| Ok(go(kid, self_name, recognized, fresh))).unwrap();
+        if let Expr::Call(vals) = &e {
+            let c = match vals[0].name() {
+                Some(name) if name != self_name => recognized.get(name),
+                _ => None,
+            };
+            if let Some(c) = c.filter(|c| c.confidence > Confidence::Low) {
+                if let Some(r) = c.instantiate(&vals[1..], fresh) {
+                    return r;
+                }
+            }
+        }
+        e
+    }
+    go(e, self_name, recognized, &mut 0)
+}
+
+#[test]
+fn test_combinators() {
+    let sym = |s: &str| Expr::Symbol(s.to_string());
+    let b = Box::new;
+    let fun = |params: &[&str], body: Expr| Expr::Func(params.iter().map(|s| s.to_string()).collect(), b(body));
+    let call = |vals: Vec<Expr>| Expr::Call(vals);
+    let nil = || Expr::List(Vec::new());
+    let matches = |v: &str, e1: Expr, hd: &str, tl: &str, e2: Expr|
+        Expr::MatchCons(b(sym(v)), b(e1), hd.to_string(), tl.to_string(), b(e2));
+
+    let defs = vec![
+        (":1115".to_string(), Expr::CONS),
+        // map through the cons alias
+        (":1".to_string(), fun(&["l", "f"], matches("l", nil(), "h", "t", call(vec![
+            sym(":1115"), call(vec![sym("f"), sym("h")]), call(vec![sym(":1"), sym("t"), sym("f")])])))),
+        // filter with the condition inlined
+        (":2".to_string(), fun(&["l"], matches("l", nil(), "h", "t", Expr::If(
+            b(Expr::Lt(b(sym("h")), b(Expr::Int(0)))),
+            b(call(vec![sym(":2"), sym("t")])),
+            b(call(vec![Expr::CONS, sym("h"), call(vec![sym(":2"), sym("t")])])))))),
+        (":3".to_string(), fun(&["a", "b"], matches("a", nil(), "x", "xs", matches("b", nil(), "y", "ys", call(vec![
+            Expr::CONS, Expr::Add(b(sym("x")), b(sym("y"))), call(vec![sym(":3"), sym("xs"), sym("ys")])]))))),
+        // nth returning [] out of range
+        (":4".to_string(), fun(&["l", "n"], matches("l", nil(), "h", "t", Expr::If(
+            b(Expr::Equ(b(Expr::Int(0)), b(sym("n")))),
+            b(sym("h")),
+            b(call(vec![sym(":4"), sym("t"), Expr::Sub(b(sym("n")), b(Expr::Int(1)))])))))),
+        // not structural: recurses on the list itself
+        (":5".to_string(), fun(&["l"], matches("l", nil(), "h", "t", call(vec![sym(":5"), sym("l")])))),
+    ];
+    let recognized = recognize(&defs);
+    let describe = |name: &str| recognized.get(name).map(|c| c.to_string());
+    assert_eq!(describe(":1").as_deref(), Some("map, high confidence"));
+    assert_eq!(describe(":2").as_deref(), Some("filter, high confidence"));
+    assert_eq!(describe(":3").as_deref(), Some("zip_with, high confidence"));
+    assert_eq!(describe(":4").as_deref(), Some("nth, low confidence"));
+    assert_eq!(describe(":5"), None);
+    assert!(recognized[":1"].is_primitive());
+    assert!(!recognized[":2"].is_primitive());
+
+    let e = fun(&["p", "q", "g"], call(vec![
+        sym(":1"),
+        call(vec![sym(":3"), call(vec![sym(":2"), sym("p")]), sym("q")]),
+        call(vec![sym(":4"), sym("g"), Expr::Int(0)])]));
+    let e = super::expr::rename(&rewrite(&e, ":9", &recognized), &super::expr::Env::new());
+    assert_eq!(e.to_string(),
+               "fun(_x0, _x1, _x2) -> map(zip_with(filter(_x0, fun(_x3) -> !(_x3 < 0)), _x1, \
+                fun(_x4, _x5) -> _x4 + _x5), :4(_x2, 0))");
+}
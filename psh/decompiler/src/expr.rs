@@ -15,8 +15,15 @@
 
 use std::fmt;
 use std::fmt::Display;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use super::combinators::Kind;
 use super::simplified;
+use super::rewrite;
+use super::symbols;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Expr {
@@ -63,6 +70,9 @@ pub enum Expr {
     MUL,
     NEG,
     Func(Vec<String>, Box<Expr>),
+
+    // A higher-order list primitive introduced by combinators::rewrite.
+    Prim(Kind),
 }
 
 impl Expr {
@@ -70,7 +80,7 @@ impl Expr {
         match self {
             Expr::Int(_) | Expr::Symbol(_) | Expr::List(_) | Expr::True | Expr::False |
             Expr::ADD | Expr::CAR | Expr::CDR | Expr::CONS | Expr::DIV |
-            Expr::EQ | Expr::ISNIL | Expr::LT | Expr::MUL | Expr::NEG => 0,
+            Expr::EQ | Expr::ISNIL | Expr::LT | Expr::MUL | Expr::NEG | Expr::Prim(_) => 0,
             Expr::Neg(_) | Expr::IsNil(_) | Expr::Not(_) => 1,
             Expr::Add(_, _) | Expr::Sub(_, _) => 4,
             Expr::Mul(_, _) | Expr::Div(_, _) | Expr::Mod(_, _) => 3,
@@ -84,7 +94,7 @@ impl Expr {
         }
     }
 
-    fn name(&self) -> Option<&str> {
+    pub(crate) fn name(&self) -> Option<&str> {
         match self {
             Expr::Symbol(name) => Some(name),
             _ => None,
@@ -151,6 +161,7 @@ impl fmt::Display for Expr {
             Expr::LT => write!(f, "<"),
             Expr::MUL => write!(f, "mul"),
             Expr::NEG => write!(f, "neg"),
+            Expr::Prim(kind) => write!(f, "{}", kind.name()),
             Expr::True => write!(f, "true"),
             Expr::False => write!(f, "false"),
             Expr::Neg(e) => {
@@ -240,47 +251,178 @@ pub enum Type {
     Func(Vec<(Option<String>, Option<Type>)>, Box<Option<Type>>),
 }
 
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::List => write!(f, "list"),
+            Type::Func(params, ret) => {
+                write!(f, "fun(")?;
+                for (i, (name, t)) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", name.as_ref().map_or("_", |n| n.as_str()))?;
+                    if let Some(t) = t {
+                        write!(f, ": {}", t)?;
+                    }
+                }
+                write!(f, ")")?;
+                if let Some(ret) = &**ret {
+                    write!(f, " -> {}", ret)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+struct Symbol {
+    name: String,
+    t: Option<Type>,
+    // Where the symbol was defined, for warnings.
+    source: String,
+}
+
 pub struct Env {
-    env: HashMap<String, (String, Option<Type>)>,
+    env: HashMap<String, Symbol>,
+    // The original names of each mapped name, for resolve.
+    orig_names: HashMap<String, Vec<String>>,
+    // Types found by the inference, used when no type is mapped.
+    inferred: HashMap<String, Type>,
+    warnings: Vec<String>,
 }
 
 impl Env {
     pub fn new() -> Self {
-        Self{env: HashMap::new()}
+        Self{env: HashMap::new(), orig_names: HashMap::new(), inferred: HashMap::new(), warnings: Vec::new()}
     }
 
     pub fn insert(&mut self, orig_name: &str, name: &str, t: Option<Type>) {
-        self.env.insert(orig_name.to_string(), (name.to_string(), t));
+        self.define(orig_name, name, t, "(builtin)");
+    }
+
+    // Later definitions override earlier ones, so that several mapping
+    // files can be merged.
+    fn define(&mut self, orig_name: &str, name: &str, t: Option<Type>, source: &str) {
+        if let Some(old) = self.env.get(orig_name) {
+            if old.name != name || old.t != t {
+                self.warnings.push(format!("{}: {} is mapped twice; {} (from {}) is replaced by {}",
+                                           source, orig_name, old.name, old.source, name));
+            }
+            if let Some(origs) = self.orig_names.get_mut(&old.name) {
+                origs.retain(|o| o != orig_name);
+            }
+        }
+        self.orig_names.entry(name.to_string()).or_default().push(orig_name.to_string());
+        self.env.insert(orig_name.to_string(), Symbol{name: name.to_string(), t, source: source.to_string()});
+    }
+
+    // Loads a symbol mapping file. See symbols.rs for the formats.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        self.load_str(&content, symbols::Format::from_path(path), &path.display().to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Loads a mapping from `content`, naming it `source` in messages.
+    pub fn load_str(&mut self, content: &str, format: symbols::Format, source: &str) -> Result<(), String> {
+        let entries = symbols::parse(content, format).map_err(|e| format!("{}: {}", source, e))?;
+        for e in entries {
+            self.define(&e.orig_name, &e.name, e.t, &format!("{}:{}", source, e.line));
+        }
+        Ok(())
+    }
+
+    // Returns the mapping ordered by the definition number.
+    pub fn entries(&self) -> Vec<symbols::Entry> {
+        let mut entries = self.env.iter().map(|(orig_name, s)| symbols::Entry{
+            line: 0,
+            orig_name: orig_name.clone(),
+            name: s.name.clone(),
+            t: s.t.clone(),
+        }).collect::<Vec<_>>();
+        entries.sort_by_key(|e| definition_order(&e.orig_name));
+        entries
+    }
+
+    pub fn export(&self, w: &mut dyn Write, format: symbols::Format) -> io::Result<()> {
+        symbols::write(w, &self.entries(), format)
+    }
+
+    // Returns the warnings about the mapping: symbols mapped twice while
+    // merging, names given to several symbols, and symbols that do not
+    // appear in `used`.
+    pub fn check(&self, used: &HashSet<String>) -> Vec<String> {
+        let mut warnings = self.warnings.clone();
+        let entries = self.entries();
+
+        let mut by_name: HashMap<&str, Vec<&str>> = HashMap::new();
+        for e in entries.iter() {
+            by_name.entry(&e.name).or_default().push(&e.orig_name);
+        }
+        for e in entries.iter() {
+            let origs = &by_name[e.name.as_str()];
+            if origs.len() > 1 && origs[0] == e.orig_name {
+                warnings.push(format!("{} is the name of {}", e.name, origs.join(", ")));
+            }
+        }
+
+        for e in entries.iter() {
+            if !used.contains(&e.orig_name) {
+                warnings.push(format!("{}: {} ({}) is unused", self.env[&e.orig_name].source, e.orig_name, e.name));
+            }
+        }
+        warnings
     }
 
     pub fn get_name(&self, name: &str) -> Option<&String> {
-        if let Some((n, _)) = self.env.get(name) {
-            Some(n)
+        if let Some(s) = self.env.get(name) {
+            Some(&s.name)
         } else {
             None
         }
     }
 
+    // The original name of a mapped name, the first one if several
+    // definitions share it.
+    pub fn get_orig_name(&self, name: &str) -> Option<String> {
+        self.orig_names.get(name)?.iter().min_by_key(|o| definition_order(o)).cloned()
+    }
+
+    // Names a definition recognized by a rewrite rule.
+    pub fn recognize(&mut self, orig_name: &str, name: &str, source: &str) {
+        self.define(orig_name, name, None, source);
+    }
+
+    pub fn set_inferred_type(&mut self, name: &str, t: Type) {
+        self.inferred.insert(name.to_string(), t);
+    }
+
     pub fn get_type(&self, name: &str) -> Option<&Type> {
-        if let Some((_, t)) = self.env.get(name) {
-            match t {
-                Some(t) => Some(t),
-                None => None
+        if let Some(s) = self.env.get(name) {
+            if let Some(t) = &s.t {
+                return Some(t);
             }
-        } else {
-            None
         }
+        self.inferred.get(name)
     }
 }
 
+// Orders :2 before :10, and names that are not numbers last.
+fn definition_order(orig_name: &str) -> (usize, String) {
+    (orig_name[1..].parse::<usize>().unwrap_or(usize::MAX), orig_name.to_string())
+}
+
 struct Constructor<'a> {
     type_env: HashMap<String, Type>,
     env: &'a Env,
 }
 
 impl<'a> Constructor<'a> {
-    fn new(env: &'a Env) -> Self {
-        Self{type_env: HashMap::new(), env}
+    fn new(env: &'a Env, type_env: HashMap<String, Type>) -> Self {
+        Self{type_env, env}
     }
 
     fn construct(&mut self, val: &simplified::Value, _: Option<&Type>) -> Expr {
@@ -417,6 +559,11 @@ impl<'a> Constructor<'a> {
         }
     }
 
+    // is_or, is_and and is_not are not rules in rules/simplify.rules
+    // because the rest of construct depends on them: a boolean makes an If
+    // out of the call it is applied to, is_not needs that If before the
+    // parameters are merged into the enclosing function, and a lambda
+    // passed to an unknown boolean would be taken apart as a pair.
     fn is_or(&self, name: &str, e: &Expr) -> bool {
         match e {
             Expr::Call(vals) =>
@@ -438,6 +585,14 @@ impl<'a> Constructor<'a> {
                     (v1 == Some(p1) && v2 == Some(p2) && v3 == Some(p1)) ||
                         (v1 == Some(p2) && v2 == Some(p1) && v3 == Some(p2))
                 },
+                // The same with the boolean typed.
+                Expr::If(e1, e2, e3) => {
+                    let p1 = &name;
+                    let p2 = &params[0];
+                    let (v1, v2, v3) = (e1.name(), e2.name(), e3.name());
+                    (v1 == Some(p1) && v2 == Some(p2) && v3 == Some(p1)) ||
+                        (v1 == Some(p2) && v2 == Some(p1) && v3 == Some(p2))
+                },
                 _ => false,
             },
             _ => false,
@@ -445,7 +600,6 @@ impl<'a> Constructor<'a> {
     }
 
     fn is_not(&self, params: &[String], e: &Expr) -> bool {
-        eprintln!("!!is_not {:?}, {:?}", params, e);
         if params.len() != 2 {
             return false;
         }
@@ -520,91 +674,9 @@ impl<'a> Constructor<'a> {
     }
 }
 
-pub fn construct(val: &simplified::Value, env: &Env) -> Expr {
-    Constructor::new(env).construct(val, None)
-}
-
-pub fn simplify(expr: &Expr) -> Expr {
-    match expr {
-        Expr::List(lst) => Expr::List(lst.iter().map(simplify).collect()),
-        Expr::Neg(e) => match simplify(&**e) {
-            Expr::Int(i) => Expr::Int(-i),
-            e => Expr::Neg(Box::new(e)),
-        },
-        Expr::Add(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            match rhs {
-                Expr::Neg(e) => match *e {
-                    Expr::Mul(e1, e2) => match *e2 {
-                        Expr::Div(e21, e22) if *e21 == lhs && *e22 == *e1 =>
-                            Expr::Mod(Box::new(lhs), e1),
-                        e2 => Expr::Sub(Box::new(lhs), Box::new(Expr::Mul(e1, Box::new(e2)))),
-                    },
-                    e => Expr::Sub(Box::new(lhs), Box::new(e)),
-                },
-                Expr::Int(i) if i < 0 => Expr::Sub(Box::new(lhs), Box::new(Expr::Int(-i))),
-                rhs => match lhs {
-                    Expr::Neg(e) => Expr::Sub(Box::new(rhs), e),
-                    Expr::Int(i) if i < 0 => Expr::Sub(Box::new(rhs), Box::new(Expr::Int(-i))),
-                    lhs => Expr::Add(Box::new(lhs), Box::new(rhs)),
-                },
-            }
-        },
-        Expr::Mul(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            Expr::Mul(Box::new(lhs), Box::new(rhs))
-        },
-        Expr::Div(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            Expr::Div(Box::new(lhs), Box::new(rhs))
-        },
-        Expr::Equ(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            Expr::Equ(Box::new(lhs), Box::new(rhs))
-        }
-        Expr::Lt(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            Expr::Lt(Box::new(lhs), Box::new(rhs))
-        }
-        Expr::Not(e) =>
-            Expr::Not(Box::new(simplify(&**e))),
-        Expr::Or(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            Expr::Or(Box::new(lhs), Box::new(rhs))
-        }
-        Expr::And(lhs, rhs) => {
-            let lhs = simplify(&**lhs);
-            let rhs = simplify(&**rhs);
-            Expr::And(Box::new(lhs), Box::new(rhs))
-        }
-        Expr::IsNil(e) => Expr::IsNil(Box::new(simplify(e))),
-        Expr::If(cond, e1, e2) => {
-            let cond = simplify(&**cond);
-            let e1 = simplify(&**e1);
-            let e2 = simplify(&**e2);
-            match (cond, e2) {
-                (Expr::IsNil(x1), Expr::LetCons(hd, tl, x2, body)) if x1 == x2 =>
-                    Expr::MatchCons(x1, Box::new(e1), hd, tl, body),
-                (cond, e2) => Expr::If(Box::new(cond), Box::new(e1), Box::new(e2))
-            }
-        },
-        Expr::Call(vals) =>
-            Expr::Call(vals.iter().map(simplify).collect()),
-        Expr::Func(params, body) =>
-            Expr::Func(params.to_vec(), Box::new(simplify(&**body))),
-        Expr::LetCons(hd, tl, e1, e2) =>
-            Expr::LetCons(hd.clone(), tl.clone(), Box::new(simplify(&**e1)), Box::new(simplify(&**e2))),
-        Expr::Let(var, e1, e2) =>
-            Expr::Let(var.clone(), Box::new(simplify(&**e1)), Box::new(simplify(&**e2))),
-        e @ Expr::MatchCons(_, _, _, _, _) => panic!("unexpected! {:?}", e),
-        e => e.clone(),
-    }
+// `types` are the types of the local variables in `val`.
+pub fn construct(val: &simplified::Value, env: &Env, types: HashMap<String, Type>) -> Expr {
+    Constructor::new(env, types).construct(val, None)
 }
 
 struct Renamer<'a> {
@@ -692,3 +764,12 @@ impl<'a> Renamer<'a> {
 pub fn rename(expr: &Expr, env: &Env) -> Expr {
     Renamer::new(env).rename_expr(expr)
 }
+
+// The inverse of rename for globals: replaces mapped names with the
+// original ones, e.g. in definitions edited by hand.
+pub fn resolve(expr: &Expr, env: &Env) -> Expr {
+    match expr {
+        Expr::Symbol(s) => Expr::Symbol(env.get_orig_name(s).unwrap_or_else(|| s.clone())),
+        e => rewrite::map_children(e, &mut |e| Ok(resolve(e, env))).unwrap(),
+    }
+}
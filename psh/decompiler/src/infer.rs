@@ -0,0 +1,634 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Hindley-Milner style type inference over simplified values.
+//
+// Top level definitions are inferred one strongly connected component at a
+// time, in dependency order, and generalized afterwards, so each definition
+// gets a polymorphic signature.
+//
+// The galaxy code uses Church encodings, so besides the usual rules:
+// - "cons a b" has the pair type (a, b), which unifies with a list when b
+//   is a list of a,
+// - a pair, list or boolean can be applied as a function: (a, b) behaves as
+//   (a -> b -> r) -> r and a boolean as p -> p -> p,
+// - a parameter applied to a function of two parameters is taken to be a
+//   pair, which becomes a list if its tail is the parameter again.
+// Applications that still do not type check are counted as errors and
+// their result is left unconstrained instead of failing the definition.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use super::callgraph;
+use super::expr::Type;
+use super::simplified::Value;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Ty {
+    Var(usize),
+    Int,
+    Bool,
+    List(Box<Ty>),
+    Pair(Box<Ty>, Box<Ty>),
+    Func(Box<Ty>, Box<Ty>),
+}
+
+impl Ty {
+    fn fmt_arg(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Func(_, _) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+
+    // Converts a type annotation, numbering the unknown types from `next`.
+    pub fn from_type(t: &Type, next: &mut usize) -> Ty {
+        let var = |next: &mut usize| {
+            *next += 1;
+            Ty::Var(*next - 1)
+        };
+        let opt = |t: &Option<Type>, next: &mut usize| match t {
+            Some(t) => Ty::from_type(t, next),
+            None => var(next),
+        };
+        match t {
+            Type::Int => Ty::Int,
+            Type::Bool => Ty::Bool,
+            Type::List => Ty::List(Box::new(var(next))),
+            Type::Func(params, ret) => {
+                let ret = opt(ret, next);
+                params.iter().rev().fold(ret, |r, (_, p)| Ty::Func(Box::new(opt(p, next)), Box::new(r)))
+            },
+        }
+    }
+
+    // Converts to the annotation type used by the decompiler. `arity` is the
+    // number of parameters of the definition.
+    pub fn to_type(&self, arity: usize) -> Option<Type> {
+        match self {
+            Ty::Var(_) => None,
+            Ty::Int => Some(Type::Int),
+            Ty::Bool => Some(Type::Bool),
+            Ty::List(_) | Ty::Pair(_, _) => Some(Type::List),
+            Ty::Func(_, _) => {
+                let mut params = Vec::new();
+                let mut t = self;
+                while params.len() < arity.max(1) {
+                    match t {
+                        Ty::Func(p, r) => {
+                            params.push((None, p.to_type(0)));
+                            t = r;
+                        },
+                        _ => break,
+                    }
+                }
+                Some(Type::Func(params, Box::new(t.to_type(0))))
+            },
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Var(i) => {
+                let c = (b'a' + (*i % 26) as u8) as char;
+                if *i < 26 {
+                    write!(f, "'{}", c)
+                } else {
+                    write!(f, "'{}{}", c, i / 26)
+                }
+            },
+            Ty::Int => write!(f, "int"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::List(e) => {
+                e.fmt_arg(f)?;
+                write!(f, " list")
+            },
+            Ty::Pair(hd, tl) => {
+                // Pairs ending with a list are written like list literals.
+                let mut items = vec![hd];
+                let mut tail = &**tl;
+                while let Ty::Pair(hd, tl) = tail {
+                    items.push(hd);
+                    tail = tl;
+                }
+                match tail {
+                    Ty::List(e) if matches!(**e, Ty::Var(_)) => {
+                        write!(f, "[")?;
+                        for (i, t) in items.iter().enumerate() {
+                            write!(f, "{}{}", if i > 0 { ", " } else { "" }, t)?;
+                        }
+                        write!(f, "]")
+                    },
+                    Ty::List(_) => {
+                        write!(f, "[")?;
+                        for t in items.iter() {
+                            write!(f, "{}, ", t)?;
+                        }
+                        write!(f, "..{}]", tail)
+                    },
+                    _ => {
+                        write!(f, "(")?;
+                        for t in items.iter() {
+                            write!(f, "{}, ", t)?;
+                        }
+                        write!(f, "{})", tail)
+                    },
+                }
+            },
+            Ty::Func(p, r) => {
+                p.fmt_arg(f)?;
+                write!(f, " -> {}", r)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Term {
+    Var,
+    Int,
+    Bool,
+    List(usize),
+    Pair(usize, usize),
+    Func(usize, usize),
+}
+
+impl Term {
+    fn is_var(&self) -> bool {
+        matches!(self, Term::Var)
+    }
+}
+
+#[derive(Default)]
+pub struct Inferred {
+    pub globals: HashMap<String, Ty>,
+    // Types of the lambda parameters of each definition.
+    pub locals: HashMap<String, HashMap<String, Ty>>,
+    // Number of ill-typed applications in each definition.
+    pub errors: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Infer {
+    parent: Vec<usize>,
+    terms: Vec<Term>,
+    trail: Vec<usize>,
+    schemes: HashMap<String, Ty>,
+    // Definitions of the component being inferred.
+    mono: HashMap<String, usize>,
+    locals: Vec<(String, usize)>,
+    errors: usize,
+}
+
+impl Infer {
+    fn node(&mut self, t: Term) -> usize {
+        self.parent.push(self.terms.len());
+        self.terms.push(t);
+        self.terms.len() - 1
+    }
+
+    fn fresh(&mut self) -> usize {
+        self.node(Term::Var)
+    }
+
+    fn func(&mut self, p: usize, r: usize) -> usize {
+        self.node(Term::Func(p, r))
+    }
+
+    fn find(&self, mut n: usize) -> usize {
+        while self.parent[n] != n {
+            n = self.parent[n];
+        }
+        n
+    }
+
+    fn link(&mut self, from: usize, to: usize) {
+        self.parent[from] = to;
+        self.trail.push(from);
+    }
+
+    fn rollback(&mut self, mark: usize) {
+        while self.trail.len() > mark {
+            let n = self.trail.pop().unwrap();
+            self.parent[n] = n;
+        }
+    }
+
+    fn occurs(&self, v: usize, n: usize, visited: &mut HashSet<usize>) -> bool {
+        let n = self.find(n);
+        if n == v {
+            return true;
+        }
+        if !visited.insert(n) {
+            return false;
+        }
+        match self.terms[n] {
+            Term::List(e) => self.occurs(v, e, visited),
+            Term::Pair(a, b) | Term::Func(a, b) => self.occurs(v, a, visited) || self.occurs(v, b, visited),
+            _ => false,
+        }
+    }
+
+    fn is_ground(&self, n: usize, path: &mut Vec<usize>) -> bool {
+        let n = self.find(n);
+        if path.contains(&n) {
+            return false;
+        }
+        path.push(n);
+        let ground = match self.terms[n] {
+            Term::Var => false,
+            Term::Int | Term::Bool => true,
+            Term::List(e) => self.is_ground(e, path),
+            Term::Pair(a, b) | Term::Func(a, b) => self.is_ground(a, path) && self.is_ground(b, path),
+        };
+        path.pop();
+        ground
+    }
+
+    fn unify(&mut self, a: usize, b: usize) -> Result<(), ()> {
+        let a = self.find(a);
+        let b = self.find(b);
+        if a == b {
+            return Ok(());
+        }
+        match (self.terms[a], self.terms[b]) {
+            (Term::Var, _) => {
+                if !self.occurs(a, b, &mut HashSet::new()) {
+                    self.link(a, b);
+                    return Ok(());
+                }
+                match self.terms[b] {
+                    // Recursion on the tail of a pair, e.g. "f x = cons y (f
+                    // x)", makes a list.
+                    Term::Pair(_, _) => {
+                        let e = self.fresh();
+                        let list = self.node(Term::List(e));
+                        self.link(a, list);
+                        self.unify(b, list)
+                    },
+                    _ => Err(()),
+                }
+            },
+            (_, Term::Var) => self.unify(b, a),
+            (Term::Int, Term::Int) | (Term::Bool, Term::Bool) => {
+                self.link(a, b);
+                Ok(())
+            },
+            (Term::List(x), Term::List(y)) => {
+                self.link(a, b);
+                self.unify(x, y)
+            },
+            (Term::Pair(h1, t1), Term::Pair(h2, t2)) | (Term::Func(h1, t1), Term::Func(h2, t2)) => {
+                self.link(a, b);
+                self.unify(h1, h2)?;
+                self.unify(t1, t2)
+            },
+            (Term::Pair(h, t), Term::List(e)) => {
+                self.link(a, b);
+                self.unify(h, e)?;
+                self.unify(t, b)
+            },
+            (Term::List(_), Term::Pair(_, _)) => self.unify(b, a),
+            // The data stays the representative, the function view is only
+            // checked.
+            (Term::Func(p, r), Term::Pair(h, t)) => {
+                self.link(a, b);
+                let t_r = self.func(t, r);
+                let f = self.func(h, t_r);
+                self.unify(p, f)
+            },
+            (Term::Func(p, r), Term::List(e)) => {
+                self.link(a, b);
+                let t_r = self.func(b, r);
+                let f = self.func(e, t_r);
+                self.unify(p, f)
+            },
+            (Term::Func(p, r), Term::Bool) => {
+                self.link(a, b);
+                let p_p = self.func(p, p);
+                self.unify(r, p_p)
+            },
+            (Term::Pair(_, _), Term::Func(_, _)) | (Term::List(_), Term::Func(_, _)) |
+            (Term::Bool, Term::Func(_, _)) => self.unify(b, a),
+            _ => Err(()),
+        }
+    }
+
+    fn instantiate(&mut self, t: &Ty, vars: &mut HashMap<usize, usize>) -> usize {
+        match t {
+            Ty::Var(i) => {
+                if let Some(n) = vars.get(i) {
+                    return *n;
+                }
+                let n = self.fresh();
+                vars.insert(*i, n);
+                n
+            },
+            Ty::Int => self.node(Term::Int),
+            Ty::Bool => self.node(Term::Bool),
+            Ty::List(e) => {
+                let e = self.instantiate(e, vars);
+                self.node(Term::List(e))
+            },
+            Ty::Pair(a, b) => {
+                let a = self.instantiate(a, vars);
+                let b = self.instantiate(b, vars);
+                self.node(Term::Pair(a, b))
+            },
+            Ty::Func(a, b) => {
+                let a = self.instantiate(a, vars);
+                let b = self.instantiate(b, vars);
+                self.func(a, b)
+            },
+        }
+    }
+
+    // Converts a node to a type, numbering the variables in `vars`. Cyclic
+    // types, which come from applying pairs and booleans, are cut off with a
+    // variable.
+    fn export(&self, n: usize, vars: &mut HashMap<usize, usize>, path: &mut Vec<usize>) -> Ty {
+        let n = self.find(n);
+        if path.contains(&n) || self.terms[n].is_var() {
+            let i = vars.len();
+            return Ty::Var(*vars.entry(n).or_insert(i));
+        }
+        path.push(n);
+        let t = match self.terms[n] {
+            Term::List(e) => Ty::List(Box::new(self.export(e, vars, path))),
+            Term::Pair(a, b) => Ty::Pair(Box::new(self.export(a, vars, path)), Box::new(self.export(b, vars, path))),
+            Term::Func(a, b) => Ty::Func(Box::new(self.export(a, vars, path)), Box::new(self.export(b, vars, path))),
+            Term::Int => Ty::Int,
+            _ => Ty::Bool,
+        };
+        path.pop();
+        t
+    }
+
+    fn apply(&mut self, f: usize, x: usize) -> usize {
+        let r = self.fresh();
+        let expected = self.func(x, r);
+        let mark = self.trail.len();
+        if self.unify(f, expected).is_err() {
+            self.rollback(mark);
+            self.errors += 1;
+        }
+        r
+    }
+
+    fn builtin(&mut self, v: &Value) -> usize {
+        let int = |s: &mut Self| s.node(Term::Int);
+        match v {
+            Value::ADD | Value::MUL | Value::DIV => {
+                let (a, b, c) = (int(self), int(self), int(self));
+                let b_c = self.func(b, c);
+                self.func(a, b_c)
+            },
+            Value::NEG => {
+                let (a, b) = (int(self), int(self));
+                self.func(a, b)
+            },
+            Value::EQ | Value::LT => {
+                let (a, b) = (int(self), int(self));
+                let c = self.node(Term::Bool);
+                let b_c = self.func(b, c);
+                self.func(a, b_c)
+            },
+            Value::CONS => {
+                let (a, b) = (self.fresh(), self.fresh());
+                let pair = self.node(Term::Pair(a, b));
+                let b_pair = self.func(b, pair);
+                self.func(a, b_pair)
+            },
+            Value::CAR | Value::CDR => {
+                let (a, b) = (self.fresh(), self.fresh());
+                let pair = self.node(Term::Pair(a, b));
+                self.func(pair, if *v == Value::CAR { a } else { b })
+            },
+            Value::ISNIL => {
+                let e = self.fresh();
+                let list = self.node(Term::List(e));
+                let b = self.node(Term::Bool);
+                self.func(list, b)
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn infer(&mut self, v: &Value) -> usize {
+        match v {
+            Value::INT(_) => self.node(Term::Int),
+            Value::T | Value::F => self.node(Term::Bool),
+            Value::SYMBOL(s) => {
+                if let Some((_, n)) = self.locals.iter().rev().find(|(name, _)| name == s) {
+                    return *n;
+                }
+                if let Some(n) = self.mono.get(s) {
+                    return *n;
+                }
+                match self.schemes.get(s).cloned() {
+                    Some(t) => self.instantiate(&t, &mut HashMap::new()),
+                    None => self.fresh(),
+                }
+            },
+            Value::Func(param, body) => {
+                let p = self.fresh();
+                self.locals.push((param.clone(), p));
+                let b = self.infer(body);
+                // Keep the binding so that the type of the parameter can be
+                // exported afterwards; names are unique within a definition.
+                let pos = self.locals.iter().rposition(|(name, _)| name == param).unwrap();
+                let binding = self.locals.remove(pos);
+                self.locals.insert(0, binding);
+                self.func(p, b)
+            },
+            // A redex is a let binding. Inferring the argument first types
+            // self applications such as "(\x. x x) (a < b)" used for "||".
+            Value::Apply(f, x) if matches!(**f, Value::Func(_, _)) => {
+                let (param, body) = match &**f {
+                    Value::Func(param, body) => (param, body),
+                    _ => unreachable!(),
+                };
+                let x = self.infer(x);
+                self.locals.push((param.clone(), x));
+                let b = self.infer(body);
+                let pos = self.locals.iter().rposition(|(name, _)| name == param).unwrap();
+                let binding = self.locals.remove(pos);
+                self.locals.insert(0, binding);
+                b
+            },
+            Value::Apply(f, x) => {
+                let local = match &**f {
+                    Value::SYMBOL(s) => self.locals.iter().any(|(name, _)| name == s),
+                    _ => false,
+                };
+                let f = self.infer(f);
+                // "p t" and "p f" are the first and second of a pair.
+                if self.terms[self.find(f)].is_var() && (**x == Value::T || **x == Value::F) {
+                    let (a, b) = (self.fresh(), self.fresh());
+                    let pair = self.node(Term::Pair(a, b));
+                    self.unify(f, pair).unwrap();
+                    return if **x == Value::T { a } else { b };
+                }
+                // "p (\a. \b. ...)" takes a pair, or a list, apart. Only
+                // parameters are taken for pairs, since calls and recursive
+                // definitions are variables too until they are known.
+                let continuation = match &**x {
+                    Value::Func(_, body) => matches!(**body, Value::Func(_, _)),
+                    _ => false,
+                };
+                if local && continuation && self.terms[self.find(f)].is_var() {
+                    let (a, b) = (self.fresh(), self.fresh());
+                    let pair = self.node(Term::Pair(a, b));
+                    self.unify(f, pair).unwrap();
+                }
+                let x = self.infer(x);
+                self.apply(f, x)
+            },
+            Value::List(items) => {
+                let items = items.iter().map(|v| self.infer(v)).collect::<Vec<_>>();
+                // Literals of the same known type are lists, others are
+                // tuples ending with a list.
+                let e = self.fresh();
+                let list = self.node(Term::List(e));
+                let mark = self.trail.len();
+                if items.iter().all(|&n| self.is_ground(n, &mut Vec::new())) &&
+                    items.iter().all(|&n| self.unify(e, n).is_ok()) {
+                    return list;
+                }
+                self.rollback(mark);
+                items.into_iter().rev().fold(list, |tail, hd| self.node(Term::Pair(hd, tail)))
+            },
+            v => self.builtin(v),
+        }
+    }
+}
+
+// Returns the strongly connected components of the dependency graph in
+// dependency order.
+fn components(defs: &[(String, Value)]) -> Vec<Vec<usize>> {
+    fn refs(v: &Value, index: &HashMap<&str, usize>, out: &mut Vec<usize>) {
+        match v {
+            Value::SYMBOL(s) => out.extend(index.get(s.as_str())),
+            Value::Func(_, body) => refs(body, index, out),
+            Value::Apply(f, x) => {
+                refs(f, index, out);
+                refs(x, index, out);
+            },
+            Value::List(items) => items.iter().for_each(|v| refs(v, index, out)),
+            _ => (),
+        }
+    }
+    let index = defs.iter().enumerate().map(|(i, (name, _))| (name.as_str(), i)).collect::<HashMap<_, _>>();
+    let edges = defs.iter().map(|(_, v)| {
+        let mut out = Vec::new();
+        refs(v, &index, &mut out);
+        out
+    }).collect::<Vec<_>>();
+
+    callgraph::components(&edges)
+}
+
+// `known` are types given by the user, which are trusted over inference.
+pub fn infer(defs: &[(String, Value)], known: &HashMap<String, Ty>) -> Inferred {
+    let mut inf = Infer{schemes: known.clone(), ..Default::default()};
+    let mut result = Inferred::default();
+    for component in components(defs) {
+        inf.mono.clear();
+        for &i in component.iter() {
+            if !known.contains_key(&defs[i].0) {
+                let n = inf.fresh();
+                inf.mono.insert(defs[i].0.clone(), n);
+            }
+        }
+        let mut locals = Vec::new();
+        for &i in component.iter() {
+            let (name, value) = &defs[i];
+            inf.errors = 0;
+            inf.locals.clear();
+            let n = inf.infer(value);
+            if let Some(&m) = inf.mono.get(name) {
+                let mark = inf.trail.len();
+                if inf.unify(m, n).is_err() {
+                    inf.rollback(mark);
+                    inf.errors += 1;
+                }
+            }
+            result.errors.insert(name.clone(), inf.errors);
+            locals.push((name.clone(), std::mem::take(&mut inf.locals), n));
+        }
+        for (name, params, n) in locals {
+            let mut vars = HashMap::new();
+            let t = match inf.mono.get(&name) {
+                Some(&m) => inf.export(m, &mut vars, &mut Vec::new()),
+                None => inf.export(n, &mut vars, &mut Vec::new()),
+            };
+            let params = params.into_iter()
+                .map(|(param, p)| (param, inf.export(p, &mut vars, &mut Vec::new())))
+                .collect();
+            result.locals.insert(name.clone(), params);
+            result.globals.insert(name, t);
+        }
+        for &i in component.iter() {
+            let name = &defs[i].0;
+            if let Some(t) = result.globals.get(name) {
+                if !known.contains_key(name) {
+                    inf.schemes.insert(name.clone(), t.clone());
+                }
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn test_infer() {
+    fn func(param: &str, body: Value) -> Value {
+        Value::Func(param.to_string(), Box::new(body))
+    }
+    fn ap(f: Value, args: Vec<Value>) -> Value {
+        args.into_iter().fold(f, |f, x| Value::Apply(Box::new(f), Box::new(x)))
+    }
+    fn sym(s: &str) -> Value {
+        Value::SYMBOL(s.to_string())
+    }
+    let defs = vec![
+        ("use".to_string(), ap(sym("pair"), vec![ap(sym("len"), vec![Value::List(vec![Value::INT(1)])]),
+                                                  ap(sym("fst"), vec![ap(sym("pair"), vec![Value::T, Value::INT(1)])])])),
+        ("len".to_string(), func("x0", ap(Value::ISNIL, vec![
+            sym("x0"), Value::INT(0),
+            ap(Value::ADD, vec![Value::INT(1), ap(sym("len"), vec![ap(Value::CDR, vec![sym("x0")])])])]))),
+        ("pair".to_string(), func("x1", func("x2", ap(Value::CONS, vec![sym("x1"), sym("x2")])))),
+        ("fst".to_string(), func("x3", ap(sym("x3"), vec![Value::T]))),
+        ("bad".to_string(), func("x4", ap(Value::ADD, vec![sym("x4"), Value::T]))),
+        ("snd".to_string(), func("x5", ap(sym("x5"), vec![func("x6", func("x7", sym("x7")))]))),
+        ("drop".to_string(), func("x8", ap(sym("x8"), vec![func("x9", func("x10", ap(sym("drop"), vec![sym("x10")])))]))),
+    ];
+    let inferred = infer(&defs, &HashMap::new());
+    let sig = |name: &str| inferred.globals[name].to_string();
+    assert_eq!(sig("len"), "'a list -> int");
+    assert_eq!(sig("pair"), "'a -> 'b -> ('a, 'b)");
+    assert_eq!(sig("fst"), "('a, 'b) -> 'a");
+    assert_eq!(sig("use"), "(int, bool)");
+    assert_eq!(sig("bad"), "int -> 'a");
+    assert_eq!(sig("snd"), "('a, 'b) -> 'b");
+    assert_eq!(sig("drop"), "'a list -> 'b");
+    assert_eq!(inferred.errors["bad"], 1);
+    assert_eq!(inferred.errors["use"], 0);
+    assert_eq!(inferred.locals["len"]["x0"].to_type(0), Some(Type::List));
+    assert_eq!(inferred.globals["len"].to_type(1),
+               Some(Type::Func(vec![(None, Some(Type::List))], Box::new(Some(Type::Int)))));
+}
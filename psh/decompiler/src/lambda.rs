@@ -13,7 +13,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use super::ast;
 
@@ -60,211 +60,380 @@ impl fmt::Display for Value {
     }
 }
 
-struct Lamdifier {
-    next_id: usize,
+// Locally nameless representation used for reduction. Bound variables are
+// de Bruijn indices, so substitution never captures; free symbols (globals)
+// and constants are kept as leaves.
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum Term {
+    Var(usize),
+    Lam(Box<Term>),
+    App(Box<Term>, Box<Term>),
+    Leaf(Value),
+}
+
+fn lam(body: Term) -> Term {
+    Term::Lam(Box::new(body))
+}
+
+fn app(fun: Term, arg: Term) -> Term {
+    Term::App(Box::new(fun), Box::new(arg))
 }
 
-impl Lamdifier {
-    fn new() -> Self {
-        Self{next_id: 0}
+fn lamdify_term(value: &ast::Value) -> Term {
+    use Term::Var;
+    match value {
+        ast::Value::Apply(fun, arg) => app(lamdify_term(fun), lamdify_term(arg)),
+        ast::Value::SYMBOL(s) => Term::Leaf(Value::SYMBOL(s.clone())),
+        ast::Value::INT(i) => Term::Leaf(Value::INT(*i)),
+        ast::Value::ADD => Term::Leaf(Value::ADD),
+        ast::Value::CAR => Term::Leaf(Value::CAR),
+        ast::Value::CDR => Term::Leaf(Value::CDR),
+        ast::Value::CONS => Term::Leaf(Value::CONS),
+        ast::Value::DIV => Term::Leaf(Value::DIV),
+        ast::Value::EQ => Term::Leaf(Value::EQ),
+        ast::Value::ISNIL => Term::Leaf(Value::ISNIL),
+        ast::Value::LT => Term::Leaf(Value::LT),
+        ast::Value::MUL => Term::Leaf(Value::MUL),
+        ast::Value::NEG => Term::Leaf(Value::NEG),
+        ast::Value::NIL => Term::Leaf(Value::NIL),
+        ast::Value::T => Term::Leaf(Value::T),
+        // (I x) = x
+        ast::Value::I => lam(Var(0)),
+        // (B x0 x1 x2) = (x0 (x1 x2))
+        ast::Value::B => lam(lam(lam(app(Var(2), app(Var(1), Var(0)))))),
+        // (C x0 x1 x2) = (x0 x2 x1)
+        ast::Value::C => lam(lam(lam(app(app(Var(2), Var(0)), Var(1))))),
+        // (S x0 x1 x2) = ((x0 x2) (x1 x2))
+        ast::Value::S => lam(lam(lam(app(app(Var(2), Var(0)), app(Var(1), Var(0)))))),
     }
+}
 
-    fn lamdify(&mut self, value: &ast::Value) -> Value {
-        match value {
-            ast::Value::Apply(fun, arg) => {
-                let fun = self.lamdify(fun);
-                let arg = self.lamdify(arg);
+// Symbols bound by an enclosing Func become indices; the others are free.
+fn to_term(value: &Value, scope: &mut Vec<String>) -> Term {
+    match value {
+        Value::Func(param, body) => {
+            scope.push(param.clone());
+            let body = to_term(body, scope);
+            scope.pop();
+            lam(body)
+        },
+        Value::Apply(fun, arg) => app(to_term(fun, scope), to_term(arg, scope)),
+        Value::SYMBOL(s) => match scope.iter().rev().position(|p| p == s) {
+            Some(i) => Term::Var(i),
+            None => Term::Leaf(value.clone()),
+        },
+        v => Term::Leaf(v.clone()),
+    }
+}
+
+// Names every binder x0, x1, ... in order, skipping the free symbols so
+// that none of them is captured.
+fn to_value(term: &Term) -> Value {
+    fn free(term: &Term, symbols: &mut HashSet<String>) {
+        match term {
+            Term::Lam(body) => free(body, symbols),
+            Term::App(fun, arg) => {
+                free(fun, symbols);
+                free(arg, symbols);
+            },
+            Term::Leaf(Value::SYMBOL(s)) => {
+                symbols.insert(s.clone());
+            },
+            _ => (),
+        }
+    }
+    fn aux(term: &Term, scope: &mut Vec<String>, next_id: &mut usize, free: &HashSet<String>) -> Value {
+        match term {
+            Term::Var(i) => Value::SYMBOL(scope[scope.len() - 1 - i].clone()),
+            Term::Lam(body) => {
+                let name = loop {
+                    let name = format!("x{}", next_id);
+                    *next_id += 1;
+                    if !free.contains(&name) {
+                        break name;
+                    }
+                };
+                scope.push(name.clone());
+                let body = aux(body, scope, next_id, free);
+                scope.pop();
+                Value::Func(name, Box::new(body))
+            },
+            Term::App(fun, arg) => {
+                let fun = aux(fun, scope, next_id, free);
+                let arg = aux(arg, scope, next_id, free);
                 Value::Apply(Box::new(fun), Box::new(arg))
             },
-            ast::Value::SYMBOL(s) => Value::SYMBOL(s.clone()),
-            ast::Value::INT(i) => Value::INT(*i),
-            ast::Value::ADD => Value::ADD,
-            ast::Value::CAR => Value::CAR,
-            ast::Value::CDR => Value::CDR,
-            ast::Value::CONS => Value::CONS,
-            ast::Value::DIV => Value::DIV,
-            ast::Value::EQ => Value::EQ,
-            ast::Value::ISNIL => Value::ISNIL,
-            ast::Value::LT => Value::LT,
-            ast::Value::MUL => Value::MUL,
-            ast::Value::NEG => Value::NEG,
-            ast::Value::NIL => Value::NIL,
-            ast::Value::T => Value::T,
-            ast::Value::I => self.new_i(),
-            ast::Value::B => self.new_b(),
-            ast::Value::C => self.new_c(),
-            ast::Value::S => self.new_s(),
+            Term::Leaf(v) => v.clone(),
         }
     }
+    let mut symbols = HashSet::new();
+    free(term, &mut symbols);
+    aux(term, &mut Vec::new(), &mut 0, &symbols)
+}
+
+// Expands the combinators into lambdas. Binders are named x0, x1, ... in
+// the order they appear.
+pub fn lamdify(value: &ast::Value) -> Value {
+    to_value(&lamdify_term(value))
+}
+
+// Adds d to the variables at or above cutoff, i.e. those bound outside.
+fn shift(term: &Term, d: usize, cutoff: usize) -> Term {
+    match term {
+        Term::Var(i) if *i >= cutoff => Term::Var(i + d),
+        Term::Lam(body) => lam(shift(body, d, cutoff + 1)),
+        Term::App(fun, arg) => app(shift(fun, d, cutoff), shift(arg, d, cutoff)),
+        t => t.clone(),
+    }
+}
 
-    fn new_id(&mut self) -> String {
-        let name = format!("x{}", self.next_id);
-        self.next_id += 1;
-        name
+// Replaces the variable bound depth binders up with arg, and removes that
+// binder from the indices of the outer variables.
+fn instantiate(term: &Term, depth: usize, arg: &Term) -> Term {
+    match term {
+        Term::Var(i) if *i == depth => shift(arg, depth, 0),
+        Term::Var(i) if *i > depth => Term::Var(i - 1),
+        Term::Lam(body) => lam(instantiate(body, depth + 1, arg)),
+        Term::App(fun, arg2) => app(instantiate(fun, depth, arg), instantiate(arg2, depth, arg)),
+        t => t.clone(),
     }
+}
 
-    // (I x) = x
-    fn new_i(&mut self) -> Value {
-        let x = self.new_id();
-        Value::Func(x.clone(), Box::new(Value::SYMBOL(x)))
+// Exchanges the variables bound depth and depth + 1 binders up.
+fn swap(term: &Term, depth: usize) -> Term {
+    match term {
+        Term::Var(i) if *i == depth => Term::Var(depth + 1),
+        Term::Var(i) if *i == depth + 1 => Term::Var(depth),
+        Term::Lam(body) => lam(swap(body, depth + 1)),
+        Term::App(fun, arg) => app(swap(fun, depth), swap(arg, depth)),
+        t => t.clone(),
     }
+}
 
-    // (B x0 x1 x2) = (x0 (x1 x2))
-    fn new_b(&mut self) -> Value {
-        let x0 = self.new_id();
-        let x1 = self.new_id();
-        let x2 = self.new_id();
-        Value::Func(x0.clone(), Box::new(
-            Value::Func(x1.clone(), Box::new(
-                Value::Func(x2.clone(), Box::new(
-                    Value::Apply(
-                        Box::new(Value::SYMBOL(x0)),
-                        Box::new(Value::Apply(
-                            Box::new(Value::SYMBOL(x1)),
-                            Box::new(Value::SYMBOL(x2))
-                        ))
-                    )
-                ))
-            ))
-        ))
+// Occurrences of the variable bound depth binders up.
+fn occurrences(term: &Term, depth: usize) -> usize {
+    match term {
+        Term::Var(i) => (*i == depth) as usize,
+        Term::Lam(body) => occurrences(body, depth + 1),
+        Term::App(fun, arg) => occurrences(fun, depth) + occurrences(arg, depth),
+        Term::Leaf(_) => 0,
     }
+}
 
-    // (C x0 x1 x2) = (x0 x2 x1)
-    fn new_c(&mut self) -> Value {
-        let x0 = self.new_id();
-        let x1 = self.new_id();
-        let x2 = self.new_id();
-        Value::Func(x0.clone(), Box::new(
-            Value::Func(x1.clone(), Box::new(
-                Value::Func(x2.clone(), Box::new(
-                    Value::Apply(
-                        Box::new(Value::Apply(
-                            Box::new(Value::SYMBOL(x0)),
-                            Box::new(Value::SYMBOL(x2)),
-                        )),
-                        Box::new(Value::SYMBOL(x1)),
-                    )
-                ))
-            ))
-        ))
+// Arguments that are cheaper to copy than to bind.
+fn is_atomic(term: &Term) -> bool {
+    matches!(term, Term::Var(_) | Term::Leaf(Value::SYMBOL(_)) | Term::Leaf(Value::INT(_)))
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Contract only redexes whose parameter is used once or whose argument
+    // is a variable or an integer. The others are kept, and become lets
+    // later, so no code is duplicated. Otherwise reduces to the
+    // normal form in normal order, which may not exist.
+    pub sharing: bool,
+    // Rewrites {\x.(f x)} to f when x is not free in f. Off by default,
+    // since expr::construct finds list matches by the parameters.
+    pub eta: bool,
+    // Contractions (beta and eta) before giving up.
+    pub max_steps: usize,
+    // Terms growing beyond this many nodes are not reduced further.
+    pub max_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options{sharing: true, eta: false, max_steps: 1_000_000, max_size: 1_000_000}
     }
+}
 
-    // (S x0 x1 x2) = ((x0 x2) (x1 x2))
-    fn new_s(&mut self) -> Value {
-        let x0 = self.new_id();
-        let x1 = self.new_id();
-        let x2 = self.new_id();
-        Value::Func(x0.clone(), Box::new(
-            Value::Func(x1.clone(), Box::new(
-                Value::Func(x2.clone(), Box::new(
-                    Value::Apply(Box::new(Value::Apply(Box::new(Value::SYMBOL(x0)),
-                                                       Box::new(Value::SYMBOL(x2.clone())))),
-                                 Box::new(Value::Apply(Box::new(Value::SYMBOL(x1)),
-                                                       Box::new(Value::SYMBOL(x2)))),
-                    )
-                ))
-            ))
-        ))
+#[derive(Debug)]
+pub struct Reduced {
+    pub value: Value,
+    pub steps: usize,
+    // False if a limit was hit, i.e. value may have redexes left.
+    pub complete: bool,
+}
+
+fn size(term: &Term) -> usize {
+    match term {
+        Term::Lam(body) => 1 + size(body),
+        Term::App(fun, arg) => 1 + size(fun) + size(arg),
+        _ => 1,
     }
 }
 
-pub fn lamdify(value: &ast::Value) -> Value {
-    Lamdifier::new().lamdify(value)
+struct Reducer<'a> {
+    options: &'a Options,
+    steps: usize,
+    complete: bool,
 }
 
-fn count_symbols(value: &Value) -> HashMap<String, usize> {
-    fn aux(counts: &mut HashMap<String, usize>, value: &Value) {
-        match value {
-            Value::Func(_, body) => aux(counts, body),
-            Value::Apply(func, arg) => {
-                aux(counts, func);
-                aux(counts, arg);
-            },
-            Value::SYMBOL(s) => *counts.entry(s.clone()).or_insert(0) += 1,
-            _ => (),
+impl<'a> Reducer<'a> {
+    fn step(&mut self) -> bool {
+        if self.steps >= self.options.max_steps {
+            self.complete = false;
+            return false;
         }
+        self.steps += 1;
+        true
     }
-    let mut counts = HashMap::new();
-    aux(&mut counts, value);
-    counts
-}
 
-// Takes the alpha-normalized value, and returns its evaluated one.
-// Here, substitution happens only when the variable appears only once.
-pub fn eval(value: Value) -> Value {
-    fn substitute(value: Value, env: &mut HashMap<String, Value>, num_symbols: &HashMap<String, usize>) -> Value {
-        match value {
-            Value::Func(param, body) =>
-                Value::Func(param, Box::new(substitute(*body, env, num_symbols))),
-            Value::Apply(fun, arg) => {
-                let fun = substitute(*fun, env, num_symbols);
-                let arg = substitute(*arg, env, num_symbols);
-                match fun {
-                    Value::Func(param, body) if num_symbols.get(&param) == Some(&1) => {
-                        env.insert(param.clone(), arg);
-                        let evaluated = substitute(*body, env, num_symbols);
-                        env.remove(&param);
-                        evaluated
-                    },
-                    fun => Value::Apply(Box::new(fun), Box::new(arg)),
-                }
+    fn contractible(&self, body: &Term, arg: &Term) -> bool {
+        !self.options.sharing || occurrences(body, 0) == 1 || is_atomic(arg)
+    }
+
+    fn beta(&mut self, body: &Term, arg: &Term) -> Option<Term> {
+        let term = instantiate(body, 0, arg);
+        if size(&term) > self.options.max_size {
+            self.complete = false;
+            return None;
+        }
+        if self.step() { Some(term) } else { None }
+    }
+
+    fn eta(&mut self, body: Term) -> Term {
+        if let Term::App(fun, arg) = &body {
+            if self.options.eta && **arg == Term::Var(0) && occurrences(fun, 0) == 0 && self.step() {
+                // The variable does not occur, so this only renumbers.
+                return instantiate(fun, 0, &Term::Leaf(Value::NIL));
+            }
+        }
+        lam(body)
+    }
+
+    fn normalize(&mut self, term: Term) -> Term {
+        if self.options.sharing {
+            self.normalize_shared(term)
+        } else {
+            self.normalize_full(term)
+        }
+    }
+
+    // Weak head normal form, for normal order reduction.
+    fn whnf(&mut self, term: Term) -> Term {
+        match term {
+            Term::App(fun, arg) => match self.whnf(*fun) {
+                Term::Lam(body) => match self.beta(&body, &arg) {
+                    Some(term) => self.whnf(term),
+                    None => app(lam(*body), *arg),
+                },
+                fun => app(fun, *arg),
             },
-            Value::SYMBOL(s) => {
-                if let Some(val) = env.remove(&s) {
-                    val
-                } else {
-                    Value::SYMBOL(s)
-                }
+            t => t,
+        }
+    }
+
+    fn normalize_full(&mut self, term: Term) -> Term {
+        match self.whnf(term) {
+            Term::Lam(body) => {
+                let body = self.normalize_full(*body);
+                self.eta(body)
             },
-            v => v,
+            Term::App(fun, arg) => app(self.normalize_full(*fun), self.normalize_full(*arg)),
+            t => t,
         }
     }
-    let substituted = {
-        let mut env = HashMap::new();
-        let num_symbols = count_symbols(&value);
-        substitute(value, &mut env, &num_symbols)
-    };
-    fn relabel(value: Value, env: &mut HashMap<String, Value>) -> Value {
-        match value {
-            Value::Func(param, body) => {
-                // unlike usual eval, this evaluates the body.
-                Value::Func(param, Box::new(relabel(*body, env)))
+
+    // Reduces innermost redexes first, since whether to contract depends on
+    // whether the argument is atomic.
+    fn normalize_shared(&mut self, term: Term) -> Term {
+        match term {
+            Term::Lam(body) => {
+                let body = self.normalize_shared(*body);
+                self.eta(body)
             },
-            Value::Apply(fun, arg) => {
-                let fun = relabel(*fun, env);
-                let arg = relabel(*arg, env);
-                match (fun, arg) {
-                    (Value::Func(param, body), a @ Value::SYMBOL(_)) => {
-                        env.insert(param.clone(), a);
-                        let relabeled = relabel(*body, env);
-                        env.remove(&param);
-                        relabeled
-                    },
-                    (Value::Func(param, body), a @ Value::INT(_)) => {
-                        env.insert(param.clone(), a);
-                        let relabeled = relabel(*body, env);
-                        env.remove(&param);
-                        relabeled
-                    },
-                    (f, a) => Value::Apply(Box::new(f), Box::new(a)),
-                }
+            Term::App(fun, arg) => {
+                let fun = self.normalize_shared(*fun);
+                let arg = self.normalize_shared(*arg);
+                self.apply(fun, arg)
             },
-            mut e @ Value::SYMBOL(_) => loop {
-                match e {
-                    Value::SYMBOL(s) => {
-                        let val = env.get(&s);
-                        if val.is_none() {
-                            return Value::SYMBOL(s);
-                        }
-                        e = val.unwrap().clone()
-                    },
-                    _ => return e,
-                };
+            t => t,
+        }
+    }
+
+    // Applies a reduced function to a reduced argument.
+    fn apply(&mut self, fun: Term, arg: Term) -> Term {
+        match fun {
+            Term::Lam(body) if self.contractible(&body, &arg) => match self.beta(&body, &arg) {
+                Some(term) => self.normalize_shared(term),
+                None => app(lam(*body), arg),
             },
-            v => v,
+            Term::Lam(body) => self.bind(*body, arg),
+            fun => app(fun, arg),
         }
     }
-    let mut env = HashMap::new();
-    eprintln!("Before relabel: {}", substituted);
-    relabel(substituted, &mut env)
+
+    // Keeps the redex, which becomes a let. Lets binding a function are
+    // moved into it, (let x = a in {\y.f}) is {\y.(let x = a in f)}, so that
+    // the arguments can be substituted and parameters stay together.
+    fn bind(&mut self, body: Term, arg: Term) -> Term {
+        match body {
+            Term::Lam(inner) => {
+                let inner = self.apply(lam(swap(&inner, 0)), shift(&arg, 1, 0));
+                lam(inner)
+            },
+            body => app(lam(body), arg),
+        }
+    }
+}
+
+// Reduces the value within the limits of the options. Binders of the
+// result are renamed to x0, x1, ...
+pub fn reduce(value: &Value, options: &Options) -> Reduced {
+    let mut reducer = Reducer{options, steps: 0, complete: true};
+    let term = reducer.normalize(to_term(value, &mut Vec::new()));
+    Reduced{value: to_value(&term), steps: reducer.steps, complete: reducer.complete}
+}
+
+// Applies the functions to their arguments when it does not duplicate
+// anything. See Options::sharing.
+pub fn eval(value: Value) -> Value {
+    reduce(&value, &Options::default()).value
+}
+
+#[test]
+fn test_eval() {
+    fn sym(s: &str) -> Value {
+        Value::SYMBOL(s.to_string())
+    }
+    fn func(param: &str, body: Value) -> Value {
+        Value::Func(param.to_string(), Box::new(body))
+    }
+    fn apply(fun: Value, arg: Value) -> Value {
+        Value::Apply(Box::new(fun), Box::new(arg))
+    }
+    let eval_str = |v: Value| eval(v).to_string();
+
+    // Substituting by name would capture y: {\y.(y y)}.
+    assert_eq!(eval_str(apply(func("x", func("y", apply(sym("x"), sym("y")))), sym("y"))), "{\\x0.(y x0)}");
+    // ... or replace the inner x, which shadows the outer one: {\x.a}.
+    assert_eq!(eval_str(apply(func("x", func("x", sym("x"))), sym("a"))), "{\\x0.x0}");
+    // Fresh names skip the free symbols.
+    assert_eq!(eval_str(apply(func("x", func("y", apply(sym("x"), sym("y")))), sym("x0"))), "{\\x1.(x0 x1)}");
+    // x appears once in the term, but twice after y is substituted, so it
+    // is kept as a let rather than duplicating (f a).
+    let dup = func("y", apply(sym("y"), sym("y")));
+    assert_eq!(eval_str(apply(func("x", apply(dup.clone(), sym("x"))), apply(sym("f"), sym("a")))),
+               "({\\x0.(x0 x0)} (f a))");
+
+    // Without sharing, reduces to the normal form in normal order.
+    let full = Options{sharing: false, ..Options::default()};
+    let omega = apply(dup.clone(), dup.clone());
+    let r = reduce(&apply(func("x", sym("a")), omega.clone()), &full);
+    assert_eq!((r.value.to_string(), r.complete), ("a".to_string(), true));
+    let r = reduce(&omega, &Options{max_steps: 10, ..full.clone()});
+    assert_eq!((r.value.to_string(), r.steps, r.complete),
+               ("({\\x0.(x0 x0)} {\\x1.(x1 x1)})".to_string(), 10, false));
+
+    let eta = Options{eta: true, ..Options::default()};
+    let r = reduce(&func("x", apply(apply(sym("f"), sym("a")), sym("x"))), &eta);
+    assert_eq!(r.value.to_string(), "(f a)");
+    let r = reduce(&func("x", apply(sym("x"), sym("x"))), &eta);
+    assert_eq!(r.value.to_string(), "{\\x0.(x0 x0)}");
+
+    // (S K' I x) = (K' x (I x)) with K' = t.
+    let (defs, errors) = super::parser::parse(":1 = ap ap ap s t i :2\n");
+    assert!(errors.is_empty());
+    assert_eq!(eval_str(lamdify(&defs[0].value)), "((t :2) :2)");
 }
@@ -13,6 +13,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt;
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Token {
     SYMBOL(String),
@@ -37,8 +39,82 @@ pub enum Token {
     T,
 }
 
-fn to_token(s: &str) -> Token {
-    match s {
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::SYMBOL(s) => write!(f, "{}", s),
+            Token::INT(v) => write!(f, "{}", v),
+            Token::ADD => write!(f, "add"),
+            Token::AP => write!(f, "ap"),
+            Token::B => write!(f, "b"),
+            Token::C => write!(f, "c"),
+            Token::CAR => write!(f, "car"),
+            Token::CDR => write!(f, "cdr"),
+            Token::CONS => write!(f, "cons"),
+            Token::DIV => write!(f, "div"),
+            Token::EQ => write!(f, "eq"),
+            Token::EQUAL => write!(f, "="),
+            Token::I => write!(f, "i"),
+            Token::ISNIL => write!(f, "isnil"),
+            Token::LT => write!(f, "lt"),
+            Token::MUL => write!(f, "mul"),
+            Token::NEG => write!(f, "neg"),
+            Token::NIL => write!(f, "nil"),
+            Token::S => write!(f, "s"),
+            Token::T => write!(f, "t"),
+        }
+    }
+}
+
+// 1-origin position in the source file.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Error {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(span: Span, message: String) -> Self {
+        Self{span, message}
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Symbols are either numbered definitions (":1029") or identifiers
+// ("galaxy", "x0").
+fn is_symbol(s: &str) -> bool {
+    if let Some(num) = s.strip_prefix(':') {
+        return !num.is_empty() && num.chars().all(|c| c.is_ascii_digit());
+    }
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn to_token(s: &str, span: Span) -> Result<Token, Error> {
+    Ok(match s {
         "add" => Token::ADD,
         "ap" => Token::AP,
         "b" => Token::B,
@@ -59,11 +135,31 @@ fn to_token(s: &str) -> Token {
         "=" => Token::EQUAL,
         _ => match s.parse::<isize>() {
             Ok(num) => Token::INT(num),
-            Err(_) => Token::SYMBOL(s.to_string()),
+            Err(_) if is_symbol(s) => Token::SYMBOL(s.to_string()),
+            Err(_) if s.trim_start_matches('-').chars().all(|c| c.is_ascii_digit()) =>
+                return Err(Error::new(span, format!("integer out of range: {}", s))),
+            Err(_) => return Err(Error::new(span, format!("invalid token: {}", s))),
         }
-    }
+    })
 }
 
-pub fn lex(s: &str) -> Vec<Token> {
-    s.split_whitespace().map(to_token).collect()
+// Splits a line into tokens. `line` is the line number used for spans.
+pub fn lex(s: &str, line: usize) -> Result<Vec<(Token, Span)>, Error> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut column = 0;
+    // The extra item flushes the last token.
+    for (pos, c) in s.char_indices().chain(std::iter::once((s.len(), ' '))) {
+        column += 1;
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((pos, column)),
+            (true, Some((begin, begin_column))) => {
+                let span = Span{line, column: begin_column};
+                tokens.push((to_token(&s[begin..pos], span)?, span));
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    Ok(tokens)
 }
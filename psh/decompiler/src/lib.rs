@@ -14,8 +14,15 @@
 // limitations under the License.
 
 pub mod ast;
+pub mod backend;
+pub mod cache;
+pub mod callgraph;
+pub mod combinators;
 pub mod expr;
+pub mod infer;
 pub mod lexer;
 pub mod parser;
+pub mod rewrite;
 pub mod lambda;
 pub mod simplified;
+pub mod symbols;
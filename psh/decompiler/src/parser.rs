@@ -15,49 +15,111 @@
 
 use super::ast;
 use super::lexer;
+use super::lexer::{Error, Span, Token};
 
-fn parse_value(iter: &mut dyn Iterator<Item = &lexer::Token>) -> ast::Value {
-    match iter.next() {
-        Some(lexer::Token::AP) => {
-            let fun = parse_value(iter);
-            let arg = parse_value(iter);
-            ast::Value::Apply(Box::new(fun), Box::new(arg))
-        },
-        Some(lexer::Token::SYMBOL(s)) => ast::Value::SYMBOL(s.clone()),
-        Some(lexer::Token::INT(v)) => ast::Value::INT(*v),
-        Some(lexer::Token::ADD) => ast::Value::ADD,
-        Some(lexer::Token::B) => ast::Value::B,
-        Some(lexer::Token::C) => ast::Value::C,
-        Some(lexer::Token::CAR) => ast::Value::CAR,
-        Some(lexer::Token::CDR) => ast::Value::CDR,
-        Some(lexer::Token::CONS) => ast::Value::CONS,
-        Some(lexer::Token::DIV) => ast::Value::DIV,
-        Some(lexer::Token::EQ) => ast::Value::EQ,
-        Some(lexer::Token::I) => ast::Value::I,
-        Some(lexer::Token::ISNIL) => ast::Value::ISNIL,
-        Some(lexer::Token::LT) => ast::Value::LT,
-        Some(lexer::Token::MUL) => ast::Value::MUL,
-        Some(lexer::Token::NEG) => ast::Value::NEG,
-        Some(lexer::Token::NIL) => ast::Value::NIL,
-        Some(lexer::Token::S) => ast::Value::S,
-        Some(lexer::Token::T) => ast::Value::T,
-        None => panic!("Unexpected EOL"),
-        x => panic!("Unexpected token: {:?}", x),
+struct Parser<'a> {
+    iter: std::slice::Iter<'a, (Token, Span)>,
+    // Position just past the last token, for errors at the end of line.
+    eol: Span,
+}
+
+impl<'a> Parser<'a> {
+    // `ap` is the application whose argument is being parsed, if any.
+    fn parse_value(&mut self, ap: Option<Span>) -> Result<ast::Value, Error> {
+        let (token, span) = match self.iter.next() {
+            Some((token, span)) => (token, *span),
+            None => return Err(Error::new(self.eol, match ap {
+                Some(ap) => format!("unexpected end of line; `ap` at {} is missing an argument", ap),
+                None => "unexpected end of line; expected an expression".to_string(),
+            })),
+        };
+        Ok(match token {
+            Token::AP => {
+                let fun = self.parse_value(Some(span))?;
+                let arg = self.parse_value(Some(span))?;
+                ast::Value::Apply(Box::new(fun), Box::new(arg))
+            },
+            Token::SYMBOL(s) => ast::Value::SYMBOL(s.clone()),
+            Token::INT(v) => ast::Value::INT(*v),
+            Token::ADD => ast::Value::ADD,
+            Token::B => ast::Value::B,
+            Token::C => ast::Value::C,
+            Token::CAR => ast::Value::CAR,
+            Token::CDR => ast::Value::CDR,
+            Token::CONS => ast::Value::CONS,
+            Token::DIV => ast::Value::DIV,
+            Token::EQ => ast::Value::EQ,
+            Token::I => ast::Value::I,
+            Token::ISNIL => ast::Value::ISNIL,
+            Token::LT => ast::Value::LT,
+            Token::MUL => ast::Value::MUL,
+            Token::NEG => ast::Value::NEG,
+            Token::NIL => ast::Value::NIL,
+            Token::S => ast::Value::S,
+            Token::T => ast::Value::T,
+            Token::EQUAL => return Err(Error::new(span, "unexpected `=` in an expression".to_string())),
+        })
     }
 }
 
-pub fn parse_line(line: &str) -> ast::Definition {
-    let tokens = lexer::lex(line);
-    let mut iter = tokens.iter();
-    let name = {
-        if let Some(lexer::Token::SYMBOL(name)) = iter.next() {
-            name
-        } else {
-            panic!("Unexpected synbol: {}", line)
-        }
+// Parses a "name = expr" line. `line_no` is the line number used for spans.
+pub fn parse_line(line: &str, line_no: usize) -> Result<ast::Definition, Error> {
+    let tokens = lexer::lex(line, line_no)?;
+    let eol = Span{line: line_no, column: line.chars().count() + 1};
+    let mut parser = Parser{iter: tokens.iter(), eol};
+    let name = match parser.iter.next() {
+        Some((Token::SYMBOL(name), _)) => name,
+        Some((token, span)) => return Err(Error::new(*span, format!("expected a definition name, found `{}`", token))),
+        None => return Err(Error::new(eol, "expected a definition".to_string())),
     };
-    if iter.next() != Some(&lexer::Token::EQUAL) {
-        panic!("Unexpected syntax: {}", line)
+    match parser.iter.next() {
+        Some((Token::EQUAL, _)) => (),
+        Some((token, span)) => return Err(Error::new(*span, format!("expected `=` after `{}`, found `{}`", name, token))),
+        None => return Err(Error::new(eol, format!("expected `=` after `{}`", name))),
+    }
+    let value = parser.parse_value(None)?;
+    if let Some((token, span)) = parser.iter.next() {
+        return Err(Error::new(*span, format!("unexpected `{}` after the end of the expression", token)));
     }
-    ast::Definition::new(name.clone(), Box::new(parse_value(&mut iter)))
-}
\ No newline at end of file
+    Ok(ast::Definition::new(name.clone(), Box::new(value)))
+}
+
+// Parses every non-empty line, collecting the errors instead of stopping at
+// the first one.
+pub fn parse(content: &str) -> (Vec<ast::Definition>, Vec<Error>) {
+    let mut defs = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_line(line, i + 1) {
+            Ok(def) => defs.push(def),
+            Err(e) => errors.push(e),
+        }
+    }
+    (defs, errors)
+}
+
+#[test]
+fn test_parse_errors() {
+    let def = parse_line(":1 = ap ap add 1 x0", 1).unwrap();
+    assert_eq!(def.name, ":1");
+
+    let check = |line: &str, column: usize, message: &str| {
+        let err = parse_line(line, 3).unwrap_err();
+        assert_eq!((err.span, err.message.as_str()), (Span{line: 3, column}, message), "{}", line);
+    };
+    check(":1 = ap ap add 1", 17, "unexpected end of line; `ap` at 3:6 is missing an argument");
+    check(":1 ap add 1", 4, "expected `=` after `:1`, found `ap`");
+    check(":1", 3, "expected `=` after `:1`");
+    check("ap = 1", 1, "expected a definition name, found `ap`");
+    check(":1 = add 1", 10, "unexpected `1` after the end of the expression");
+    check(":1 = ap add =", 13, "unexpected `=` in an expression");
+    check(":1 = ap add 1x", 13, "invalid token: 1x");
+    check(":1 = :x", 6, "invalid token: :x");
+
+    let (defs, errors) = parse(":1 = 1\n:2 = ap\n\n:3 = 3\n:4 4\n");
+    assert_eq!(defs.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(), vec![":1", ":3"]);
+    assert_eq!(errors.iter().map(|e| e.span.line).collect::<Vec<_>>(), vec![2, 5]);
+}
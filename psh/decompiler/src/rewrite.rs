@@ -0,0 +1,802 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Rewrite rules over expressions.
+//
+// Rules are written as S-expressions in rule files:
+//
+//   # x + -y is x - y.
+//   rule sub: (add ?x (neg ?y)) => (sub ?x ?y)
+//   rule sub_int: (add ?x ?n:int) => (sub ?x (negate ?n)) where (negative ?n)
+//   name List.len: (fun (?l) (match ?l 0 ?h ?t (add 1 (call @self ?t))))
+//
+// Patterns:
+// - (neg e), (not e), (isnil e), (add a b), (sub a b), (mul a b),
+//   (div a b), (mod a b), (eq a b), (lt a b), (or a b), (and a b),
+//   (if c a b), (match v e1 hd tl e2), (letcons hd tl e1 e2), (let x e1 e2),
+//   (call f args...), (list items...) and (fun (params...) body),
+// - ?x matches anything, ?x:int an integer and ?x:sym a symbol. A variable
+//   used twice matches equal expressions. In binder positions, e.g. the
+//   parameters of fun, it matches the name.
+// - ..?xs at the end of call, list and the parameters of fun matches the
+//   rest of the items.
+// - Integers, true, false, and @add, @car, @cdr, @cons, @div, @eq, @isnil,
+//   @lt, @mul, @neg for the builtin functions.
+// - @self is the definition being rewritten.
+// - Other words are symbols. They match a definition by its original name
+//   (e.g. :1115) or by its name in the symbol map (e.g. cons).
+// The right hand side may use (negate ?n) to negate an integer.
+//
+// Conditions after "where": (negative ?n) and (notfree ?x e), i.e. the name
+// ?x does not occur in e.
+//
+// Rules are tried in order at each node, bottom up, until none applies.
+// A rule whose right hand side matches its own left hand side is rejected,
+// and rewriting stops with an error on a cycle or after too many steps.
+//
+// "name" declarations recognize whole definitions; a definition without a
+// name in the symbol map gets the first name whose pattern matches it.
+
+use std::collections::{HashMap, HashSet};
+use super::expr::{Env, Expr};
+
+// Built-in rules, used by default.
+pub const SIMPLIFY: &str = include_str!("../rules/simplify.rules");
+pub const IDIOMS: &str = include_str!("../rules/idioms.rules");
+
+// Maximum number of rewrites for a definition.
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Kind {
+    Any,
+    Int,
+    Sym,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum Pattern {
+    Var(String, Kind),
+    Leaf(Expr),
+    SelfName,
+    // A node with its children and the variable matching the rest.
+    Node(String, Vec<Pattern>, Option<String>),
+    // The parameters of fun.
+    Params(Vec<Pattern>, Option<String>),
+}
+
+#[derive(Debug, Clone)]
+enum Cond {
+    Negative(String),
+    NotFree(String, Pattern),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub line: usize,
+    lhs: Pattern,
+    rhs: Option<Pattern>,
+    conds: Vec<Cond>,
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    One(Expr),
+    Many(Vec<Expr>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+// Heads with their number of children, and whether more may follow.
+const HEADS: &[(&str, usize, bool)] = &[
+    ("neg", 1, false), ("not", 1, false), ("isnil", 1, false),
+    ("add", 2, false), ("sub", 2, false), ("mul", 2, false), ("div", 2, false), ("mod", 2, false),
+    ("eq", 2, false), ("lt", 2, false), ("or", 2, false), ("and", 2, false),
+    ("if", 3, false), ("match", 5, false), ("letcons", 4, false), ("let", 3, false),
+    ("call", 1, true), ("list", 0, true), ("fun", 2, false),
+];
+
+const BUILTINS: &[(&str, Expr)] = &[
+    ("@add", Expr::ADD), ("@car", Expr::CAR), ("@cdr", Expr::CDR), ("@cons", Expr::CONS), ("@div", Expr::DIV),
+    ("@eq", Expr::EQ), ("@isnil", Expr::ISNIL), ("@lt", Expr::LT), ("@mul", Expr::MUL), ("@neg", Expr::NEG),
+];
+
+// A child of an expression as seen by patterns.
+enum Kid<'a> {
+    Expr(&'a Expr),
+    Name(&'a str),
+    Params(&'a [String]),
+}
+
+fn view(e: &Expr) -> Option<(&'static str, Vec<Kid<'_>>)> {
+    fn one<'a>(head: &'static str, a: &'a Expr) -> (&'static str, Vec<Kid<'a>>) {
+        (head, vec![Kid::Expr(a)])
+    }
+    fn two<'a>(head: &'static str, a: &'a Expr, b: &'a Expr) -> (&'static str, Vec<Kid<'a>>) {
+        (head, vec![Kid::Expr(a), Kid::Expr(b)])
+    }
+    Some(match e {
+        Expr::Neg(a) => one("neg", a),
+        Expr::Not(a) => one("not", a),
+        Expr::IsNil(a) => one("isnil", a),
+        Expr::Add(a, b) => two("add", a, b),
+        Expr::Sub(a, b) => two("sub", a, b),
+        Expr::Mul(a, b) => two("mul", a, b),
+        Expr::Div(a, b) => two("div", a, b),
+        Expr::Mod(a, b) => two("mod", a, b),
+        Expr::Equ(a, b) => two("eq", a, b),
+        Expr::Lt(a, b) => two("lt", a, b),
+        Expr::Or(a, b) => two("or", a, b),
+        Expr::And(a, b) => two("and", a, b),
+        Expr::If(c, a, b) => ("if", vec![Kid::Expr(c), Kid::Expr(a), Kid::Expr(b)]),
+        Expr::MatchCons(v, e1, hd, tl, e2) =>
+            ("match", vec![Kid::Expr(v), Kid::Expr(e1), Kid::Name(hd), Kid::Name(tl), Kid::Expr(e2)]),
+        Expr::LetCons(hd, tl, e1, e2) => ("letcons", vec![Kid::Name(hd), Kid::Name(tl), Kid::Expr(e1), Kid::Expr(e2)]),
+        Expr::Let(var, e1, e2) => ("let", vec![Kid::Name(var), Kid::Expr(e1), Kid::Expr(e2)]),
+        Expr::Call(vals) => ("call", vals.iter().map(Kid::Expr).collect()),
+        Expr::List(items) => ("list", items.iter().map(Kid::Expr).collect()),
+        Expr::Func(params, body) => ("fun", vec![Kid::Params(params), Kid::Expr(body)]),
+        _ => return None,
+    })
+}
+
+// The inverse of view. Fails when a binder is not a symbol.
+enum Built {
+    Expr(Expr),
+    Params(Vec<String>),
+}
+
+fn build(head: &str, kids: Vec<Built>) -> Option<Expr> {
+    let mut iter = kids.into_iter();
+    if head == "fun" {
+        return match (iter.next(), iter.next()) {
+            (Some(Built::Params(params)), Some(Built::Expr(body))) => Some(Expr::Func(params, Box::new(body))),
+            _ => None,
+        };
+    }
+    let mut expr = || match iter.next() {
+        Some(Built::Expr(e)) => Some(Box::new(e)),
+        _ => None,
+    };
+    let name = |e: Box<Expr>| match *e {
+        Expr::Symbol(s) => Some(s),
+        _ => None,
+    };
+    Some(match head {
+        "neg" => Expr::Neg(expr()?),
+        "not" => Expr::Not(expr()?),
+        "isnil" => Expr::IsNil(expr()?),
+        "add" => Expr::Add(expr()?, expr()?),
+        "sub" => Expr::Sub(expr()?, expr()?),
+        "mul" => Expr::Mul(expr()?, expr()?),
+        "div" => Expr::Div(expr()?, expr()?),
+        "mod" => Expr::Mod(expr()?, expr()?),
+        "eq" => Expr::Equ(expr()?, expr()?),
+        "lt" => Expr::Lt(expr()?, expr()?),
+        "or" => Expr::Or(expr()?, expr()?),
+        "and" => Expr::And(expr()?, expr()?),
+        "if" => Expr::If(expr()?, expr()?, expr()?),
+        "match" => {
+            let (v, e1) = (expr()?, expr()?);
+            let (hd, tl) = (name(expr()?)?, name(expr()?)?);
+            Expr::MatchCons(v, e1, hd, tl, expr()?)
+        },
+        "letcons" => {
+            let (hd, tl) = (name(expr()?)?, name(expr()?)?);
+            Expr::LetCons(hd, tl, expr()?, expr()?)
+        },
+        "let" => Expr::Let(name(expr()?)?, expr()?, expr()?),
+        "call" | "list" => {
+            let mut items = Vec::new();
+            while let Some(e) = expr() {
+                items.push(*e);
+            }
+            if head == "call" { Expr::Call(items) } else { Expr::List(items) }
+        },
+        _ => return None,
+    })
+}
+
+// Rebuilds `e` with `f` applied to its children.
+pub(crate) fn map_children(e: &Expr, f: &mut dyn FnMut(&Expr) -> Result<Expr, String>) -> Result<Expr, String> {
+    let (head, kids) = match view(e) {
+        Some(v) => v,
+        None => return Ok(e.clone()),
+    };
+    let mut built = Vec::new();
+    for kid in kids {
+        built.push(match kid {
+            Kid::Expr(e) => Built::Expr(f(e)?),
+            Kid::Name(n) => Built::Expr(Expr::Symbol(n.to_string())),
+            Kid::Params(ps) => Built::Params(ps.to_vec()),
+        });
+    }
+    Ok(build(head, built).unwrap())
+}
+
+pub(crate) fn mentions(name: &str, e: &Expr) -> bool {
+    if let Expr::Symbol(s) = e {
+        return s == name;
+    }
+    match view(e) {
+        Some((_, kids)) => kids.iter().any(|kid| match kid {
+            Kid::Expr(e) => mentions(name, e),
+            Kid::Name(n) => *n == name,
+            Kid::Params(ps) => ps.iter().any(|p| p == name),
+        }),
+        None => false,
+    }
+}
+
+// The subexpressions of `e`, excluding binders.
+pub(crate) fn children(e: &Expr) -> Vec<&Expr> {
+    match view(e) {
+        Some((_, kids)) => kids.into_iter().filter_map(|kid| match kid {
+            Kid::Expr(e) => Some(e),
+            _ => None,
+        }).collect(),
+        None => Vec::new(),
+    }
+}
+
+struct Matcher<'a> {
+    self_name: &'a str,
+    env: &'a Env,
+}
+
+impl<'a> Matcher<'a> {
+    fn bind(&self, var: &str, value: Binding, b: &mut Bindings) -> bool {
+        match (b.get(var), &value) {
+            (None, _) => {
+                b.insert(var.to_string(), value);
+                true
+            },
+            (Some(Binding::One(old)), Binding::One(new)) => old == new,
+            (Some(Binding::Many(old)), Binding::Many(new)) => old == new,
+            _ => false,
+        }
+    }
+
+    fn matches(&self, p: &Pattern, e: &Expr, b: &mut Bindings) -> bool {
+        match p {
+            Pattern::Var(var, kind) => {
+                let ok = match kind {
+                    Kind::Any => true,
+                    Kind::Int => matches!(e, Expr::Int(_)),
+                    Kind::Sym => matches!(e, Expr::Symbol(_)),
+                };
+                ok && self.bind(var, Binding::One(e.clone()), b)
+            },
+            Pattern::Leaf(Expr::Symbol(s)) => match e {
+                Expr::Symbol(t) => s == t || self.env.get_name(t) == Some(s),
+                _ => false,
+            },
+            Pattern::Leaf(leaf) => leaf == e,
+            Pattern::SelfName => *e == Expr::Symbol(self.self_name.to_string()),
+            Pattern::Node(head, pats, rest) => match view(e) {
+                Some((h, kids)) if h == head => self.matches_kids(pats, rest, &kids, b),
+                _ => false,
+            },
+            Pattern::Params(_, _) => false,
+        }
+    }
+
+    fn matches_kid(&self, p: &Pattern, kid: &Kid, b: &mut Bindings) -> bool {
+        match (kid, p) {
+            (Kid::Expr(e), _) => self.matches(p, e, b),
+            (Kid::Name(n), _) => self.matches(p, &Expr::Symbol(n.to_string()), b),
+            (Kid::Params(ps), Pattern::Params(pats, rest)) => {
+                let names = ps.iter().map(|p| Expr::Symbol(p.clone())).collect::<Vec<_>>();
+                self.matches_kids(pats, rest, &names.iter().map(Kid::Expr).collect::<Vec<_>>(), b)
+            },
+            _ => false,
+        }
+    }
+
+    fn matches_kids(&self, pats: &[Pattern], rest: &Option<String>, kids: &[Kid], b: &mut Bindings) -> bool {
+        if kids.len() < pats.len() || (rest.is_none() && kids.len() != pats.len()) {
+            return false;
+        }
+        if !pats.iter().zip(kids.iter()).all(|(p, kid)| self.matches_kid(p, kid, b)) {
+            return false;
+        }
+        match rest {
+            Some(var) => {
+                let items = kids[pats.len()..].iter().map(|kid| match kid {
+                    Kid::Expr(e) => (*e).clone(),
+                    Kid::Name(n) => Expr::Symbol(n.to_string()),
+                    Kid::Params(_) => unreachable!(),
+                }).collect();
+                self.bind(var, Binding::Many(items), b)
+            },
+            None => true,
+        }
+    }
+
+    fn check(&self, cond: &Cond, b: &Bindings) -> bool {
+        match cond {
+            Cond::Negative(var) => matches!(b.get(var), Some(Binding::One(Expr::Int(i))) if *i < 0),
+            Cond::NotFree(var, p) => match (b.get(var), self.instantiate(p, b)) {
+                (Some(Binding::One(Expr::Symbol(name))), Some(e)) => !mentions(name, &e),
+                _ => false,
+            },
+        }
+    }
+
+    fn instantiate(&self, p: &Pattern, b: &Bindings) -> Option<Expr> {
+        match p {
+            Pattern::Var(var, _) => match b.get(var) {
+                Some(Binding::One(e)) => Some(e.clone()),
+                _ => None,
+            },
+            Pattern::Leaf(e) => Some(e.clone()),
+            Pattern::SelfName => Some(Expr::Symbol(self.self_name.to_string())),
+            Pattern::Node(head, pats, _) if head == "negate" => match self.instantiate(&pats[0], b)? {
+                Expr::Int(i) => Some(Expr::Int(i.wrapping_neg())),
+                _ => None,
+            },
+            Pattern::Node(head, pats, rest) => {
+                let mut kids = Vec::new();
+                for p in pats {
+                    kids.push(match p {
+                        Pattern::Params(ps, rest) => Built::Params(self.instantiate_seq(ps, rest, b)?.into_iter()
+                            .map(|e| match e {
+                                Expr::Symbol(s) => Some(s),
+                                _ => None,
+                            })
+                            .collect::<Option<Vec<_>>>()?),
+                        p => Built::Expr(self.instantiate(p, b)?),
+                    });
+                }
+                if let Some(var) = rest {
+                    match b.get(var) {
+                        Some(Binding::Many(items)) => kids.extend(items.iter().cloned().map(Built::Expr)),
+                        _ => return None,
+                    }
+                }
+                build(head, kids)
+            },
+            Pattern::Params(_, _) => None,
+        }
+    }
+
+    fn instantiate_seq(&self, pats: &[Pattern], rest: &Option<String>, b: &Bindings) -> Option<Vec<Expr>> {
+        let mut items = pats.iter().map(|p| self.instantiate(p, b)).collect::<Option<Vec<_>>>()?;
+        if let Some(var) = rest {
+            match b.get(var) {
+                Some(Binding::Many(rest)) => items.extend(rest.iter().cloned()),
+                _ => return None,
+            }
+        }
+        Some(items)
+    }
+
+    fn apply(&self, rule: &Rule, e: &Expr) -> Option<Expr> {
+        let mut b = Bindings::new();
+        if !self.matches(&rule.lhs, e, &mut b) || !rule.conds.iter().all(|c| self.check(c, &b)) {
+            return None;
+        }
+        match &rule.rhs {
+            Some(rhs) => self.instantiate(rhs, &b),
+            None => Some(e.clone()),
+        }
+    }
+}
+
+struct Rewriter<'a> {
+    rules: &'a [Rule],
+    matcher: Matcher<'a>,
+    steps: usize,
+}
+
+impl<'a> Rewriter<'a> {
+    fn normalize(&mut self, e: &Expr) -> Result<Expr, String> {
+        let mut e = map_children(e, &mut |c| self.normalize(c))?;
+        let mut seen = Vec::new();
+        loop {
+            let (rule, next) = match self.rules.iter().find_map(|r| self.matcher.apply(r, &e).map(|n| (r, n))) {
+                Some(r) => r,
+                None => return Ok(e),
+            };
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(format!("rewriting did not terminate after {} steps; last rule: {} (line {})",
+                                   MAX_STEPS, rule.name, rule.line));
+            }
+            if next == e || seen.contains(&next) {
+                return Err(format!("rewriting loops at rule {} (line {}): {}", rule.name, rule.line, next));
+            }
+            seen.push(e);
+            e = map_children(&next, &mut |c| self.normalize(c))?;
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+    pub names: Vec<Rule>,
+}
+
+impl Rules {
+    // Parses a rule file. Errors have the line number.
+    pub fn parse(content: &str) -> Result<Rules, String> {
+        let mut parser = Parser{tokens: tokenize(content), pos: 0};
+        let mut rules = Rules::default();
+        while let Some((keyword, line)) = parser.next() {
+            let result = match keyword.as_str() {
+                "rule" | "name" => parser.statement(keyword == "rule"),
+                _ => Err(format!("expected `rule` or `name`, found `{}`", keyword)),
+            };
+            let rule = result.map_err(|e| format!("line {}: {}", line, e))?;
+            let rule = Rule{line, ..rule};
+            validate(&rule).map_err(|e| format!("line {}: {}", line, e))?;
+            if rule.rhs.is_some() {
+                rules.rules.push(rule);
+            } else {
+                rules.names.push(rule);
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn extend(&mut self, other: Rules) {
+        self.rules.extend(other.rules);
+        self.names.extend(other.names);
+    }
+
+    // Rewrites a definition named `self_name` until no rule applies.
+    pub fn rewrite(&self, e: &Expr, self_name: &str, env: &Env) -> Result<Expr, String> {
+        let mut rewriter = Rewriter{rules: &self.rules, matcher: Matcher{self_name, env}, steps: 0};
+        rewriter.normalize(e)
+    }
+
+    // Returns the name of the first recognizer matching the definition.
+    pub fn recognize(&self, e: &Expr, self_name: &str, env: &Env) -> Option<&Rule> {
+        let matcher = Matcher{self_name, env};
+        self.names.iter().find(|r| matcher.apply(r, e).is_some())
+    }
+
+    // Symbols the rewrite rules refer to, i.e. names whose mapping affects
+    // rewriting.
+    pub fn symbols(&self) -> HashSet<String> {
+        fn walk(p: &Pattern, out: &mut HashSet<String>) {
+            match p {
+                Pattern::Leaf(Expr::Symbol(s)) => {
+                    out.insert(s.clone());
+                },
+                Pattern::Node(_, pats, _) | Pattern::Params(pats, _) => pats.iter().for_each(|p| walk(p, out)),
+                _ => (),
+            }
+        }
+        let mut out = HashSet::new();
+        for rule in self.rules.iter() {
+            walk(&rule.lhs, &mut out);
+            rule.rhs.iter().for_each(|p| walk(p, &mut out));
+            for cond in rule.conds.iter() {
+                if let Cond::NotFree(_, p) = cond {
+                    walk(p, &mut out);
+                }
+            }
+        }
+        out
+    }
+}
+
+// Writes an expression in the pattern syntax, without variables. Fails on
+// the primitives of combinators.rs, which patterns cannot express.
+pub fn to_pattern(e: &Expr) -> Option<String> {
+    if let Some((name, _)) = BUILTINS.iter().find(|(_, b)| b == e) {
+        return Some(name.to_string());
+    }
+    match e {
+        Expr::Int(i) => return Some(i.to_string()),
+        Expr::True => return Some("true".to_string()),
+        Expr::False => return Some("false".to_string()),
+        Expr::Symbol(s) => return Some(s.clone()),
+        _ => (),
+    }
+    let (head, kids) = view(e)?;
+    let mut out = format!("({}", head);
+    for kid in kids {
+        out.push(' ');
+        match kid {
+            Kid::Expr(e) => out.push_str(&to_pattern(e)?),
+            Kid::Name(n) => out.push_str(n),
+            Kid::Params(ps) => out.push_str(&format!("({})", ps.join(" "))),
+        }
+    }
+    out.push(')');
+    Some(out)
+}
+
+// The inverse of to_pattern.
+pub fn from_pattern(s: &str) -> Result<Expr, String> {
+    let mut parser = Parser{tokens: tokenize(s), pos: 0};
+    let p = parser.pattern()?;
+    if let Some(t) = parser.peek() {
+        return Err(format!("unexpected `{}`", t));
+    }
+    let env = Env::new();
+    Matcher{self_name: "", env: &env}.instantiate(&p, &Bindings::new())
+        .ok_or_else(|| "not an expression".to_string())
+}
+
+fn validate(rule: &Rule) -> Result<(), String> {
+    fn vars(p: &Pattern, out: &mut Vec<String>) {
+        match p {
+            Pattern::Var(v, _) => out.push(v.clone()),
+            Pattern::Node(_, pats, rest) | Pattern::Params(pats, rest) => {
+                pats.iter().for_each(|p| vars(p, out));
+                out.extend(rest.iter().cloned());
+            },
+            _ => (),
+        }
+    }
+    fn check_negate(p: &Pattern) -> Result<(), String> {
+        match p {
+            Pattern::Node(head, _, _) if head == "negate" => Err("`negate` is only allowed on the right hand side".to_string()),
+            Pattern::Node(_, pats, _) | Pattern::Params(pats, _) => pats.iter().try_for_each(check_negate),
+            _ => Ok(()),
+        }
+    }
+    check_negate(&rule.lhs)?;
+    let mut bound = Vec::new();
+    vars(&rule.lhs, &mut bound);
+    let mut used = Vec::new();
+    if let Some(rhs) = &rule.rhs {
+        vars(rhs, &mut used);
+    }
+    for c in rule.conds.iter() {
+        match c {
+            Cond::Negative(v) => used.push(v.clone()),
+            Cond::NotFree(v, p) => {
+                used.push(v.clone());
+                vars(p, &mut used);
+            },
+        }
+    }
+    if let Some(v) = used.iter().find(|v| !bound.contains(v)) {
+        return Err(format!("?{} is not bound by the pattern", v));
+    }
+
+    // Instantiate the right hand side with placeholders for the variables.
+    // If the pattern matches it, the rule would apply again to its result.
+    if let Some(rhs) = &rule.rhs {
+        let env = Env::new();
+        let matcher = Matcher{self_name: "@self", env: &env};
+        let b = bound.iter().map(|v| (v.clone(), Binding::One(Expr::Symbol(format!("?{}", v))))).collect();
+        if let Some(result) = matcher.instantiate(rhs, &b) {
+            if matcher.matches(&rule.lhs, &result, &mut Bindings::new()) {
+                return Err(format!("rule {} applies to its own result", rule.name));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn tokenize(content: &str) -> Vec<(String, usize)> {
+    let mut tokens = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        for word in line.replace('(', " ( ").replace(')', " ) ").split_whitespace() {
+            tokens.push((word.to_string(), i + 1));
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<(String, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<(String, usize)> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|(t, _)| t.as_str())
+    }
+
+    fn expect(&mut self, want: &str) -> Result<(), String> {
+        match self.next() {
+            Some((t, _)) if t == want => Ok(()),
+            Some((t, _)) => Err(format!("expected `{}`, found `{}`", want, t)),
+            None => Err(format!("expected `{}`, found the end of file", want)),
+        }
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some((t, _)) if t != "(" && t != ")" => Ok(t),
+            Some((t, _)) => Err(format!("unexpected `{}`", t)),
+            None => Err("unexpected end of file".to_string()),
+        }
+    }
+
+    // After "rule" or "name".
+    fn statement(&mut self, is_rule: bool) -> Result<Rule, String> {
+        let mut name = self.word()?;
+        if name.len() > 1 && name.ends_with(':') {
+            name.pop();
+        } else {
+            self.expect(":")?;
+        }
+        let lhs = self.pattern()?;
+        let rhs = if is_rule {
+            self.expect("=>")?;
+            Some(self.pattern()?)
+        } else {
+            None
+        };
+        let mut conds = Vec::new();
+        if self.peek() == Some("where") {
+            self.pos += 1;
+            while self.peek() == Some("(") {
+                self.pos += 1;
+                let cond = match self.word()?.as_str() {
+                    "negative" => Cond::Negative(self.var()?),
+                    "notfree" => Cond::NotFree(self.var()?, self.pattern()?),
+                    c => return Err(format!("unknown condition `{}`", c)),
+                };
+                self.expect(")")?;
+                conds.push(cond);
+            }
+            if conds.is_empty() {
+                return Err("expected a condition after `where`".to_string());
+            }
+        }
+        Ok(Rule{name, line: 0, lhs, rhs, conds})
+    }
+
+    fn var(&mut self) -> Result<String, String> {
+        let w = self.word()?;
+        match w.strip_prefix('?') {
+            Some(v) if !v.is_empty() => Ok(v.to_string()),
+            _ => Err(format!("expected a variable, found `{}`", w)),
+        }
+    }
+
+    // Patterns until ")", with an optional rest variable.
+    fn items(&mut self) -> Result<(Vec<Pattern>, Option<String>), String> {
+        let mut pats = Vec::new();
+        loop {
+            match self.peek() {
+                Some(")") => {
+                    self.pos += 1;
+                    return Ok((pats, None));
+                },
+                Some(w) if w.starts_with("..?") => {
+                    let rest = w[3..].to_string();
+                    self.pos += 1;
+                    self.expect(")")?;
+                    return Ok((pats, Some(rest)));
+                },
+                _ => pats.push(self.pattern()?),
+            }
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, String> {
+        let w = match self.next() {
+            Some((w, _)) => w,
+            None => return Err("unexpected end of file".to_string()),
+        };
+        if w == "(" {
+            let head = self.word()?;
+            if head == "fun" {
+                self.expect("(")?;
+                let (params, rest) = self.items()?;
+                if let Some(p) = params.iter().find(|p| !matches!(p, Pattern::Var(_, _) | Pattern::Leaf(Expr::Symbol(_)))) {
+                    return Err(format!("bad parameter {:?}", p));
+                }
+                let body = self.pattern()?;
+                self.expect(")")?;
+                return Ok(Pattern::Node(head, vec![Pattern::Params(params, rest), body], None));
+            }
+            let (pats, rest) = self.items()?;
+            if head == "negate" {
+                return if pats.len() == 1 && rest.is_none() {
+                    Ok(Pattern::Node(head, pats, None))
+                } else {
+                    Err("`negate` takes one argument".to_string())
+                };
+            }
+            return match HEADS.iter().find(|(h, _, _)| *h == head) {
+                None => Err(format!("unknown form `{}`", head)),
+                Some((_, n, variadic)) if pats.len() < *n || (!variadic && (pats.len() != *n || rest.is_some())) =>
+                    Err(format!("`{}` takes {} argument(s)", head, n)),
+                Some(_) => Ok(Pattern::Node(head, pats, rest)),
+            };
+        }
+        if w == ")" || w == "=>" {
+            return Err(format!("unexpected `{}`", w));
+        }
+        if let Some(var) = w.strip_prefix('?') {
+            let (name, kind) = match var.split_once(':') {
+                None => (var, Kind::Any),
+                Some((name, "int")) => (name, Kind::Int),
+                Some((name, "sym")) => (name, Kind::Sym),
+                Some((_, kind)) => return Err(format!("unknown kind `{}`", kind)),
+            };
+            if name.is_empty() {
+                return Err(format!("bad variable `{}`", w));
+            }
+            return Ok(Pattern::Var(name.to_string(), kind));
+        }
+        if w == "@self" {
+            return Ok(Pattern::SelfName);
+        }
+        if let Some((_, e)) = BUILTINS.iter().find(|(name, _)| *name == w) {
+            return Ok(Pattern::Leaf(e.clone()));
+        }
+        Ok(Pattern::Leaf(match w.as_str() {
+            "true" => Expr::True,
+            "false" => Expr::False,
+            _ => match w.parse::<isize>() {
+                Ok(i) => Expr::Int(i),
+                Err(_) => Expr::Symbol(w),
+            },
+        }))
+    }
+}
+
+#[test]
+fn test_rewrite() {
+    let rules = Rules::parse("
+        # comment
+        rule neg_int: (neg ?n:int) => (negate ?n)
+        rule sub: (add ?x (neg ?y)) => (sub ?x ?y)
+        rule not: (fun (?p ?q) (if ?c ?q ?p)) => (not ?c) where (notfree ?p ?c) (notfree ?q ?c)
+        rule swap_call: (call cons ?a ..?rest) => (call @cons ?a ..?rest)
+        name List.len: (fun (?l) (match ?l 0 ?h ?t (add 1 (call @self ?t))))
+    ").unwrap();
+    let mut env = Env::new();
+    env.insert(":1115", "cons", None);
+    let sym = |s: &str| Box::new(Expr::Symbol(s.to_string()));
+
+    let e = Expr::Add(sym("x"), Box::new(Expr::Neg(Box::new(Expr::Neg(sym("y"))))));
+    assert_eq!(rules.rewrite(&e, ":1", &env).unwrap().to_string(), "x - -y");
+    let e = Expr::Add(sym("x"), Box::new(Expr::Neg(Box::new(Expr::Int(3)))));
+    assert_eq!(rules.rewrite(&e, ":1", &env).unwrap().to_string(), "x + -3");
+
+    let not = |c| Expr::Func(vec!["p".to_string(), "q".to_string()], Box::new(Expr::If(c, sym("q"), sym("p"))));
+    assert_eq!(rules.rewrite(&not(sym("c")), ":1", &env).unwrap().to_string(), "!c");
+    assert_eq!(rules.rewrite(&not(sym("p")), ":1", &env).unwrap().to_string(), "fun(p, q) -> if p { q } else { p }");
+
+    let e = Expr::Call(vec![*sym(":1115"), Expr::Int(1), Expr::List(vec![])]);
+    assert_eq!(rules.rewrite(&e, ":1", &env).unwrap(), Expr::Call(vec![Expr::CONS, Expr::Int(1), Expr::List(vec![])]));
+
+    let len = Expr::Func(vec!["l".to_string()], Box::new(Expr::MatchCons(
+        sym("l"), Box::new(Expr::Int(0)), "h".to_string(), "t".to_string(),
+        Box::new(Expr::Add(Box::new(Expr::Int(1)), Box::new(Expr::Call(vec![*sym(":9"), *sym("t")])))))));
+    assert_eq!(rules.recognize(&len, ":9", &env).map(|r| r.name.as_str()), Some("List.len"));
+    assert!(rules.recognize(&len, ":10", &env).is_none());
+
+    assert_eq!(Rules::parse("rule a: (add ?x ?y) => (add ?y ?x)").unwrap_err(),
+               "line 1: rule a applies to its own result");
+    assert_eq!(Rules::parse("rule a: ?x => (neg ?y)").unwrap_err(), "line 1: ?y is not bound by the pattern");
+    assert_eq!(Rules::parse("\nrule a: (add ?x) => ?x").unwrap_err(), "line 2: `add` takes 2 argument(s)");
+
+    // Rules that undo each other.
+    let rules = Rules::parse("rule a: (neg ?x) => (not ?x)\nrule b: (not ?x) => (neg ?x)").unwrap();
+    let err = rules.rewrite(&Expr::Neg(sym("x")), ":1", &env).unwrap_err();
+    assert!(err.starts_with("rewriting loops at rule b (line 2)"), "{}", err);
+}
@@ -0,0 +1,571 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Symbol mapping files, which give names (and optionally types) to the
+// numbered definitions of galaxy.txt.
+//
+// Three formats are supported, chosen by the file extension:
+//
+// Plain text (default). One symbol per line, "#" starts a comment:
+//   :1029 Bitmap.Galaxy
+//   :1124 List.mem fun(x, xs) -> bool
+//
+// TOML (.toml):
+//   ":1029" = "Bitmap.Galaxy"
+//   ":1124" = { name = "List.mem", type = "fun(x, xs) -> bool" }
+//
+//   [":1204"]
+//   name = "Image.contained_rect"
+//   type = "fun(p, rect) -> bool"
+//
+// JSON (.json):
+//   {
+//     ":1029": "Bitmap.Galaxy",
+//     ":1124": {"name": "List.mem", "type": "fun(x, xs) -> bool"}
+//   }
+//
+// Types are written as "int", "bool", "list" or "fun(p1, p2: type, ...)",
+// optionally followed by "-> type". "_" stands for an unknown name or type.
+
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use super::expr::Type;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Format {
+    Text,
+    Toml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    pub line: usize,
+    pub orig_name: String,
+    pub name: String,
+    pub t: Option<Type>,
+}
+
+pub fn parse(content: &str, format: Format) -> Result<Vec<Entry>, String> {
+    match format {
+        Format::Text => parse_text(content),
+        Format::Toml => parse_toml(content),
+        Format::Json => parse_json(content),
+    }
+}
+
+pub fn write(w: &mut dyn Write, entries: &[Entry], format: Format) -> io::Result<()> {
+    match format {
+        Format::Text => {
+            for e in entries {
+                match &e.t {
+                    Some(t) => writeln!(w, "{} {} {}", e.orig_name, e.name, t)?,
+                    None => writeln!(w, "{} {}", e.orig_name, e.name)?,
+                }
+            }
+        },
+        Format::Toml => {
+            for e in entries {
+                match &e.t {
+                    Some(t) => writeln!(w, "{} = {{ name = {}, type = {} }}",
+                                        quote(&e.orig_name), quote(&e.name), quote(&t.to_string()))?,
+                    None => writeln!(w, "{} = {}", quote(&e.orig_name), quote(&e.name))?,
+                }
+            }
+        },
+        Format::Json => {
+            writeln!(w, "{{")?;
+            for (i, e) in entries.iter().enumerate() {
+                let sep = if i + 1 < entries.len() { "," } else { "" };
+                match &e.t {
+                    Some(t) => writeln!(w, "  {}: {{\"name\": {}, \"type\": {}}}{}",
+                                        quote(&e.orig_name), quote(&e.name), quote(&t.to_string()), sep)?,
+                    None => writeln!(w, "  {}: {}{}", quote(&e.orig_name), quote(&e.name), sep)?,
+                }
+            }
+            writeln!(w, "}}")?;
+        },
+    }
+    Ok(())
+}
+
+// TOML and JSON share the same basic string escapes.
+pub(crate) fn quote(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+fn parse_text(content: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let mut iter = line.split_whitespace();
+        let orig_name = match iter.next() {
+            Some(s) => s,
+            None => continue,
+        };
+        let name = match iter.next() {
+            Some(s) => s,
+            None => return Err(format!("line {}: missing name for {}", i + 1, orig_name)),
+        };
+        // The type is the rest of the line, which may have spaces.
+        let rest = iter.collect::<Vec<_>>().join(" ");
+        let t = if rest.is_empty() {
+            None
+        } else {
+            Some(parse_type(&rest).map_err(|e| format!("line {}: {}", i + 1, e))?)
+        };
+        entries.push(Entry{line: i + 1, orig_name: orig_name.to_string(), name: name.to_string(), t});
+    }
+    Ok(entries)
+}
+
+// Reads the fields of a {name, type} table into an entry.
+fn set_field(entry: &mut Entry, key: &str, value: String) -> Result<(), String> {
+    match key {
+        "name" => entry.name = value,
+        "type" => entry.t = Some(parse_type(&value)?),
+        _ => return Err(format!("unknown field: {}", key)),
+    }
+    Ok(())
+}
+
+fn check_entry(entry: &Entry) -> Result<(), String> {
+    if entry.name.is_empty() {
+        Err(format!("line {}: missing name for {}", entry.line, entry.orig_name))
+    } else {
+        Ok(())
+    }
+}
+
+// A cursor over a string, shared by the TOML and JSON readers.
+struct Scanner<'a> {
+    s: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(s: &'a str) -> Self {
+        Self{s, pos: 0, line: 1}
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    // Skips spaces (and newlines if `newlines` is set) and "#" comments.
+    fn skip(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while let Some(c) = self.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    self.bump();
+                }
+            } else if c == ' ' || c == '\t' || c == '\r' || (newlines && c == '\n') {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line, msg))
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.bump() == Some(c) {
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", c))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut ret = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.bump() {
+                    Some('"') => ret.push('"'),
+                    Some('\\') => ret.push('\\'),
+                    Some('/') => ret.push('/'),
+                    Some('n') => ret.push('\n'),
+                    Some('t') => ret.push('\t'),
+                    _ => return self.error("unsupported escape sequence"),
+                },
+                Some('\n') | None => return self.error("unterminated string"),
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    // TOML keys may be bare or quoted.
+    fn key(&mut self) -> Result<String, String> {
+        if self.peek() == Some('"') {
+            return self.string();
+        }
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                break;
+            }
+            self.bump();
+        }
+        if start == self.pos {
+            return self.error("expected a key");
+        }
+        Ok(self.s[start..self.pos].to_string())
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip(false);
+        match self.bump() {
+            Some('\n') | None => Ok(()),
+            _ => self.error("expected end of line"),
+        }
+    }
+}
+
+fn parse_toml(content: &str) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut in_table = false;
+    let mut sc = Scanner::new(content);
+    loop {
+        sc.skip(true);
+        let line = sc.line;
+        match sc.peek() {
+            None => break,
+            Some('[') => {
+                if in_table {
+                    check_entry(entries.last().unwrap())?;
+                }
+                sc.bump();
+                sc.skip(false);
+                let orig_name = sc.key()?;
+                sc.skip(false);
+                sc.expect(']')?;
+                entries.push(Entry{line, orig_name, name: String::new(), t: None});
+                in_table = true;
+            },
+            Some(_) => {
+                let key = sc.key()?;
+                sc.skip(false);
+                sc.expect('=')?;
+                sc.skip(false);
+                if in_table {
+                    let value = sc.string()?;
+                    set_field(entries.last_mut().unwrap(), &key, value)
+                        .map_err(|e| format!("line {}: {}", line, e))?;
+                } else {
+                    let mut entry = Entry{line, orig_name: key, name: String::new(), t: None};
+                    if sc.peek() == Some('{') {
+                        sc.bump();
+                        loop {
+                            sc.skip(false);
+                            if sc.peek() == Some('}') {
+                                sc.bump();
+                                break;
+                            }
+                            let field = sc.key()?;
+                            sc.skip(false);
+                            sc.expect('=')?;
+                            sc.skip(false);
+                            let value = sc.string()?;
+                            set_field(&mut entry, &field, value).map_err(|e| format!("line {}: {}", line, e))?;
+                            sc.skip(false);
+                            if sc.peek() == Some(',') {
+                                sc.bump();
+                            }
+                        }
+                    } else {
+                        entry.name = sc.string()?;
+                    }
+                    check_entry(&entry)?;
+                    entries.push(entry);
+                }
+            },
+        }
+        sc.end_of_line()?;
+    }
+    if in_table {
+        check_entry(entries.last().unwrap())?;
+    }
+    Ok(entries)
+}
+
+fn parse_json(content: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut sc = Scanner::new(content);
+    sc.skip(true);
+    sc.expect('{')?;
+    sc.skip(true);
+    if sc.peek() == Some('}') {
+        sc.bump();
+    } else {
+        loop {
+            sc.skip(true);
+            let line = sc.line;
+            let orig_name = sc.string()?;
+            sc.skip(true);
+            sc.expect(':')?;
+            sc.skip(true);
+            let mut entry = Entry{line, orig_name, name: String::new(), t: None};
+            if sc.peek() == Some('{') {
+                sc.bump();
+                sc.skip(true);
+                if sc.peek() == Some('}') {
+                    sc.bump();
+                } else {
+                    loop {
+                        sc.skip(true);
+                        let field = sc.string()?;
+                        sc.skip(true);
+                        sc.expect(':')?;
+                        sc.skip(true);
+                        if sc.s[sc.pos..].starts_with("null") {
+                            sc.pos += 4;
+                        } else {
+                            let value = sc.string()?;
+                            set_field(&mut entry, &field, value).map_err(|e| format!("line {}: {}", sc.line, e))?;
+                        }
+                        sc.skip(true);
+                        match sc.bump() {
+                            Some(',') => continue,
+                            Some('}') => break,
+                            _ => return sc.error("expected ',' or '}'"),
+                        }
+                    }
+                }
+            } else {
+                entry.name = sc.string()?;
+            }
+            check_entry(&entry)?;
+            entries.push(entry);
+            sc.skip(true);
+            match sc.bump() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return sc.error("expected ',' or '}'"),
+            }
+        }
+    }
+    sc.skip(true);
+    if sc.peek().is_some() {
+        return sc.error("trailing characters");
+    }
+    Ok(entries)
+}
+
+fn type_tokens(s: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' || c == ',' || c == ':' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c == '-' {
+            chars.next();
+            if chars.next() != Some('>') {
+                return Err(format!("invalid type: {}", s));
+            }
+            tokens.push("->".to_string());
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(ident);
+        } else {
+            return Err(format!("invalid type: {}", s));
+        }
+    }
+    Ok(tokens)
+}
+
+struct TypeParser<'a> {
+    src: &'a str,
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl<'a> TypeParser<'a> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|s| s.as_str())
+    }
+
+    fn next(&mut self) -> Result<&str, String> {
+        self.pos += 1;
+        match self.tokens.get(self.pos - 1) {
+            Some(s) => Ok(s),
+            None => Err(format!("invalid type: {}", self.src)),
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.next()? == token {
+            Ok(())
+        } else {
+            Err(format!("invalid type: {}", self.src))
+        }
+    }
+
+    // "_" or a type.
+    fn opt_type(&mut self) -> Result<Option<Type>, String> {
+        if self.peek() == Some("_") {
+            self.pos += 1;
+            Ok(None)
+        } else {
+            Ok(Some(self.parse_type()?))
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<Type, String> {
+        match self.next()? {
+            "int" => Ok(Type::Int),
+            "bool" => Ok(Type::Bool),
+            "list" => Ok(Type::List),
+            "fun" => {
+                self.expect("(")?;
+                let mut params = Vec::new();
+                if self.peek() == Some(")") {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        let name = match self.next()? {
+                            "_" => None,
+                            s => Some(s.to_string()),
+                        };
+                        let t = if self.peek() == Some(":") {
+                            self.pos += 1;
+                            self.opt_type()?
+                        } else {
+                            None
+                        };
+                        params.push((name, t));
+                        match self.next()? {
+                            "," => continue,
+                            ")" => break,
+                            _ => return Err(format!("invalid type: {}", self.src)),
+                        }
+                    }
+                }
+                let ret = if self.peek() == Some("->") {
+                    self.pos += 1;
+                    self.opt_type()?
+                } else {
+                    None
+                };
+                Ok(Type::Func(params, Box::new(ret)))
+            },
+            _ => Err(format!("invalid type: {}", self.src)),
+        }
+    }
+}
+
+pub fn parse_type(s: &str) -> Result<Type, String> {
+    let mut parser = TypeParser{src: s, tokens: type_tokens(s)?, pos: 0};
+    let t = parser.parse_type()?;
+    if parser.peek().is_some() {
+        return Err(format!("invalid type: {}", s));
+    }
+    Ok(t)
+}
+
+#[test]
+fn test_parse_formats() {
+    let text = "# Comment.\n:1029 Bitmap.Galaxy\n\n:1124 List.mem fun(x, _: list) -> bool  # Trailing.\n";
+    let toml = "\":1029\" = \"Bitmap.Galaxy\"  # Comment.\n\n[\":1124\"]\nname = \"List.mem\"\ntype = \"fun(x, _: list) -> bool\"\n";
+    let json = "{\n  \":1029\": \"Bitmap.Galaxy\",\n  \":1124\": {\"name\": \"List.mem\", \"type\": \"fun(x, _: list) -> bool\"}\n}\n";
+
+    let mem_type = Type::Func(vec!((Some("x".to_string()), None), (None, Some(Type::List))),
+                              Box::new(Some(Type::Bool)));
+    for (content, format) in &[(text, Format::Text), (toml, Format::Toml), (json, Format::Json)] {
+        let entries = parse(content, *format).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].orig_name.as_str(), entries[0].name.as_str(), &entries[0].t),
+                   (":1029", "Bitmap.Galaxy", &None));
+        assert_eq!((entries[1].orig_name.as_str(), entries[1].name.as_str(), &entries[1].t),
+                   (":1124", "List.mem", &Some(mem_type.clone())));
+
+        // Exported files read back to the same mapping.
+        let mut out = Vec::new();
+        write(&mut out, &entries, *format).unwrap();
+        let reread = parse(&String::from_utf8(out).unwrap(), *format).unwrap();
+        assert_eq!(reread.iter().map(|e| (&e.orig_name, &e.name, &e.t)).collect::<Vec<_>>(),
+                   entries.iter().map(|e| (&e.orig_name, &e.name, &e.t)).collect::<Vec<_>>());
+    }
+
+    // Columns aligned with several spaces.
+    let aligned = parse(":1029   Bitmap.Galaxy\n:1124   List.mem    fun(x,  _: list) -> bool\n", Format::Text).unwrap();
+    assert_eq!((aligned[0].name.as_str(), &aligned[0].t), ("Bitmap.Galaxy", &None));
+    assert_eq!((aligned[1].name.as_str(), &aligned[1].t), ("List.mem", &Some(mem_type)));
+
+    assert!(parse(":1029\n", Format::Text).is_err());
+    assert!(parse(":1029 Foo fun(\n", Format::Text).is_err());
+    assert!(parse("{\":1029\": \"Foo\",}", Format::Json).is_err());
+
+    // Names resolve to the first definition with them, and replaced names
+    // to nothing.
+    let mut env = super::expr::Env::new();
+    env.load_str(":1124 Foo\n:1029 Foo\n:3 Bar\n", Format::Text, "a").unwrap();
+    env.load_str(":3 Baz\n", Format::Text, "b").unwrap();
+    assert_eq!(env.get_orig_name("Foo"), Some(":1029".to_string()));
+    assert_eq!(env.get_orig_name("Bar"), None);
+    assert_eq!(env.get_orig_name("Baz"), Some(":3".to_string()));
+}
@@ -0,0 +1,462 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Differential tester for galaxy evaluators.
+//
+// Every evaluator runs the same click sequence from the same initial state,
+// and the resulting states, images and sent data are compared bit-exactly
+// against each other and against the expectations recorded in the trace.
+//
+// psh/decompiler's lambda::eval only simplifies single definitions
+// symbolically and cannot run the interact protocol, so it is not one of the
+// evaluators here.
+//
+// Trace format (a superset of the REPL click script):
+//
+//   state <s-expression>     initial state (before the first click)
+//   click <x> <y>
+//   send <s-expression>      data sent to the server (optional)
+//   recv <s-expression>      the server's response
+//   state <s-expression>     expected state after the click (optional)
+//   images <s-expression>    expected images after the click (optional)
+//
+// Evaluators never talk to the server on their own: responses come from the
+// trace, or from the real server with --send.
+
+use super::{
+    as_num, dsl, eval, expr_to_list, expr_to_value, parse_sexp_str, repl::diff_value,
+    repl::parse_click, send_request_value, value_to_expr, vm, Dictionary, Result, Value,
+};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    rc::Rc,
+};
+
+pub trait Evaluator {
+    fn name(&self) -> &str;
+
+    // Evaluates `galaxy state event` once and returns (flag, new state, data).
+    fn galaxy(&mut self, state: &Value, event: &Value) -> Result<(i64, Value, Value)>;
+}
+
+// The graph reduction evaluator in main.rs.
+pub struct GraphEvaluator {
+    dict: Dictionary,
+}
+
+impl GraphEvaluator {
+    pub fn new(dict: Dictionary) -> GraphEvaluator {
+        GraphEvaluator { dict }
+    }
+}
+
+impl Evaluator for GraphEvaluator {
+    fn name(&self) -> &str {
+        "graph"
+    }
+
+    fn galaxy(&mut self, state: &Value, event: &Value) -> Result<(i64, Value, Value)> {
+        use dsl::*;
+
+        let dict = &self.dict;
+        catch_unwind(AssertUnwindSafe(|| {
+            let expr = app(
+                app(atom("galaxy"), value_to_expr(state)),
+                value_to_expr(event),
+            );
+            let res = expr_to_list(eval(expr, dict), dict);
+            if res.len() != 3 {
+                return None;
+            }
+            Some((
+                as_num(Rc::clone(&res[0]), dict)?,
+                expr_to_value(Rc::clone(&res[1]), dict),
+                expr_to_value(Rc::clone(&res[2]), dict),
+            ))
+        }))
+        .map_err(|_| "evaluator panicked")?
+        .ok_or_else(|| "galaxy did not return [flag, state, data]".into())
+    }
+}
+
+// The compiled evaluator in vm.rs.
+pub struct VmEvaluator {
+    program: vm::Program,
+}
+
+impl VmEvaluator {
+    pub fn new(dict: &Dictionary) -> Result<VmEvaluator> {
+        Ok(VmEvaluator {
+            program: vm::compile(dict)?,
+        })
+    }
+}
+
+impl Evaluator for VmEvaluator {
+    fn name(&self) -> &str {
+        "vm"
+    }
+
+    fn galaxy(&mut self, state: &Value, event: &Value) -> Result<(i64, Value, Value)> {
+        vm::Machine::new(&self.program).galaxy(state, event)
+    }
+}
+
+pub fn evaluators(dict: &Dictionary) -> Result<Vec<Box<dyn Evaluator>>> {
+    Ok(vec![
+        Box::new(GraphEvaluator::new(dict.clone())),
+        Box::new(VmEvaluator::new(dict)?),
+    ])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub send: Option<Value>,
+    pub recv: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub click: (i64, i64),
+    pub exchanges: Vec<Exchange>,
+    pub state: Option<Value>,
+    pub images: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub initial: Value,
+    pub steps: Vec<Step>,
+}
+
+pub fn parse_trace(s: &str) -> Result<Trace> {
+    let mut trace = Trace {
+        initial: Value::nil(),
+        steps: vec![],
+    };
+    // Whether the last entry was a `send` waiting for its `recv`.
+    let mut awaiting_recv = false;
+
+    for (lineno, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let err = || format!("line {}: invalid trace entry: {}", lineno + 1, line);
+        let mut words = line.splitn(2, char::is_whitespace);
+        let cmd = words.next().unwrap_or("");
+        let args = words.next().unwrap_or("");
+
+        if cmd == "click" {
+            trace.steps.push(Step {
+                click: parse_click(args.split_whitespace()).ok_or_else(err)?,
+                exchanges: vec![],
+                state: None,
+                images: None,
+            });
+            awaiting_recv = false;
+            continue;
+        }
+
+        let v = parse_sexp_str(args).ok_or_else(err)?;
+        match (cmd, trace.steps.last_mut()) {
+            ("state", None) => trace.initial = v,
+            ("state", Some(step)) => step.state = Some(v),
+            ("images", Some(step)) => step.images = Some(v),
+            ("send", Some(step)) => {
+                step.exchanges.push(Exchange {
+                    send: Some(v),
+                    recv: Value::nil(),
+                });
+                awaiting_recv = true;
+                continue;
+            }
+            ("recv", Some(step)) => match step.exchanges.last_mut() {
+                Some(ex) if awaiting_recv => ex.recv = v,
+                _ => step.exchanges.push(Exchange {
+                    send: None,
+                    recv: v,
+                }),
+            },
+            _ => return Err(err().into()),
+        }
+        awaiting_recv = false;
+    }
+
+    Ok(trace)
+}
+
+pub fn format_trace(trace: &Trace) -> String {
+    let mut ret = format!("state {}\n", trace.initial.to_sexp());
+    for step in trace.steps.iter() {
+        ret += &format!("click {} {}\n", step.click.0, step.click.1);
+        for ex in step.exchanges.iter() {
+            if let Some(send) = &ex.send {
+                ret += &format!("send {}\n", send.to_sexp());
+            }
+            ret += &format!("recv {}\n", ex.recv.to_sexp());
+        }
+        if let Some(state) = &step.state {
+            ret += &format!("state {}\n", state.to_sexp());
+        }
+        if let Some(images) = &step.images {
+            ret += &format!("images {}\n", images.to_sexp());
+        }
+    }
+    ret
+}
+
+// Runs the interact loop for a single click.
+fn run_step(ev: &mut dyn Evaluator, state: &Value, step: &Step, allow_send: bool) -> Result<Step> {
+    let mut event = Value::cons(Value::int(step.click.0), Value::int(step.click.1));
+    let mut state = state.clone();
+    let mut exchanges = vec![];
+
+    loop {
+        let (flag, new_state, data) = ev.galaxy(&state, &event)?;
+        state = new_state;
+
+        if flag == 0 {
+            return Ok(Step {
+                click: step.click,
+                exchanges,
+                state: Some(state),
+                images: Some(data),
+            });
+        }
+
+        let recv = match step.exchanges.get(exchanges.len()) {
+            Some(ex) => ex.recv.clone(),
+            None if allow_send => send_request_value(&data),
+            None => {
+                return Err(format!(
+                    "no recorded response for send {}; record it in the trace or use --send",
+                    data.to_sexp()
+                )
+                .into())
+            }
+        };
+        exchanges.push(Exchange {
+            send: Some(data),
+            recv: recv.clone(),
+        });
+        event = recv;
+    }
+}
+
+fn compare(what: &str, expected: &Value, actual: &Value, out: &mut Vec<String>) {
+    if expected != actual {
+        out.push(format!("{} differs", what));
+        for d in diff_value(expected, actual) {
+            out.push(format!("  {}", d));
+        }
+    }
+}
+
+// Lists the differences of `actual` from `expected`. Only the parts present
+// in `expected` are checked.
+fn compare_step(expected: &Step, actual: &Step) -> Vec<String> {
+    let mut ret = vec![];
+    for (i, ex) in expected.exchanges.iter().enumerate() {
+        match actual.exchanges.get(i) {
+            Some(Exchange {
+                send: Some(send), ..
+            }) => {
+                if let Some(expected_send) = &ex.send {
+                    compare(&format!("send #{}", i + 1), expected_send, send, &mut ret);
+                }
+            }
+            _ => ret.push(format!("send #{} missing", i + 1)),
+        }
+    }
+    if actual.exchanges.len() > expected.exchanges.len() && !expected.exchanges.is_empty() {
+        ret.push(format!(
+            "{} sends, expected {}",
+            actual.exchanges.len(),
+            expected.exchanges.len()
+        ));
+    }
+    if let (Some(e), Some(a)) = (&expected.state, &actual.state) {
+        compare("state", e, a, &mut ret);
+    }
+    if let (Some(e), Some(a)) = (&expected.images, &actual.images) {
+        compare("images", e, a, &mut ret);
+    }
+    ret
+}
+
+pub struct Report {
+    // Trace as produced by the first evaluator, up to where it failed.
+    pub recorded: Trace,
+    pub mismatches: Vec<String>,
+}
+
+// Runs every evaluator over the trace. An evaluator that diverges is not
+// run for the remaining steps, since all of them would differ as well.
+pub fn run_trace(evaluators: &mut [Box<dyn Evaluator>], trace: &Trace, allow_send: bool) -> Report {
+    let mut states = vec![Some(trace.initial.clone()); evaluators.len()];
+    let mut report = Report {
+        recorded: Trace {
+            initial: trace.initial.clone(),
+            steps: vec![],
+        },
+        mismatches: vec![],
+    };
+
+    for (n, step) in trace.steps.iter().enumerate() {
+        let mut reference: Option<(String, Step)> = None;
+
+        for (i, ev) in evaluators.iter_mut().enumerate() {
+            let state = match &states[i] {
+                Some(state) => state.clone(),
+                None => continue,
+            };
+            let header = format!("step {} click {:?}: {}", n + 1, step.click, ev.name());
+
+            let actual = match run_step(ev.as_mut(), &state, step, allow_send) {
+                Ok(actual) => actual,
+                Err(e) => {
+                    report.mismatches.push(format!("{}: {}", header, e));
+                    states[i] = None;
+                    continue;
+                }
+            };
+
+            let mut diffs = compare_step(step, &actual)
+                .into_iter()
+                .map(|d| format!("{}: {} (vs trace)", header, d))
+                .collect::<Vec<_>>();
+            if let Some((name, r)) = &reference {
+                diffs.extend(
+                    compare_step(r, &actual)
+                        .into_iter()
+                        .map(|d| format!("{}: {} (vs {})", header, d, name)),
+                );
+            }
+
+            states[i] = if diffs.is_empty() {
+                actual.state.clone()
+            } else {
+                None
+            };
+            report.mismatches.extend(diffs);
+
+            if i == 0 && states[0].is_some() {
+                report.recorded.steps.push(actual.clone());
+            }
+            if reference.is_none() {
+                reference = Some((ev.name().to_string(), actual));
+            }
+        }
+
+        if states.iter().all(Option::is_none) {
+            break;
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+struct FakeEvaluator;
+
+// Counts clicks in the state, and asks the server once per click.
+#[cfg(test)]
+impl Evaluator for FakeEvaluator {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    fn galaxy(&mut self, state: &Value, event: &Value) -> Result<(i64, Value, Value)> {
+        let n = match state {
+            Value::Int(n) => *n,
+            _ => 0,
+        };
+        match event {
+            Value::Cons(_, _) => Ok((1, Value::int(n), Value::int(n * 10))),
+            _ => Ok((0, Value::int(n + 1), Value::list(vec![event.clone()]))),
+        }
+    }
+}
+
+#[test]
+fn test_trace_roundtrip() {
+    let s = "state 0\nclick 1 2\nsend 0\nrecv 5\nstate 1\nimages (5 . ())\nclick -1 0\n";
+    let trace = parse_trace(s).unwrap();
+    assert_eq!(trace.steps.len(), 2);
+    assert_eq!(
+        trace.steps[0].exchanges,
+        vec![Exchange {
+            send: Some(Value::int(0)),
+            recv: Value::int(5)
+        }]
+    );
+    assert_eq!(format_trace(&trace), s);
+
+    assert!(parse_trace("images ()\n").is_err());
+    assert!(parse_trace("click 1\n").is_err());
+    assert!(parse_trace("state (1 x)\n").is_err());
+    assert!(parse_trace("click 0 0\nrecv (1 . 2 3)\n").is_err());
+    assert!(parse_trace("click 0 0\nsend (0\n").is_err());
+}
+
+#[test]
+fn test_run_trace() {
+    let mut evs: Vec<Box<dyn Evaluator>> = vec![Box::new(FakeEvaluator), Box::new(FakeEvaluator)];
+
+    let trace = parse_trace("click 0 0\nrecv 7\nclick 0 0\nrecv 8\n").unwrap();
+    let report = run_trace(&mut evs, &trace, false);
+    assert_eq!(report.mismatches, Vec::<String>::new());
+    assert_eq!(
+        format_trace(&report.recorded),
+        "state ()\nclick 0 0\nsend 0\nrecv 7\nstate 1\nimages (7 . ())\n\
+         click 0 0\nsend 10\nrecv 8\nstate 2\nimages (8 . ())\n"
+    );
+
+    let trace = parse_trace("click 0 0\nrecv 7\nstate 2\nclick 0 0\nrecv 8\n").unwrap();
+    let report = run_trace(&mut evs, &trace, false);
+    assert_eq!(
+        report.mismatches,
+        vec![
+            "step 1 click (0, 0): fake: state differs (vs trace)",
+            "step 1 click (0, 0): fake:   .: 2 -> 1 (vs trace)",
+            "step 1 click (0, 0): fake: state differs (vs trace)",
+            "step 1 click (0, 0): fake:   .: 2 -> 1 (vs trace)",
+        ]
+    );
+
+    let trace = parse_trace("click 0 0\n").unwrap();
+    let report = run_trace(&mut evs, &trace, false);
+    assert_eq!(report.mismatches.len(), 2);
+    assert!(report.mismatches[0].contains("no recorded response"));
+}
+
+#[test]
+fn test_graph_evaluator() {
+    let dict = super::parse_functions().unwrap();
+    let mut evs = evaluators(&dict).unwrap();
+
+    let trace =
+        parse_trace("state ()\nclick 0 0\nstate (0 (0) 0 ())\nclick 0 0\nstate (0 (1) 0 ())\n")
+            .unwrap();
+    let report = run_trace(&mut evs, &trace, false);
+    assert_eq!(report.mismatches, Vec::<String>::new());
+    assert_eq!(report.recorded.steps.len(), 2);
+
+    let trace = parse_trace("state ()\nclick 0 0\nstate (0 (1) 0 ())\n").unwrap();
+    let report = run_trace(&mut evs, &trace, false);
+    assert_eq!(report.mismatches.len(), 4);
+}
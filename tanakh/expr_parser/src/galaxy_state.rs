@@ -0,0 +1,379 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Typed view of the galaxy state.
+//
+// The layout follows psh/decompiled/decompiled.txt:
+//
+//   state = [mode, scene_state, unlocked, secret_keys]
+//
+// where `mode` indexes Garaxy.Scenes (:1336) and `scene_state` is owned by
+// the scene. Fields whose meaning is not understood yet are kept as raw
+// values, and states that do not match any known layout decode to
+// `Scene::Unknown`, so encode(decode(v)) == v holds for every state.
+
+use super::{Result, Value};
+
+pub const MODE_OPENING: i64 = 0;
+pub const MODE_CALIBRATION: i64 = 1;
+pub const MODE_MAIN_MENU: i64 = 2;
+pub const MODE_TICTACTOE: i64 = 3;
+pub const MODE_PELMANISM: i64 = 4;
+pub const MODE_HISTORY: i64 = 5;
+pub const MODE_TUTORIAL: i64 = 6;
+pub const MODE_ERROR: i64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GalaxyState {
+    pub scene: Scene,
+    // Number of entries unlocked in the history list.
+    pub unlocked: i64,
+    // Results of solved puzzles (tic-tac-toe, pelmanism), newest first.
+    pub secret_keys: Vec<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scene {
+    // Opening.Scene (:1227): [counter]
+    Opening {
+        counter: i64,
+    },
+    // Cariblation.Scene (:1445): [counter]
+    Calibration {
+        counter: i64,
+    },
+    // Space.Scene (:1420): [phase, selected alien]
+    MainMenu {
+        phase: i64,
+        selected: i64,
+    },
+    // TicTacToe.Scene (:1451): [phase, board, solved lines, key]
+    TicTacToe {
+        phase: i64,
+        board: Vec<i64>,
+        lines: Vec<i64>,
+        key: i64,
+    },
+    // Pelmanism.Scene (:1305): [phase, tiles, selected tile, key, matched]
+    Pelmanism {
+        phase: i64,
+        tiles: Vec<i64>,
+        selected: i64,
+        key: i64,
+        matched: Vec<i64>,
+    },
+    History(History),
+    // Tutorial.Scene (:1344): [stage, phase, ...]
+    Tutorial {
+        stage: i64,
+        phase: i64,
+        rest: Vec<Value>,
+    },
+    // Error.run (:1337) keeps no state.
+    Error,
+    Unknown {
+        mode: i64,
+        state: Value,
+    },
+}
+
+// History.InitSceneState (:1231):
+// [phase, playerKey, ?, ?, ?, game log, cursor, timestamp]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    pub phase: i64,
+    pub player_key: i64,
+    pub field2: Value,
+    pub field3: Value,
+    pub field4: Value,
+    pub game_log: Value,
+    pub cursor: Value,
+    pub timestamp: i64,
+}
+
+// Phase of the history scene that shows a game replay.
+pub const HISTORY_PHASE_REPLAY: i64 = 7;
+
+impl History {
+    // Replay of the game identified by `player_key`. `game_log` is the
+    // response to the history request, as in replay_state.txt.
+    pub fn replay(player_key: i64, game_log: Value, timestamp: i64) -> History {
+        History {
+            phase: HISTORY_PHASE_REPLAY,
+            player_key,
+            field2: Value::nil(),
+            field3: Value::nil(),
+            field4: Value::nil(),
+            game_log,
+            cursor: Value::cons(Value::int(0), Value::int(0)),
+            timestamp,
+        }
+    }
+}
+
+fn as_int(v: &Value) -> Result<i64> {
+    match v {
+        Value::Int(n) => Ok(*n),
+        _ => Err(format!("expected int, got {}", v.to_sexp()).into()),
+    }
+}
+
+fn as_list(v: &Value) -> Result<Vec<&Value>> {
+    let mut ret = vec![];
+    let mut v = v;
+    loop {
+        match v {
+            Value::Nil => return Ok(ret),
+            Value::Cons(hd, tl) => {
+                ret.push(hd.as_ref());
+                v = tl;
+            }
+            Value::Int(_) => return Err(format!("expected list, got {}", v.to_sexp()).into()),
+        }
+    }
+}
+
+fn as_tuple(v: &Value, len: usize) -> Result<Vec<&Value>> {
+    let ret = as_list(v)?;
+    if ret.len() != len {
+        return Err(format!("expected {} elements, got {}", len, v.to_sexp()).into());
+    }
+    Ok(ret)
+}
+
+fn as_ints(v: &Value) -> Result<Vec<i64>> {
+    as_list(v)?.into_iter().map(as_int).collect()
+}
+
+fn ints(v: &[i64]) -> Value {
+    Value::list(v.iter().map(|n| Value::int(*n)).collect())
+}
+
+impl Scene {
+    pub fn mode(&self) -> i64 {
+        match self {
+            Scene::Opening { .. } => MODE_OPENING,
+            Scene::Calibration { .. } => MODE_CALIBRATION,
+            Scene::MainMenu { .. } => MODE_MAIN_MENU,
+            Scene::TicTacToe { .. } => MODE_TICTACTOE,
+            Scene::Pelmanism { .. } => MODE_PELMANISM,
+            Scene::History(_) => MODE_HISTORY,
+            Scene::Tutorial { .. } => MODE_TUTORIAL,
+            Scene::Error => MODE_ERROR,
+            Scene::Unknown { mode, .. } => *mode,
+        }
+    }
+
+    pub fn decode(mode: i64, v: &Value) -> Scene {
+        Scene::decode_known(mode, v).unwrap_or_else(|_| Scene::Unknown {
+            mode,
+            state: v.clone(),
+        })
+    }
+
+    fn decode_known(mode: i64, v: &Value) -> Result<Scene> {
+        Ok(match mode {
+            MODE_OPENING => Scene::Opening {
+                counter: as_int(as_tuple(v, 1)?[0])?,
+            },
+            MODE_CALIBRATION => Scene::Calibration {
+                counter: as_int(as_tuple(v, 1)?[0])?,
+            },
+            MODE_MAIN_MENU => {
+                let v = as_tuple(v, 2)?;
+                Scene::MainMenu {
+                    phase: as_int(v[0])?,
+                    selected: as_int(v[1])?,
+                }
+            }
+            MODE_TICTACTOE => {
+                let v = as_tuple(v, 4)?;
+                Scene::TicTacToe {
+                    phase: as_int(v[0])?,
+                    board: as_ints(v[1])?,
+                    lines: as_ints(v[2])?,
+                    key: as_int(v[3])?,
+                }
+            }
+            MODE_PELMANISM => {
+                let v = as_tuple(v, 5)?;
+                Scene::Pelmanism {
+                    phase: as_int(v[0])?,
+                    tiles: as_ints(v[1])?,
+                    selected: as_int(v[2])?,
+                    key: as_int(v[3])?,
+                    matched: as_ints(v[4])?,
+                }
+            }
+            MODE_HISTORY => {
+                let v = as_tuple(v, 8)?;
+                Scene::History(History {
+                    phase: as_int(v[0])?,
+                    player_key: as_int(v[1])?,
+                    field2: v[2].clone(),
+                    field3: v[3].clone(),
+                    field4: v[4].clone(),
+                    game_log: v[5].clone(),
+                    cursor: v[6].clone(),
+                    timestamp: as_int(v[7])?,
+                })
+            }
+            MODE_TUTORIAL => {
+                let v = as_tuple(v, 13)?;
+                Scene::Tutorial {
+                    stage: as_int(v[0])?,
+                    phase: as_int(v[1])?,
+                    rest: v[2..].iter().map(|v| (*v).clone()).collect(),
+                }
+            }
+            MODE_ERROR if *v == Value::nil() => Scene::Error,
+            _ => return Err(format!("unknown mode: {}", mode).into()),
+        })
+    }
+
+    pub fn encode(&self) -> Value {
+        match self {
+            Scene::Opening { counter } | Scene::Calibration { counter } => {
+                Value::list(vec![Value::int(*counter)])
+            }
+            Scene::MainMenu { phase, selected } => {
+                Value::list(vec![Value::int(*phase), Value::int(*selected)])
+            }
+            Scene::TicTacToe {
+                phase,
+                board,
+                lines,
+                key,
+            } => Value::list(vec![
+                Value::int(*phase),
+                ints(board),
+                ints(lines),
+                Value::int(*key),
+            ]),
+            Scene::Pelmanism {
+                phase,
+                tiles,
+                selected,
+                key,
+                matched,
+            } => Value::list(vec![
+                Value::int(*phase),
+                ints(tiles),
+                Value::int(*selected),
+                Value::int(*key),
+                ints(matched),
+            ]),
+            Scene::History(h) => Value::list(vec![
+                Value::int(h.phase),
+                Value::int(h.player_key),
+                h.field2.clone(),
+                h.field3.clone(),
+                h.field4.clone(),
+                h.game_log.clone(),
+                h.cursor.clone(),
+                Value::int(h.timestamp),
+            ]),
+            Scene::Tutorial { stage, phase, rest } => {
+                let mut v = vec![Value::int(*stage), Value::int(*phase)];
+                v.extend(rest.iter().cloned());
+                Value::list(v)
+            }
+            Scene::Error => Value::nil(),
+            Scene::Unknown { state, .. } => state.clone(),
+        }
+    }
+}
+
+impl GalaxyState {
+    // The state of the history scene replaying the given game, the same as
+    // replay_state.txt.
+    pub fn replay(player_key: i64, game_log: Value, timestamp: i64) -> GalaxyState {
+        GalaxyState {
+            scene: Scene::History(History::replay(player_key, game_log, timestamp)),
+            unlocked: 1,
+            secret_keys: vec![],
+        }
+    }
+
+    pub fn decode(v: &Value) -> Result<GalaxyState> {
+        let v = as_tuple(v, 4).map_err(|e| format!("galaxy state: {}", e))?;
+        Ok(GalaxyState {
+            scene: Scene::decode(as_int(v[0])?, v[1]),
+            unlocked: as_int(v[2])?,
+            secret_keys: as_list(v[3])?.into_iter().cloned().collect(),
+        })
+    }
+
+    pub fn encode(&self) -> Value {
+        Value::list(vec![
+            Value::int(self.scene.mode()),
+            self.scene.encode(),
+            Value::int(self.unlocked),
+            Value::list(self.secret_keys.clone()),
+        ])
+    }
+}
+
+#[test]
+fn test_galaxy_state_roundtrip() {
+    use super::parse_sexp_str;
+
+    let states = [
+        "(0 (0) 0 ())",
+        "(1 (3) 0 ())",
+        "(2 (1 -1) 2 (123))",
+        "(3 (0 (0 0 0 0 1 0 0 0 2) (3 5) 0) 0 ())",
+        "(4 (1 (122 203) -1 0 ()) 0 ())",
+        "(5 (7 42 () () () (1 2 3) (0 . 0) 999) 1 ())",
+        "(6 (1 0 0 1 0 0 () () 4 () () () ()) 0 ())",
+        "(10 () 0 ())",
+        "(7 (1 2) 0 ())",
+        "(2 (1 2 3) 0 ())",
+    ];
+    for s in states.iter() {
+        let v = parse_sexp_str(s).unwrap();
+        let state = GalaxyState::decode(&v).unwrap();
+        assert_eq!(state.encode(), v, "{}", s);
+    }
+
+    let v = parse_sexp_str("(2 (1 -1) 0 ())").unwrap();
+    assert_eq!(
+        GalaxyState::decode(&v).unwrap().scene,
+        Scene::MainMenu {
+            phase: 1,
+            selected: -1
+        }
+    );
+    let v = parse_sexp_str("(2 (1 2 3) 0 ())").unwrap();
+    assert_eq!(
+        GalaxyState::decode(&v).unwrap().scene.mode(),
+        MODE_MAIN_MENU
+    );
+    assert!(GalaxyState::decode(&parse_sexp_str("(1 2)").unwrap()).is_err());
+}
+
+#[test]
+fn test_galaxy_state_replay() {
+    use super::parse_sexp_str;
+
+    // replay_state.txt with playerKey = 42, history = (1 2), timestamp = 7.
+    let expected = parse_sexp_str(concat!(
+        "(5 . ((7 . (42 . (() . (() . (() . ((1 2) . ((0 . 0) . (7 . ())))))))) . ",
+        "(1 . (() . ()))))"
+    ))
+    .unwrap();
+    let state = GalaxyState::replay(42, parse_sexp_str("(1 2)").unwrap(), 7);
+    assert_eq!(state.encode(), expected);
+}
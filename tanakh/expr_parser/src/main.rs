@@ -20,10 +20,19 @@ use std::{
     fs::File,
     io::{BufRead, BufReader},
     iter::Peekable,
+    path::{Path, PathBuf},
     rc::Rc,
 };
 use structopt::StructOpt;
 
+mod difftest;
+mod galaxy_state;
+mod profile;
+mod repl;
+mod vm;
+
+use difftest::Evaluator;
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 type Dictionary = BTreeMap<String, ExprRef>;
@@ -274,11 +283,24 @@ fn as_num(x: ExprRef, dict: &Dictionary) -> Option<i64> {
     eval(x, dict).borrow().value.borrow().int()
 }
 
+// Looks up the memoized result of `e`, counting the lookup.
+fn memoized(e: &ExprRef) -> Option<ExprRef> {
+    let ret = e.borrow().evaluated.as_ref().map(Rc::clone);
+    profile::memo(ret.is_some());
+    ret
+}
+
 fn eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
-    if let Some(e) = &e.borrow().evaluated {
-        return Rc::clone(&e);
+    if let Some(e) = memoized(&e) {
+        return e;
     }
 
+    let _frame = if profile::enabled() {
+        head_definition(&e, dict).map(|name| profile::FrameGuard::enter(&name))
+    } else {
+        None
+    };
+
     let init_expr = Rc::clone(&e);
     let mut e = e;
 
@@ -292,6 +314,28 @@ fn eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
     }
 }
 
+// Returns the name of the definition applied at the head of `e`, if `e` is
+// an application of one.
+fn head_definition(e: &ExprRef, dict: &Dictionary) -> Option<String> {
+    let mut e = match &*e.borrow().value.borrow() {
+        ExprValue::App(f, _) => Rc::clone(f),
+        _ => return None,
+    };
+    loop {
+        let f = match &*e.borrow().value.borrow() {
+            ExprValue::App(f, _) => Rc::clone(f),
+            ExprValue::Atom(name) if dict.contains_key(name) => return Some(name.clone()),
+            _ => return None,
+        };
+        e = f;
+    }
+}
+
+fn reduced(op: &str, e: ExprRef) -> ExprRef {
+    profile::reduction(op);
+    e
+}
+
 fn try_eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
     use dsl::*;
 
@@ -305,12 +349,14 @@ fn try_eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
         as_num(x, dict).unwrap_or_else(|| panic!(format!("{}: argument is not int", fname)))
     };
 
-    if let Some(e) = &e.borrow().evaluated {
+    // Already counted by eval.
+    if let Some(e) = e.borrow().evaluated.as_ref() {
         return Rc::clone(e);
     }
 
     if let Some(name) = e.borrow().value.borrow().atom() {
         if let Some(ret) = dict.get(name) {
+            profile::expansion(name);
             return Rc::clone(ret);
         }
     }
@@ -326,12 +372,12 @@ fn try_eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
         let x = Rc::clone(x);
 
         match f.borrow().value.borrow().atom() {
-            Some("neg") => return Expr::make_int(-num("neg", x)),
-            Some("i") => return x,
-            Some("nil") => return tt(),
-            Some("isnil") => return app(x, app(tt(), app(tt(), ff()))),
-            Some("car") => return app(x, tt()),
-            Some("cdr") => return app(x, ff()),
+            Some("neg") => return reduced("neg", Expr::make_int(-num("neg", x))),
+            Some("i") => return reduced("i", x),
+            Some("nil") => return reduced("nil", tt()),
+            Some("isnil") => return reduced("isnil", app(x, app(tt(), app(tt(), ff())))),
+            Some("car") => return reduced("car", app(x, tt())),
+            Some("cdr") => return reduced("cdr", app(x, ff())),
             _ => {}
         }
 
@@ -342,14 +388,14 @@ fn try_eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
             let y = Rc::clone(y);
 
             match f.borrow().value.borrow().atom() {
-                Some("t") => return y,
-                Some("f") => return x,
-                Some("add") => return int(num("add", y) + num("add", x)),
-                Some("mul") => return int(num("mul", y) * num("mul", x)),
-                Some("div") => return int(num("div", y) / num("div", x)),
-                Some("lt") => return bool(num("lt", y) < num("lt", x)),
-                Some("eq") => return bool(num("eq", y) == num("eq", x)),
-                Some("cons") => return eval_cons(y, x),
+                Some("t") => return reduced("t", y),
+                Some("f") => return reduced("f", x),
+                Some("add") => return reduced("add", int(num("add", y) + num("add", x))),
+                Some("mul") => return reduced("mul", int(num("mul", y) * num("mul", x))),
+                Some("div") => return reduced("div", int(num("div", y) / num("div", x))),
+                Some("lt") => return reduced("lt", bool(num("lt", y) < num("lt", x))),
+                Some("eq") => return reduced("eq", bool(num("eq", y) == num("eq", x))),
+                Some("cons") => return reduced("cons", eval_cons(y, x)),
                 _ => {}
             }
 
@@ -363,10 +409,10 @@ fn try_eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
                 let bbf = bf.value.borrow();
 
                 match bbf.atom() {
-                    Some("s") => return app(app(z, Rc::clone(&x)), app(y, x)),
-                    Some("c") => return app(app(z, x), y),
-                    Some("b") => return app(z, app(y, x)),
-                    Some("cons") => return app(app(x, z), y),
+                    Some("s") => return reduced("s", app(app(z, Rc::clone(&x)), app(y, x))),
+                    Some("c") => return reduced("c", app(app(z, x), y)),
+                    Some("b") => return reduced("b", app(z, app(y, x))),
+                    Some("cons") => return reduced("cons", app(app(x, z), y)),
 
                     Some(f) => panic!("invalid function: {}", f),
                     _ => panic!(
@@ -386,7 +432,7 @@ fn try_eval(e: ExprRef, dict: &Dictionary) -> ExprRef {
     e
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Value {
     Int(i64),
     Nil,
@@ -598,6 +644,8 @@ fn parse_expr(s: &str) -> ExprRef {
     ret
 }
 
+// Returns None on malformed input, e.g. an atom other than an integer or
+// unbalanced parentheses.
 fn parse_sexp<'a>(it: &mut Peekable<impl Iterator<Item = &'a str>>) -> Option<Value> {
     let s = it.next()?;
 
@@ -606,13 +654,14 @@ fn parse_sexp<'a>(it: &mut Peekable<impl Iterator<Item = &'a str>>) -> Option<Va
         loop {
             let s = it.peek()?;
             if s == &")" {
-                assert_eq!(it.next().unwrap(), ")");
+                it.next();
                 return Some(Value::list(v));
             } else if s == &"." {
-                assert_eq!(it.next().unwrap(), ".");
+                it.next();
                 let mut ret = parse_sexp(it)?;
-                let s = it.next()?;
-                assert_eq!(s, ")");
+                if it.next()? != ")" {
+                    return None;
+                }
                 for v in v.into_iter().rev() {
                     ret = Value::cons(v, ret);
                 }
@@ -621,10 +670,8 @@ fn parse_sexp<'a>(it: &mut Peekable<impl Iterator<Item = &'a str>>) -> Option<Va
                 v.push(parse_sexp(it)?);
             }
         }
-    } else if let Ok(n) = s.parse() {
-        Some(Value::int(n))
     } else {
-        unreachable!("{}", s);
+        s.parse().ok().map(Value::int)
     }
 }
 
@@ -643,7 +690,9 @@ fn parse_sexp_str(s: &str) -> Option<Value> {
     let it = ns.split_whitespace();
     let mut it = it.peekable();
     let ret = parse_sexp(&mut it)?;
-    assert!(it.next().is_none());
+    if it.next().is_some() {
+        return None;
+    }
     Some(ret)
 }
 
@@ -678,6 +727,12 @@ fn test_parse_sexp() {
             Value::int(3)
         ]))
     );
+
+    assert_eq!(parse_sexp_str("(1 x)"), None);
+    assert_eq!(parse_sexp_str("(1 . 2 3)"), None);
+    assert_eq!(parse_sexp_str("(1 2"), None);
+    assert_eq!(parse_sexp_str("1 2"), None);
+    assert_eq!(parse_sexp_str(")"), None);
 }
 
 // curl -X POST "https://icfpc2020-api.testkontur.ru/aliens/send?apiKey=REDACTED" -H "accept: */*" -H "Content-Type: text/plain" -d "111111011000010100000"
@@ -787,9 +842,13 @@ fn plot(ps: &[Vec<(i64, i64)>], html: bool) {
 }
 
 fn parse_functions() -> Result<Dictionary> {
+    parse_functions_from(Path::new("galaxy.txt"))
+}
+
+fn parse_functions_from(path: &Path) -> Result<Dictionary> {
     let mut dict = Dictionary::new();
 
-    let mut f = BufReader::new(File::open("galaxy.txt")?);
+    let mut f = BufReader::new(File::open(path)?);
 
     loop {
         let mut s = String::new();
@@ -868,7 +927,9 @@ fn value_to_list(v: Value) -> VecDeque<Value> {
 fn expr_to_value(e: ExprRef, dict: &Dictionary) -> Value {
     use dsl::*;
 
-    if expr_to_bool(app(atom("isnil"), Rc::clone(&e)), dict) {
+    if let Some(n) = as_num(Rc::clone(&e), dict) {
+        Value::Int(n)
+    } else if expr_to_bool(app(atom("isnil"), Rc::clone(&e)), dict) {
         Value::Nil
     } else {
         let car = app(atom("car"), Rc::clone(&e));
@@ -887,88 +948,79 @@ fn value_to_expr(e: &Value) -> ExprRef {
     }
 }
 
-fn interact(dict: &Dictionary, state: ExprRef, event: ExprRef) -> (ExprRef, ExprRef) {
-    use dsl::*;
-
-    let expr = app(app(atom("galaxy"), state), event);
-    let res = eval(expr, dict);
-
-    let res = expr_to_list(res, dict);
-    assert_eq!(res.len(), 3);
-
-    let flag = Rc::clone(&res[0]);
-    let new_state = Rc::clone(&res[1]);
-    let data = Rc::clone(&res[2]);
-
-    let flag = as_num(flag, dict).unwrap_or_else(|| panic!("interact: flag is not zero"));
-
+// Runs the interact protocol: feeds the event to galaxy, and the server's
+// response back in as long as galaxy wants to send something.
+fn interact(ev: &mut dyn Evaluator, state: &Value, event: &Value) -> Result<(Value, Value)> {
+    let (flag, new_state, data) = ev.galaxy(state, event)?;
     if flag == 0 {
-        (new_state, data)
+        Ok((new_state, data))
     } else {
-        interact(
-            dict,
-            new_state,
-            value_to_expr(&send_request_value(&expr_to_value(data, dict))),
-        )
+        interact(ev, &new_state, &send_request_value(&data))
     }
 }
 
-fn print_image(images: ExprRef, dict: &Dictionary) {
-    // assume e is [[(int, int)]]
+// The graph reducer, or the compiled VM if `vm`.
+fn evaluator(dict: Dictionary, vm: bool) -> Result<Box<dyn Evaluator>> {
+    Ok(if vm {
+        Box::new(difftest::VmEvaluator::new(&dict)?)
+    } else {
+        Box::new(difftest::GraphEvaluator::new(dict))
+    })
+}
 
-    use dsl::*;
+fn print_image(images: &Value) -> Result<()> {
+    // assume images is [[(int, int)]]
+    let not_images = || "images is not a list of lists of points";
 
     let mut vs = vec![];
-
-    for image in expr_to_list(images, dict) {
+    for image in value_to_list(images.clone()) {
         let mut v = vec![];
-
-        let pts = expr_to_list(image, dict);
-        for pt in pts {
-            let x = as_num(app(atom("car"), Rc::clone(&pt)), dict)
-                .expect("images contains non-integer value");
-            let y = as_num(app(atom("cdr"), pt), dict).expect("images contains non-integer value");
-
-            v.push((x, y));
+        for pt in value_to_list(image) {
+            match pt {
+                Value::Cons(x, y) => match (*x, *y) {
+                    (Value::Int(x), Value::Int(y)) => v.push((x, y)),
+                    _ => return Err(not_images().into()),
+                },
+                _ => return Err(not_images().into()),
+            }
         }
-
         vs.push(v);
     }
 
     plot(&vs, false);
+    Ok(())
 }
 
 fn run(opt: &RunOpt) -> Result<()> {
-    use dsl::*;
+    let mut ev = evaluator(parse_functions()?, opt.vm)?;
 
-    let dict = parse_functions()?;
-
-    let mut state = value_to_expr(&parse_sexp_str(&opt.state).ok_or("Failed to parse state")?);
+    let mut state = parse_sexp_str(&opt.state).ok_or("Failed to parse state")?;
     let input = value_to_list(parse_sexp_str(&opt.input).ok_or("Failed to parse input")?);
 
-    for (step, pt) in input.iter().enumerate() {
-        let pt = value_to_expr(&pt);
-        let x = app(atom("car"), Rc::clone(&pt));
-        let y = app(atom("cdr"), pt);
+    let mut recorder = profile::Recorder::new(opt.profile.stats, opt.profile.profile.clone());
+
+    for (step, pt) in input.into_iter().enumerate() {
+        let (x, y) = match &pt {
+            Value::Cons(x, y) => match (&**x, &**y) {
+                (Value::Int(x), Value::Int(y)) => (*x, *y),
+                _ => return Err("input is not a list of points".into()),
+            },
+            _ => return Err("input is not a list of points".into()),
+        };
 
-        let click = app(app(atom("cons"), Rc::clone(&x)), Rc::clone(&y));
-        let (new_state, images) = interact(&dict, state, click);
+        let (new_state, images) = interact(&mut *ev, &state, &pt)?;
+        recorder.step(&format!("step {}", step + 1))?;
 
         println!("step:  {}", step + 1);
-        println!(
-            "input: {:?}",
-            (as_num(x, &dict).unwrap(), as_num(y, &dict).unwrap())
-        );
-        println!(
-            "state: {}",
-            expr_to_value(Rc::clone(&new_state), &dict).print()
-        );
+        println!("input: {:?}", (x, y));
+        println!("state: {}", new_state.print());
 
-        print_image(images, &dict);
+        print_image(&images)?;
 
         state = new_state;
     }
 
+    recorder.finish()?;
     Ok(())
 }
 
@@ -982,10 +1034,105 @@ struct SendOpt {
     msg: String,
 }
 
+#[derive(StructOpt, Debug)]
+struct ProfileOpt {
+    /// Prints evaluator statistics to stderr after each click
+    #[structopt(long)]
+    stats: bool,
+    /// Writes a flamegraph-compatible folded stack profile to the file
+    #[structopt(long, parse(from_os_str))]
+    profile: Option<PathBuf>,
+}
+
 #[derive(StructOpt, Debug)]
 struct RunOpt {
     state: String,
     input: String,
+    /// Evaluates with the compiled VM instead of the graph reducer
+    #[structopt(long, conflicts_with_all = &["stats", "profile"])]
+    vm: bool,
+    #[structopt(flatten)]
+    profile: ProfileOpt,
+}
+
+#[derive(StructOpt, Debug)]
+struct ReplOpt {
+    #[structopt(default_value = "()")]
+    state: String,
+    /// Evaluates with the compiled VM instead of the graph reducer
+    #[structopt(long, conflicts_with_all = &["stats", "profile"])]
+    vm: bool,
+    #[structopt(flatten)]
+    profile: ProfileOpt,
+}
+
+#[derive(StructOpt, Debug)]
+struct DiffOpt {
+    #[structopt(long, default_value = "galaxy.txt", parse(from_os_str))]
+    galaxy: PathBuf,
+    /// Sends requests to the server when the trace has no recorded response
+    #[structopt(long)]
+    send: bool,
+    /// Writes the trace produced by the first evaluator to the file
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+    #[structopt(parse(from_os_str), required = true)]
+    traces: Vec<PathBuf>,
+}
+
+fn diff(opt: &DiffOpt) -> Result<()> {
+    if opt.record.is_some() && opt.traces.len() != 1 {
+        return Err("--record takes a single trace".into());
+    }
+
+    let dict = parse_functions_from(&opt.galaxy)?;
+    let mut evaluators = difftest::evaluators(&dict)?;
+
+    let mut failures = 0;
+    for path in opt.traces.iter() {
+        let trace = difftest::parse_trace(&std::fs::read_to_string(path)?)?;
+        let report = difftest::run_trace(&mut evaluators, &trace, opt.send);
+
+        println!(
+            "{}: {} steps, {}",
+            path.display(),
+            trace.steps.len(),
+            if report.mismatches.is_empty() {
+                "ok"
+            } else {
+                "FAILED"
+            }
+        );
+        for m in report.mismatches.iter() {
+            println!("  {}", m);
+        }
+        if !report.mismatches.is_empty() {
+            failures += 1;
+        }
+
+        if let Some(record) = &opt.record {
+            std::fs::write(record, difftest::format_trace(&report.recorded))?;
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} of {} traces failed", failures, opt.traces.len()).into());
+    }
+    Ok(())
+}
+
+#[derive(StructOpt, Debug)]
+enum StateOpt {
+    Decode {
+        state: String,
+    },
+    Replay {
+        player_key: i64,
+        #[structopt(default_value = "()")]
+        game_log: String,
+        #[structopt(default_value = "0")]
+        timestamp: i64,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -993,6 +1140,9 @@ enum Opt {
     Send(SendOpt),
     SendRaw,
     Run(RunOpt),
+    Repl(ReplOpt),
+    State(StateOpt),
+    Diff(DiffOpt),
 }
 
 fn main() -> Result<()> {
@@ -1017,6 +1167,26 @@ fn main() -> Result<()> {
         Opt::Run(opt) => {
             run(&opt)?;
         }
+        Opt::Repl(opt) => {
+            let recorder = profile::Recorder::new(opt.profile.stats, opt.profile.profile);
+            repl::repl(&opt.state, opt.vm, recorder)?;
+        }
+        Opt::Diff(opt) => {
+            diff(&opt)?;
+        }
+        Opt::State(StateOpt::Decode { state }) => {
+            let state = parse_sexp_str(&state).ok_or("Failed to parse state")?;
+            println!("{:#?}", galaxy_state::GalaxyState::decode(&state)?);
+        }
+        Opt::State(StateOpt::Replay {
+            player_key,
+            game_log,
+            timestamp,
+        }) => {
+            let game_log = parse_sexp_str(&game_log).ok_or("Failed to parse game log")?;
+            let state = galaxy_state::GalaxyState::replay(player_key, game_log, timestamp);
+            println!("{}", state.encode().to_sexp());
+        }
     }
 
     Ok(())
@@ -0,0 +1,336 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Evaluator instrumentation.
+//
+// The profiler is a thread-local that is off by default, so the evaluator
+// only pays for a flag check per call unless profiling was requested.
+//
+// Time and reductions are attributed to named definitions (`:1141`,
+// `galaxy`, ...): whenever `eval` starts on an expression whose head is a
+// definition, a frame for that definition is pushed until the expression
+// reaches weak head normal form.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct DefStats {
+    pub calls: u64,
+    pub expansions: u64,
+    pub self_reductions: u64,
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+struct Frame {
+    name: String,
+    start: Instant,
+    child_time: Duration,
+}
+
+#[derive(Default)]
+pub struct Profile {
+    pub reductions: BTreeMap<String, u64>,
+    pub definitions: BTreeMap<String, DefStats>,
+    pub memo_hits: u64,
+    pub memo_misses: u64,
+    // Folded call stacks ("a;b;c") to the number of reductions performed
+    // with that stack on top.
+    pub folded: BTreeMap<String, u64>,
+    stack: Vec<Frame>,
+}
+
+thread_local! {
+    static PROFILE: RefCell<Option<Profile>> = const { RefCell::new(None) };
+}
+
+pub fn enable() {
+    PROFILE.with(|p| *p.borrow_mut() = Some(Profile::default()));
+}
+
+pub fn enabled() -> bool {
+    PROFILE.with(|p| p.borrow().is_some())
+}
+
+// Returns the statistics collected so far and starts over.
+pub fn take() -> Option<Profile> {
+    PROFILE.with(|p| {
+        let mut p = p.borrow_mut();
+        let ret = p.take()?;
+        *p = Some(Profile::default());
+        Some(ret)
+    })
+}
+
+fn with<F: FnOnce(&mut Profile)>(f: F) {
+    PROFILE.with(|p| {
+        if let Some(p) = p.borrow_mut().as_mut() {
+            f(p);
+        }
+    })
+}
+
+pub fn reduction(op: &str) {
+    with(|p| {
+        *p.reductions.entry(op.to_string()).or_insert(0) += 1;
+        p.count_self_reduction();
+    });
+}
+
+pub fn expansion(name: &str) {
+    with(|p| {
+        *p.reductions.entry(name.to_string()).or_insert(0) += 1;
+        p.definitions
+            .entry(name.to_string())
+            .or_default()
+            .expansions += 1;
+        p.count_self_reduction();
+    });
+}
+
+pub fn memo(hit: bool) {
+    with(|p| {
+        if hit {
+            p.memo_hits += 1;
+        } else {
+            p.memo_misses += 1;
+        }
+    });
+}
+
+// Attributes everything until the guard is dropped to the definition `name`.
+pub struct FrameGuard(());
+
+impl FrameGuard {
+    pub fn enter(name: &str) -> FrameGuard {
+        with(|p| {
+            p.stack.push(Frame {
+                name: name.to_string(),
+                start: Instant::now(),
+                child_time: Duration::default(),
+            });
+            p.definitions.entry(name.to_string()).or_default().calls += 1;
+        });
+        FrameGuard(())
+    }
+}
+
+impl Drop for FrameGuard {
+    fn drop(&mut self) {
+        with(|p| {
+            let frame = match p.stack.pop() {
+                Some(frame) => frame,
+                None => return,
+            };
+            let elapsed = frame.start.elapsed();
+
+            // Recursive frames must not count their time twice.
+            let recursive = p.stack.iter().any(|f| f.name == frame.name);
+            let stats = p.definitions.get_mut(&frame.name).unwrap();
+            stats.self_time += elapsed - frame.child_time.min(elapsed);
+            if !recursive {
+                stats.total_time += elapsed;
+            }
+
+            if let Some(parent) = p.stack.last_mut() {
+                parent.child_time += elapsed;
+            }
+        });
+    }
+}
+
+impl Profile {
+    fn count_self_reduction(&mut self) {
+        let key = if self.stack.is_empty() {
+            "(top)".to_string()
+        } else {
+            self.stack
+                .iter()
+                .map(|f| f.name.as_str())
+                .collect::<Vec<_>>()
+                .join(";")
+        };
+        *self.folded.entry(key).or_insert(0) += 1;
+
+        if let Some(frame) = self.stack.last() {
+            self.definitions
+                .get_mut(&frame.name)
+                .unwrap()
+                .self_reductions += 1;
+        }
+    }
+
+    pub fn total_reductions(&self) -> u64 {
+        self.reductions.values().sum()
+    }
+
+    pub fn memo_hit_rate(&self) -> f64 {
+        let total = self.memo_hits + self.memo_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.memo_hits as f64 / total as f64
+        }
+    }
+
+    // Human readable summary. `top` limits the number of definitions shown.
+    pub fn report(&self, w: &mut impl Write, top: usize) -> std::io::Result<()> {
+        writeln!(w, "reductions: {}", self.total_reductions())?;
+        writeln!(
+            w,
+            "memo:       {} hits / {} lookups ({:.1}%)",
+            self.memo_hits,
+            self.memo_hits + self.memo_misses,
+            self.memo_hit_rate() * 100.0
+        )?;
+
+        let mut ops = self
+            .reductions
+            .iter()
+            .filter(|(op, _)| !self.definitions.contains_key(*op))
+            .collect::<Vec<_>>();
+        ops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(w, "combinators:")?;
+        for (op, count) in ops {
+            writeln!(w, "  {:<8} {:>12}", op, count)?;
+        }
+
+        let mut defs = self.definitions.iter().collect::<Vec<_>>();
+        defs.sort_by(|a, b| b.1.self_time.cmp(&a.1.self_time).then(a.0.cmp(b.0)));
+        writeln!(
+            w,
+            "definitions:\n  {:<8} {:>10} {:>12} {:>12} {:>10} {:>10}",
+            "name", "calls", "expansions", "self_reds", "self_ms", "total_ms"
+        )?;
+        for (name, stats) in defs.into_iter().take(top) {
+            writeln!(
+                w,
+                "  {:<8} {:>10} {:>12} {:>12} {:>10.3} {:>10.3}",
+                name,
+                stats.calls,
+                stats.expansions,
+                stats.self_reductions,
+                stats.self_time.as_secs_f64() * 1000.0,
+                stats.total_time.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+
+    // Writes the profile in the folded stack format understood by
+    // flamegraph.pl and inferno, weighted by reduction count.
+    pub fn write_folded(&self, w: &mut impl Write) -> std::io::Result<()> {
+        for (stack, count) in self.folded.iter() {
+            writeln!(w, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: Profile) {
+        for (op, count) in other.reductions {
+            *self.reductions.entry(op).or_insert(0) += count;
+        }
+        for (name, stats) in other.definitions {
+            let s = self.definitions.entry(name).or_default();
+            s.calls += stats.calls;
+            s.expansions += stats.expansions;
+            s.self_reductions += stats.self_reductions;
+            s.self_time += stats.self_time;
+            s.total_time += stats.total_time;
+        }
+        for (stack, count) in other.folded {
+            *self.folded.entry(stack).or_insert(0) += count;
+        }
+        self.memo_hits += other.memo_hits;
+        self.memo_misses += other.memo_misses;
+    }
+}
+
+// Collects per-step profiles for the command line tools.
+pub struct Recorder {
+    stats: bool,
+    folded: Option<PathBuf>,
+    total: Profile,
+}
+
+impl Recorder {
+    // Enables the profiler if either statistics or a folded profile were
+    // requested.
+    pub fn new(stats: bool, folded: Option<PathBuf>) -> Recorder {
+        if stats || folded.is_some() {
+            enable();
+        }
+        Recorder {
+            stats,
+            folded,
+            total: Profile::default(),
+        }
+    }
+
+    pub fn step(&mut self, label: &str) -> std::io::Result<()> {
+        if let Some(p) = take() {
+            if self.stats {
+                let stderr = std::io::stderr();
+                let mut w = stderr.lock();
+                writeln!(w, "--- profile: {}", label)?;
+                p.report(&mut w, 20)?;
+            }
+            self.total.merge(p);
+        }
+        Ok(())
+    }
+
+    pub fn finish(&self) -> std::io::Result<()> {
+        if let Some(path) = &self.folded {
+            self.total.write_folded(&mut File::create(path)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_profile_counts() {
+    use super::{as_num, parse_expr, Dictionary};
+
+    let mut dict = Dictionary::new();
+    dict.insert(":1".to_string(), parse_expr("ap ap add 1 2"));
+    dict.insert(":2".to_string(), parse_expr("ap ap b ap mul 2 ap add :1"));
+
+    enable();
+    assert_eq!(as_num(parse_expr("ap :2 5"), &dict), Some(16));
+    let p = take().unwrap();
+    PROFILE.with(|p| *p.borrow_mut() = None);
+
+    assert_eq!(p.reductions.get("b"), Some(&1));
+    assert_eq!(p.reductions.get("add"), Some(&2));
+    assert_eq!(p.reductions.get("mul"), Some(&1));
+    assert_eq!(p.definitions[":2"].calls, 1);
+    assert_eq!(p.definitions[":2"].expansions, 1);
+    assert_eq!(p.definitions[":1"].expansions, 1);
+    assert!(p.memo_misses > 0);
+    assert!(p.memo_hits > 0);
+
+    let mut folded = vec![];
+    p.write_folded(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(folded.lines().any(|l| l.starts_with(":2 ")));
+}
@@ -0,0 +1,311 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Interactive galaxy explorer.
+//
+// Keeps the galaxy state across commands so that the UI can be poked at
+// click by click. A session is fully described by its initial state and the
+// list of clicks, which is what `save` writes out and `load` replays.
+
+use super::{
+    evaluator, interact, parse_functions, parse_sexp_str, print_image, profile::Recorder,
+    value_to_list, Evaluator, Result, Value,
+};
+use std::{
+    fs::File,
+    io::{BufRead, Write},
+};
+
+struct Session {
+    ev: Box<dyn Evaluator>,
+    initial: Value,
+    clicks: Vec<(i64, i64)>,
+    // states[i] is the state after the first i clicks.
+    states: Vec<Value>,
+}
+
+impl Session {
+    fn new(ev: Box<dyn Evaluator>, initial: Value) -> Session {
+        Session {
+            ev,
+            states: vec![initial.clone()],
+            initial,
+            clicks: vec![],
+        }
+    }
+
+    fn state(&self) -> &Value {
+        self.states.last().unwrap()
+    }
+
+    fn click(&mut self, x: i64, y: i64) -> Result<Value> {
+        let click = Value::cons(Value::int(x), Value::int(y));
+        let (new_state, images) = interact(&mut *self.ev, self.states.last().unwrap(), &click)?;
+
+        self.clicks.push((x, y));
+        self.states.push(new_state);
+        Ok(images)
+    }
+
+    fn undo(&mut self) -> bool {
+        if self.clicks.pop().is_none() {
+            return false;
+        }
+        self.states.pop();
+        true
+    }
+
+    fn reset(&mut self, initial: Value) {
+        self.states = vec![initial.clone()];
+        self.initial = initial;
+        self.clicks.clear();
+    }
+}
+
+// Click script format:
+//
+//   # comment
+//   state <s-expression>
+//   click <x> <y>
+//   ...
+//
+// The `state` line is optional and defaults to `()`.
+fn format_script(initial: &Value, clicks: &[(i64, i64)]) -> String {
+    let mut ret = format!("state {}\n", initial.to_sexp());
+    for (x, y) in clicks.iter() {
+        ret += &format!("click {} {}\n", x, y);
+    }
+    ret
+}
+
+fn parse_script(s: &str) -> Result<(Value, Vec<(i64, i64)>)> {
+    let mut initial = Value::nil();
+    let mut clicks = vec![];
+
+    for (lineno, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let err = || format!("line {}: invalid command: {}", lineno + 1, line);
+
+        let mut words = line.splitn(2, char::is_whitespace);
+        let args = words.next().and(words.next()).unwrap_or("");
+        match line.split_whitespace().next() {
+            Some("state") => initial = parse_sexp_str(args).ok_or_else(err)?,
+            Some("click") => clicks.push(parse_click(args.split_whitespace()).ok_or_else(err)?),
+            _ => return Err(err().into()),
+        }
+    }
+
+    Ok((initial, clicks))
+}
+
+pub fn parse_click<'a>(mut it: impl Iterator<Item = &'a str>) -> Option<(i64, i64)> {
+    let x = it.next()?.parse().ok()?;
+    let y = it.next()?.parse().ok()?;
+    if it.next().is_some() {
+        return None;
+    }
+    Some((x, y))
+}
+
+// Lists the differences between two values. Paths index into the list
+// structure, e.g. `[1][0]` is the first element of the second element.
+pub fn diff_value(old: &Value, new: &Value) -> Vec<String> {
+    let mut ret = vec![];
+    diff_rec(old, new, "", 0, &mut ret);
+    ret
+}
+
+fn diff_rec(old: &Value, new: &Value, path: &str, index: usize, out: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Cons(a0, d0), Value::Cons(a1, d1)) => {
+            diff_rec(a0, a1, &format!("{}[{}]", path, index), 0, out);
+            diff_rec(d0, d1, path, index + 1, out);
+        }
+        _ => {
+            if old != new {
+                let path = if index == 0 {
+                    path.to_string()
+                } else {
+                    format!("{}[{}..]", path, index)
+                };
+                let path = if path.is_empty() { "." } else { &path };
+                out.push(format!("{}: {} -> {}", path, old.to_sexp(), new.to_sexp()));
+            }
+        }
+    }
+}
+
+fn print_diff(old: &Value, new: &Value) {
+    let diff = diff_value(old, new);
+    if diff.is_empty() {
+        println!("diff:  (no change)");
+    }
+    for d in diff.iter() {
+        println!("diff:  {}", d);
+    }
+}
+
+fn load_script(session: &mut Session, path: &str) -> Result<()> {
+    let (initial, clicks) = parse_script(&std::fs::read_to_string(path)?)?;
+    session.reset(initial);
+    for (x, y) in clicks {
+        session.click(x, y)?;
+    }
+    Ok(())
+}
+
+fn save_script(session: &Session, path: &str) -> Result<()> {
+    let mut f = File::create(path)?;
+    f.write_all(format_script(&session.initial, &session.clicks).as_bytes())?;
+    Ok(())
+}
+
+const HELP: &str = "\
+commands:
+  click <x> <y>   send a click to the galaxy
+  undo            revert the last click
+  state           print the current state
+  history         print the clicks so far
+  save <file>     write a click script reproducing this session
+  load <file>     replay a click script from scratch
+  quit            exit";
+
+fn exec(session: &mut Session, line: &str) -> Result<bool> {
+    let mut it = line.split_whitespace();
+    let cmd = match it.next() {
+        Some(cmd) => cmd,
+        None => return Ok(true),
+    };
+
+    match cmd {
+        "click" => {
+            let (x, y) = parse_click(it).ok_or("usage: click <x> <y>")?;
+            let old = session.state().clone();
+            let images = session.click(x, y)?;
+
+            println!("step:  {}", session.clicks.len());
+            println!("input: {:?}", (x, y));
+            println!("state: {}", session.state().print());
+            print_diff(&old, session.state());
+            if value_to_list(images.clone())
+                .iter()
+                .all(|p| *p == Value::nil())
+            {
+                println!("(no image)");
+            } else {
+                print_image(&images)?;
+            }
+        }
+        "undo" => {
+            let old = session.state().clone();
+            if !session.undo() {
+                return Err("nothing to undo".into());
+            }
+            println!("step:  {}", session.clicks.len());
+            println!("state: {}", session.state().print());
+            print_diff(&old, session.state());
+        }
+        "state" => println!("{}", session.state().to_sexp()),
+        "history" => print!("{}", format_script(&session.initial, &session.clicks)),
+        "save" => {
+            let path = it.next().ok_or("usage: save <file>")?;
+            save_script(session, path)?;
+            println!("saved {} clicks to {}", session.clicks.len(), path);
+        }
+        "load" => {
+            let path = it.next().ok_or("usage: load <file>")?;
+            load_script(session, path)?;
+            println!("step:  {}", session.clicks.len());
+            println!("state: {}", session.state().print());
+        }
+        "help" => println!("{}", HELP),
+        "quit" | "exit" => return Ok(false),
+        _ => return Err(format!("unknown command: {} (try `help`)", cmd).into()),
+    }
+
+    Ok(true)
+}
+
+pub fn repl(state: &str, vm: bool, mut recorder: Recorder) -> Result<()> {
+    let ev = evaluator(parse_functions()?, vm)?;
+    let initial = parse_sexp_str(state).ok_or("Failed to parse state")?;
+    let mut session = Session::new(ev, initial);
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let res = exec(&mut session, &line);
+        recorder.step(line.trim())?;
+        match res {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    recorder.finish()?;
+    Ok(())
+}
+
+#[test]
+fn test_script_roundtrip() {
+    let initial = parse_sexp_str("(1 (2 . 3) ())").unwrap();
+    let clicks = vec![(0, 0), (-3, 8)];
+
+    let s = format_script(&initial, &clicks);
+    assert_eq!(
+        s,
+        "state (1 . ((2 . 3) . (() . ())))\nclick 0 0\nclick -3 8\n"
+    );
+    assert_eq!(parse_script(&s).unwrap(), (initial, clicks));
+
+    assert_eq!(
+        parse_script("# comment\nclick 1 2\n").unwrap(),
+        (Value::nil(), vec![(1, 2)])
+    );
+    assert!(parse_script("click 1\n").is_err());
+    assert!(parse_script("jump 1 2\n").is_err());
+    assert!(parse_script("state (1 x)\n").is_err());
+    assert!(parse_script("state (1 . 2 3)\n").is_err());
+    assert!(parse_script("state (1 2\n").is_err());
+    assert!(parse_script("state ) 1\n").is_err());
+}
+
+#[test]
+fn test_diff_value() {
+    let old = parse_sexp_str("(0 (0) 0 ())").unwrap();
+    let new = parse_sexp_str("(0 (1) 0 () 5)").unwrap();
+    assert_eq!(
+        diff_value(&old, &new),
+        vec!["[1][0]: 0 -> 1", "[4..]: () -> (5 . ())"]
+    );
+
+    assert_eq!(diff_value(&old, &old), Vec::<String>::new());
+    assert_eq!(
+        diff_value(&Value::int(1), &Value::int(2)),
+        vec![".: 1 -> 2"]
+    );
+}
@@ -0,0 +1,442 @@
+// Copyright 2020 Google LLC
+// Copyright 2020 Team Spacecat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Compiled galaxy evaluator.
+//
+// `compile` lowers a Dictionary into a flat array of nodes: atoms become
+// `Op`s, `:NNNN` references become indices into the global table, and
+// applications refer to their children by index. The machine then does lazy
+// graph reduction on a copy of that array, overwriting each redex with its
+// result, so sharing and memoization work as in `eval` without any
+// Rc/RefCell traffic or string comparisons.
+
+use super::{Dictionary, ExprRef, ExprValue, Result, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Mul,
+    Div,
+    Lt,
+    Eq,
+    Neg,
+    I,
+    T,
+    F,
+    S,
+    B,
+    C,
+    Cons,
+    Car,
+    Cdr,
+    Nil,
+    IsNil,
+}
+
+impl Op {
+    fn from_name(s: &str) -> Option<Op> {
+        Some(match s {
+            "add" => Op::Add,
+            "mul" => Op::Mul,
+            "div" => Op::Div,
+            "lt" => Op::Lt,
+            "eq" => Op::Eq,
+            "neg" => Op::Neg,
+            "i" => Op::I,
+            "t" => Op::T,
+            "f" => Op::F,
+            "s" => Op::S,
+            "b" => Op::B,
+            "c" => Op::C,
+            "cons" => Op::Cons,
+            "car" => Op::Car,
+            "cdr" => Op::Cdr,
+            "nil" => Op::Nil,
+            "isnil" => Op::IsNil,
+            _ => return None,
+        })
+    }
+
+    // Number of arguments needed for a reduction.
+    fn arity(self) -> usize {
+        match self {
+            Op::Neg | Op::I | Op::Car | Op::Cdr | Op::Nil | Op::IsNil => 1,
+            Op::Add | Op::Mul | Op::Div | Op::Lt | Op::Eq | Op::T | Op::F => 2,
+            Op::S | Op::B | Op::C | Op::Cons => 3,
+        }
+    }
+}
+
+type Ref = u32;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Int(i64),
+    Op(Op),
+    Global(u32),
+    App(Ref, Ref),
+    // Left behind by a reduction whose result is another node.
+    Ind(Ref),
+}
+
+// Nodes allocated at fixed positions by `compile`.
+const NODE_T: Ref = 0;
+const NODE_F: Ref = 1;
+const NODE_NIL: Ref = 2;
+const NODE_CONS: Ref = 3;
+const NODE_ISNIL: Ref = 4;
+const NODE_CAR: Ref = 5;
+const NODE_CDR: Ref = 6;
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    nodes: Vec<Node>,
+    globals: Vec<Ref>,
+    names: BTreeMap<String, u32>,
+}
+
+pub fn compile(dict: &Dictionary) -> Result<Program> {
+    let names = dict
+        .keys()
+        .enumerate()
+        .map(|(i, name)| (name.clone(), i as u32))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut program = Program {
+        nodes: [Op::T, Op::F, Op::Nil, Op::Cons, Op::IsNil, Op::Car, Op::Cdr]
+            .iter()
+            .map(|op| Node::Op(*op))
+            .collect(),
+        globals: vec![],
+        names,
+    };
+
+    for body in dict.values() {
+        let r = program.compile_expr(body)?;
+        program.globals.push(r);
+    }
+    Ok(program)
+}
+
+impl Program {
+    fn compile_expr(&mut self, e: &ExprRef) -> Result<Ref> {
+        let node = match &*e.borrow().value.borrow() {
+            ExprValue::Int(n) => Node::Int(*n),
+            ExprValue::Atom(name) => {
+                if let Some(op) = Op::from_name(name) {
+                    Node::Op(op)
+                } else if let Some(g) = self.names.get(name) {
+                    Node::Global(*g)
+                } else {
+                    return Err(format!("undefined symbol: {}", name).into());
+                }
+            }
+            ExprValue::App(f, x) => {
+                let f = self.compile_expr(f)?;
+                let x = self.compile_expr(x)?;
+                Node::App(f, x)
+            }
+            ExprValue::Lam(_, _) => return Err("lambda is not supported".into()),
+        };
+        self.nodes.push(node);
+        Ok((self.nodes.len() - 1) as Ref)
+    }
+
+    pub fn global(&self, name: &str) -> Option<u32> {
+        self.names.get(name).cloned()
+    }
+}
+
+pub struct Machine<'a> {
+    program: &'a Program,
+    heap: Vec<Node>,
+    // Number of reductions performed so far.
+    pub steps: u64,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program: &'a Program) -> Machine<'a> {
+        Machine {
+            program,
+            heap: program.nodes.clone(),
+            steps: 0,
+        }
+    }
+
+    fn alloc(&mut self, node: Node) -> Ref {
+        self.heap.push(node);
+        (self.heap.len() - 1) as Ref
+    }
+
+    fn app(&mut self, f: Ref, x: Ref) -> Ref {
+        self.alloc(Node::App(f, x))
+    }
+
+    fn resolve(&self, mut r: Ref) -> Ref {
+        while let Node::Ind(next) = self.heap[r as usize] {
+            r = next;
+        }
+        r
+    }
+
+    pub fn global(&mut self, g: u32) -> Ref {
+        self.alloc(Node::Global(g))
+    }
+
+    // Reduces `r` to weak head normal form and returns the node holding the
+    // result.
+    fn whnf(&mut self, r: Ref) -> Result<Ref> {
+        let root = self.resolve(r);
+        let mut spine: Vec<Ref> = vec![];
+        let mut head = root;
+
+        loop {
+            head = self.resolve(head);
+            match self.heap[head as usize] {
+                Node::App(f, _) => {
+                    spine.push(head);
+                    head = f;
+                }
+                Node::Global(g) => {
+                    self.heap[head as usize] = Node::Ind(self.program.globals[g as usize]);
+                }
+                Node::Int(n) => {
+                    if spine.is_empty() {
+                        return Ok(head);
+                    }
+                    return Err(format!("applied an int: {}", n).into());
+                }
+                Node::Op(op) => {
+                    let k = op.arity();
+                    if spine.len() < k {
+                        return Ok(self.resolve(root));
+                    }
+                    let redex = spine[spine.len() - k];
+                    let mut args = [0; 3];
+                    for (i, arg) in args.iter_mut().enumerate().take(k) {
+                        if let Node::App(_, x) = self.heap[spine[spine.len() - 1 - i] as usize] {
+                            *arg = x;
+                        }
+                    }
+                    spine.truncate(spine.len() - k);
+
+                    self.reduce(op, redex, args)?;
+                    self.steps += 1;
+                    head = redex;
+                }
+                Node::Ind(_) => unreachable!(),
+            }
+        }
+    }
+
+    fn int(&mut self, r: Ref) -> Result<i64> {
+        let r = self.whnf(r)?;
+        match self.heap[r as usize] {
+            Node::Int(n) => Ok(n),
+            node => Err(format!("argument is not int: {:?}", node).into()),
+        }
+    }
+
+    fn reduce(&mut self, op: Op, redex: Ref, args: [Ref; 3]) -> Result<()> {
+        let [x, y, z] = args;
+        let node = match op {
+            Op::I | Op::T => Node::Ind(x),
+            Op::F => Node::Ind(y),
+            Op::Neg => Node::Int(-self.int(x)?),
+            Op::Add => Node::Int(self.int(x)? + self.int(y)?),
+            Op::Mul => Node::Int(self.int(x)? * self.int(y)?),
+            Op::Div => {
+                let (a, b) = (self.int(x)?, self.int(y)?);
+                if b == 0 {
+                    return Err("division by zero".into());
+                }
+                Node::Int(a / b)
+            }
+            Op::Lt => Node::Ind(if self.int(x)? < self.int(y)? {
+                NODE_T
+            } else {
+                NODE_F
+            }),
+            Op::Eq => Node::Ind(if self.int(x)? == self.int(y)? {
+                NODE_T
+            } else {
+                NODE_F
+            }),
+            Op::Nil => Node::Ind(NODE_T),
+            Op::IsNil => {
+                let tf = self.app(NODE_T, NODE_F);
+                let ttf = self.app(NODE_T, tf);
+                Node::App(x, ttf)
+            }
+            Op::Car => Node::App(x, NODE_T),
+            Op::Cdr => Node::App(x, NODE_F),
+            Op::S => {
+                let xz = self.app(x, z);
+                let yz = self.app(y, z);
+                Node::App(xz, yz)
+            }
+            Op::B => {
+                let yz = self.app(y, z);
+                Node::App(x, yz)
+            }
+            Op::C => {
+                let xz = self.app(x, z);
+                Node::App(xz, y)
+            }
+            Op::Cons => {
+                let zx = self.app(z, x);
+                Node::App(zx, y)
+            }
+        };
+        self.heap[redex as usize] = node;
+        Ok(())
+    }
+
+    pub fn load_value(&mut self, v: &Value) -> Ref {
+        match v {
+            Value::Int(n) => self.alloc(Node::Int(*n)),
+            Value::Nil => NODE_NIL,
+            Value::Cons(a, b) => {
+                let a = self.load_value(a);
+                let b = self.load_value(b);
+                let ca = self.app(NODE_CONS, a);
+                self.app(ca, b)
+            }
+        }
+    }
+
+    // Returns (car, cdr) if `r` is in whnf and is a cons cell built by `cons`.
+    fn as_cons(&self, r: Ref) -> Option<(Ref, Ref)> {
+        if let Node::App(f, b) = self.heap[r as usize] {
+            if let Node::App(c, a) = self.heap[self.resolve(f) as usize] {
+                if let Node::Op(Op::Cons) = self.heap[self.resolve(c) as usize] {
+                    return Some((a, b));
+                }
+            }
+        }
+        None
+    }
+
+    // Splits a cons-like value with isnil/car/cdr, like expr_to_value does.
+    fn uncons(&mut self, r: Ref) -> Result<Option<(Ref, Ref)>> {
+        if let Some(cell) = self.as_cons(r) {
+            return Ok(Some(cell));
+        }
+        if let Node::Op(Op::Nil) = self.heap[r as usize] {
+            return Ok(None);
+        }
+
+        let zero = self.alloc(Node::Int(0));
+        let one = self.alloc(Node::Int(1));
+        let test = self.app(NODE_ISNIL, r);
+        let test = self.app(test, zero);
+        let test = self.app(test, one);
+        if self.int(test)? == 0 {
+            return Ok(None);
+        }
+        let car = self.app(NODE_CAR, r);
+        let cdr = self.app(NODE_CDR, r);
+        Ok(Some((car, cdr)))
+    }
+
+    pub fn read_value(&mut self, r: Ref) -> Result<Value> {
+        let mut items = vec![];
+        let mut r = self.whnf(r)?;
+
+        // Walks the spine of lists iteratively to keep the recursion shallow.
+        let tail = loop {
+            if let Node::Int(n) = self.heap[r as usize] {
+                break Value::Int(n);
+            }
+            match self.uncons(r)? {
+                None => break Value::Nil,
+                Some((car, cdr)) => {
+                    items.push(self.read_value(car)?);
+                    r = self.whnf(cdr)?;
+                }
+            }
+        };
+
+        Ok(items
+            .into_iter()
+            .rev()
+            .fold(tail, |acc, v| Value::cons(v, acc)))
+    }
+
+    // Evaluates `galaxy state event` and returns (flag, new state, data).
+    pub fn galaxy(&mut self, state: &Value, event: &Value) -> Result<(i64, Value, Value)> {
+        let g = self
+            .program
+            .global("galaxy")
+            .ok_or("galaxy is not defined")?;
+        let galaxy = self.global(g);
+        let state = self.load_value(state);
+        let event = self.load_value(event);
+        let e = self.app(galaxy, state);
+        let e = self.app(e, event);
+
+        match self.read_value(e)? {
+            Value::Cons(flag, rest) => match (*flag, *rest) {
+                (Value::Int(flag), Value::Cons(state, rest)) => match *rest {
+                    Value::Cons(data, nil) if *nil == Value::Nil => Ok((flag, *state, *data)),
+                    _ => Err("galaxy did not return [flag, state, data]".into()),
+                },
+                _ => Err("galaxy did not return [flag, state, data]".into()),
+            },
+            _ => Err("galaxy did not return [flag, state, data]".into()),
+        }
+    }
+}
+
+#[test]
+fn test_vm_arith() {
+    use super::parse_expr;
+
+    let mut dict = Dictionary::new();
+    dict.insert(":1".to_string(), parse_expr("ap ap add 1 2"));
+    dict.insert(":2".to_string(), parse_expr("ap ap b ap mul 2 ap add :1"));
+    dict.insert(
+        ":3".to_string(),
+        parse_expr("ap ap cons 1 ap ap cons :1 nil"),
+    );
+    dict.insert(
+        ":4".to_string(),
+        parse_expr("ap ap s ap ap c ap eq 0 1 ap ap b ap mul 2 ap ap b :4 ap add -1"),
+    );
+    let program = compile(&dict).unwrap();
+    let mut m = Machine::new(&program);
+
+    let eval = |m: &mut Machine, name: &str, args: &[i64]| {
+        let mut e = m.global(program.global(name).unwrap());
+        for arg in args {
+            let arg = m.load_value(&Value::int(*arg));
+            e = m.app(e, arg);
+        }
+        m.read_value(e).unwrap()
+    };
+
+    assert_eq!(eval(&mut m, ":1", &[]), Value::int(3));
+    assert_eq!(eval(&mut m, ":2", &[5]), Value::int(16));
+    assert_eq!(
+        eval(&mut m, ":3", &[]),
+        Value::list(vec![Value::int(1), Value::int(3)])
+    );
+    // pow2
+    assert_eq!(eval(&mut m, ":4", &[10]), Value::int(1024));
+
+    dict.insert(":5".to_string(), parse_expr("ap :6 1"));
+    assert!(compile(&dict).is_err());
+}